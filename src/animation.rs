@@ -0,0 +1,537 @@
+//! Keyframe animation. A Timeline is loaded from a JSON file, or from the
+//! animation section of a saved scene, and holds Tracks for the animatable
+//! parameters of the scene: the camera, the light, the transforms of Objects
+//! and the values of Materials. Each Track is sampled at the current frame and
+//! the result is written into the SceneDesc.
+//!
+//! ```json
+//! {
+//!     "start": 0,
+//!     "end": 119,
+//!     "camera": {
+//!         "eye": {
+//!             "interpolation": "catmull-rom",
+//!             "easing": "ease-in-out",
+//!             "keys": [
+//!                 { "frame": 0, "value": [5.0, 2.0, 5.0] },
+//!                 { "frame": 60, "value": [0.0, 3.0, 8.0] },
+//!                 { "frame": 119, "value": [-5.0, 2.0, 5.0] }
+//!             ]
+//!         }
+//!     },
+//!     "objects": {
+//!         "cube": { "rotation": { "keys": [ { "frame": 0, "value": [0, 0, 0] },
+//!                                           { "frame": 119, "value": [0, 360, 0] } ] } }
+//!     },
+//!     "materials": {
+//!         "green": { "reflect": { "interpolation": "step",
+//!                                 "keys": [ { "frame": 0, "value": 0.0 },
+//!                                           { "frame": 60, "value": 0.9 } ] } }
+//!     }
+//! }
+//! ```
+
+use na::Vec3;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use precision::Float;
+use scene::SceneDesc;
+
+/// How values are interpolated between two keyframes
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Interpolation {
+    /// Holds the value of the previous keyframe
+    Step,
+    Linear,
+    /// Smooth curve through all of the keyframes
+    CatmullRom,
+    /// Cubic curve shaped by the tangents of the keyframes. Keyframes without
+    /// tangents use the Catmull-Rom tangents.
+    Bezier,
+}
+
+impl Default for Interpolation {
+    fn default() -> Interpolation {
+        Interpolation::Linear
+    }
+}
+
+/// Remaps the progress between two keyframes
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Default for Easing {
+    fn default() -> Easing {
+        Easing::Linear
+    }
+}
+
+impl Easing {
+    fn apply(&self, s: Float) -> Float {
+        match *self {
+            Easing::Linear => s,
+            Easing::EaseIn => s * s,
+            Easing::EaseOut => 1.0 - (1.0 - s) * (1.0 - s),
+            Easing::EaseInOut => s * s * (3.0 - 2.0 * s),
+        }
+    }
+}
+
+/// Values that can be stored in a Track
+pub trait Animatable: Copy {
+    fn add(self, other: Self) -> Self;
+    fn scale(self, s: Float) -> Self;
+}
+
+impl Animatable for Float {
+    fn add(self, other: Float) -> Float {
+        self + other
+    }
+
+    fn scale(self, s: Float) -> Float {
+        self * s
+    }
+}
+
+impl Animatable for [Float; 3] {
+    fn add(self, other: [Float; 3]) -> [Float; 3] {
+        [self[0] + other[0], self[1] + other[1], self[2] + other[2]]
+    }
+
+    fn scale(self, s: Float) -> [Float; 3] {
+        [self[0] * s, self[1] * s, self[2] * s]
+    }
+}
+
+fn to_vec3(v: [Float; 3]) -> Vec3<Float> {
+    Vec3::new(v[0], v[1], v[2])
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Keyframe<T> {
+    pub frame: Float,
+    pub value: T,
+    /// Tangents used by Bezier interpolation, in value change per segment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_tangent: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_tangent: Option<T>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Track<T> {
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    #[serde(default)]
+    pub easing: Easing,
+    pub keys: Vec<Keyframe<T>>,
+}
+
+impl<T: Animatable> Track<T> {
+    /// Value of the Track at 'frame'. Frames before the first keyframe or after
+    /// the last keyframe hold the value of that keyframe.
+    pub fn sample(&self, frame: Float) -> T {
+        let keys = &self.keys;
+        let last = keys.len() - 1;
+        if frame <= keys[0].frame {
+            return keys[0].value;
+        }
+        if frame >= keys[last].frame {
+            return keys[last].value;
+        }
+        let i = keys.iter().rposition(|k| k.frame <= frame).unwrap();
+        let (k1, k2) = (&keys[i], &keys[i + 1]);
+        let s = self
+            .easing
+            .apply((frame - k1.frame) / (k2.frame - k1.frame));
+        let (p1, p2) = (k1.value, k2.value);
+
+        // Catmull-Rom tangents, the end points are repeated at the ends of the Track
+        let p0 = if i > 0 { keys[i - 1].value } else { p1 };
+        let p3 = if i + 1 < last { keys[i + 2].value } else { p2 };
+        let m1 = p2.add(p0.scale(-1.0)).scale(0.5);
+        let m2 = p3.add(p1.scale(-1.0)).scale(0.5);
+
+        match self.interpolation {
+            Interpolation::Step => p1,
+            Interpolation::Linear => p1.scale(1.0 - s).add(p2.scale(s)),
+            Interpolation::CatmullRom => hermite(p1, p2, m1, m2, s),
+            Interpolation::Bezier => hermite(
+                p1,
+                p2,
+                k1.out_tangent.unwrap_or(m1),
+                k2.in_tangent.unwrap_or(m2),
+                s,
+            ),
+        }
+    }
+}
+
+/// Cubic Hermite curve from p1 to p2 with tangents m1 and m2. This is the
+/// same curve as the cubic Bezier with control points p1 + m1 / 3 and p2 - m2 / 3.
+fn hermite<T: Animatable>(p1: T, p2: T, m1: T, m2: T, s: Float) -> T {
+    let s2 = s * s;
+    let s3 = s2 * s;
+    p1.scale(2.0 * s3 - 3.0 * s2 + 1.0)
+        .add(m1.scale(s3 - 2.0 * s2 + s))
+        .add(p2.scale(-2.0 * s3 + 3.0 * s2))
+        .add(m2.scale(s3 - s2))
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraTracks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eye: Option<Track<[Float; 3]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub look: Option<Track<[Float; 3]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub up: Option<Track<[Float; 3]>>,
+    /// Rotation about the viewing direction in degrees
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roll: Option<Track<Float>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LightTracks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<Track<[Float; 3]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<Track<[Float; 3]>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ObjectTracks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translation: Option<Track<[Float; 3]>>,
+    /// Euler angles in degrees
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<Track<[Float; 3]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<Track<Float>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialTracks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amb: Option<Track<[Float; 3]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reflect: Option<Track<Float>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub roughness: Option<Track<Float>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emission: Option<Track<[Float; 3]>>,
+}
+
+/// All of the Tracks of an animation along with the range of frames to render.
+/// Objects and Materials are referred to by name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Timeline {
+    /// First frame to render
+    pub start: u32,
+    /// Last frame to render, inclusive
+    pub end: u32,
+    #[serde(default)]
+    pub camera: CameraTracks,
+    #[serde(default)]
+    pub light: LightTracks,
+    #[serde(default)]
+    pub objects: BTreeMap<String, ObjectTracks>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialTracks>,
+}
+
+impl Timeline {
+    /// Reads a Timeline from a JSON file. The keyframes of each Track are sorted by frame.
+    pub fn load(path: &str) -> Result<Timeline, String> {
+        let file = File::open(path).map_err(|e| format!("Can't open '{}': {}", path, e))?;
+        let mut timeline: Timeline = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("Can't parse '{}': {}", path, e))?;
+        timeline.prepare(path)?;
+        Ok(timeline)
+    }
+
+    /// Checks the range and the keyframes of a Timeline read from 'path' and
+    /// sorts the keyframes of each Track by frame
+    pub fn prepare(&mut self, path: &str) -> Result<(), String> {
+        if self.end < self.start {
+            return Err(format!("The animation of '{}' ends before it starts", path));
+        }
+        prepare("camera.eye", &mut self.camera.eye)?;
+        prepare("camera.look", &mut self.camera.look)?;
+        prepare("camera.up", &mut self.camera.up)?;
+        prepare("camera.roll", &mut self.camera.roll)?;
+        prepare("light.position", &mut self.light.position)?;
+        prepare("light.color", &mut self.light.color)?;
+        for (name, tracks) in self.objects.iter_mut() {
+            prepare(&format!("{}.translation", name), &mut tracks.translation)?;
+            prepare(&format!("{}.rotation", name), &mut tracks.rotation)?;
+            prepare(&format!("{}.scale", name), &mut tracks.scale)?;
+        }
+        for (name, tracks) in self.materials.iter_mut() {
+            prepare(&format!("{}.amb", name), &mut tracks.amb)?;
+            prepare(&format!("{}.reflect", name), &mut tracks.reflect)?;
+            prepare(&format!("{}.roughness", name), &mut tracks.roughness)?;
+            prepare(&format!("{}.emission", name), &mut tracks.emission)?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure every Object and Material the Timeline animates exists in 'desc'
+    pub fn validate(&self, desc: &SceneDesc) -> Result<(), String> {
+        for name in self.objects.keys() {
+            if !desc.objects.iter().any(|o| &o.name == name) {
+                return Err(format!("The scene has no object named '{}'", name));
+            }
+        }
+        for name in self.materials.keys() {
+            if !desc.materials.iter().any(|m| &m.0 == name) {
+                return Err(format!("The scene has no material named '{}'", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrites every animated parameter of 'desc' with its value at 'frame'
+    pub fn apply(&self, desc: &mut SceneDesc, frame: Float) {
+        if let Some(ref track) = self.camera.eye {
+            desc.view.eye = to_vec3(track.sample(frame));
+        }
+        if let Some(ref track) = self.camera.look {
+            desc.view.look = to_vec3(track.sample(frame));
+        }
+        if let Some(ref track) = self.camera.up {
+            desc.view.up = to_vec3(track.sample(frame));
+        }
+        if let Some(ref track) = self.camera.roll {
+            desc.view.roll = track.sample(frame);
+        }
+        if let Some(ref track) = self.light.position {
+            desc.light.position = to_vec3(track.sample(frame));
+        }
+        if let Some(ref track) = self.light.color {
+            desc.light.color = to_vec3(track.sample(frame));
+        }
+        for (name, tracks) in self.objects.iter() {
+            if let Some(object) = desc.object_mut(name) {
+                if let Some(ref track) = tracks.translation {
+                    object.transform.translation = to_vec3(track.sample(frame));
+                }
+                if let Some(ref track) = tracks.rotation {
+                    object.transform.rotation = to_vec3(track.sample(frame));
+                }
+                if let Some(ref track) = tracks.scale {
+                    object.transform.scale = track.sample(frame);
+                }
+            }
+        }
+        for (name, tracks) in self.materials.iter() {
+            if let Some(material) = desc.material_mut(name) {
+                if let Some(ref track) = tracks.amb {
+                    material.amb = to_vec3(track.sample(frame));
+                }
+                if let Some(ref track) = tracks.reflect {
+                    material.reflect = track.sample(frame).max(0.0).min(1.0);
+                }
+                if let Some(ref track) = tracks.roughness {
+                    material.roughness = track.sample(frame).max(0.0).min(1.0);
+                }
+                if let Some(ref track) = tracks.emission {
+                    material.emission = to_vec3(track.sample(frame));
+                }
+            }
+        }
+    }
+}
+
+/// Checks that the Track has keyframes with valid times and sorts them by frame
+fn prepare<T>(name: &str, track: &mut Option<Track<T>>) -> Result<(), String> {
+    if let Some(ref mut track) = *track {
+        if track.keys.is_empty() {
+            return Err(format!("Track '{}' has no keyframes", name));
+        }
+        if track.keys.iter().any(|k| !k.frame.is_finite()) {
+            return Err(format!(
+                "Track '{}' has a keyframe with an invalid frame",
+                name
+            ));
+        }
+        track
+            .keys
+            .sort_by(|a, b| a.frame.partial_cmp(&b.frame).unwrap());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use default_scene;
+
+    fn track(
+        interpolation: Interpolation,
+        easing: Easing,
+        keys: &[(Float, Float)],
+    ) -> Track<Float> {
+        Track {
+            interpolation: interpolation,
+            easing: easing,
+            keys: keys
+                .iter()
+                .map(|&(frame, value)| Keyframe {
+                    frame: frame,
+                    value: value,
+                    in_tangent: None,
+                    out_tangent: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn assert_close(a: Float, b: Float) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn linear_holds_the_ends() {
+        let t = track(
+            Interpolation::Linear,
+            Easing::Linear,
+            &[(0.0, 0.0), (10.0, 10.0)],
+        );
+        assert_close(t.sample(-5.0), 0.0);
+        assert_close(t.sample(2.5), 2.5);
+        assert_close(t.sample(10.0), 10.0);
+        assert_close(t.sample(20.0), 10.0);
+    }
+
+    #[test]
+    fn step_holds_the_previous_key() {
+        let t = track(
+            Interpolation::Step,
+            Easing::Linear,
+            &[(0.0, 1.0), (10.0, 2.0)],
+        );
+        assert_close(t.sample(9.9), 1.0);
+        assert_close(t.sample(10.0), 2.0);
+    }
+
+    #[test]
+    fn easing_remaps_the_progress() {
+        let keys = [(0.0, 0.0), (10.0, 1.0)];
+        assert_close(
+            track(Interpolation::Linear, Easing::EaseIn, &keys).sample(5.0),
+            0.25,
+        );
+        assert_close(
+            track(Interpolation::Linear, Easing::EaseOut, &keys).sample(5.0),
+            0.75,
+        );
+        assert_close(
+            track(Interpolation::Linear, Easing::EaseInOut, &keys).sample(2.5),
+            0.15625,
+        );
+    }
+
+    #[test]
+    fn catmull_rom_passes_through_the_keys() {
+        let t = track(
+            Interpolation::CatmullRom,
+            Easing::Linear,
+            &[(0.0, 0.0), (1.0, 3.0), (2.0, 1.0), (3.0, 2.0)],
+        );
+        assert_close(t.sample(1.0), 3.0);
+        assert_close(t.sample(2.0), 1.0);
+        // Evenly spaced keys on a line stay on it
+        let line = track(
+            Interpolation::CatmullRom,
+            Easing::Linear,
+            &[(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)],
+        );
+        assert_close(line.sample(1.5), 1.5);
+    }
+
+    #[test]
+    fn bezier_uses_the_tangents() {
+        let mut t = track(
+            Interpolation::Bezier,
+            Easing::Linear,
+            &[(0.0, 0.0), (1.0, 1.0)],
+        );
+        t.keys[0].out_tangent = Some(0.0);
+        t.keys[1].in_tangent = Some(0.0);
+        // Flat tangents at both ends give the smoothstep curve
+        assert_close(t.sample(0.25), 0.15625);
+        assert_close(t.sample(0.5), 0.5);
+    }
+
+    #[test]
+    fn prepare_sorts_and_checks_the_keys() {
+        let mut t = Some(track(
+            Interpolation::Linear,
+            Easing::Linear,
+            &[(10.0, 1.0), (0.0, 0.0)],
+        ));
+        prepare("t", &mut t).unwrap();
+        assert_close(t.as_ref().unwrap().keys[0].frame, 0.0);
+        assert_close(t.unwrap().sample(5.0), 0.5);
+
+        let mut empty = Some(track(Interpolation::Linear, Easing::Linear, &[]));
+        assert!(prepare("empty", &mut empty).is_err());
+        let mut nan = Some(track(
+            Interpolation::Linear,
+            Easing::Linear,
+            &[(Float::NAN, 0.0)],
+        ));
+        assert!(prepare("nan", &mut nan).is_err());
+    }
+
+    #[test]
+    fn apply_clamps_the_material_values() {
+        // Catmull-Rom overshoots next to the step from 0 to 1
+        let curve = || {
+            track(
+                Interpolation::CatmullRom,
+                Easing::Linear,
+                &[(0.0, 0.0), (1.0, 0.0), (2.0, 1.0), (3.0, 1.0)],
+            )
+        };
+        assert!(curve().sample(0.5) < 0.0 && curve().sample(2.5) > 1.0);
+        let mut timeline = Timeline {
+            start: 0,
+            end: 3,
+            camera: CameraTracks::default(),
+            light: LightTracks::default(),
+            objects: BTreeMap::new(),
+            materials: BTreeMap::new(),
+        };
+        timeline.materials.insert(
+            "green".to_string(),
+            MaterialTracks {
+                reflect: Some(curve()),
+                roughness: Some(curve()),
+                ..MaterialTracks::default()
+            },
+        );
+        let mut desc = default_scene();
+        for &frame in [0.5, 2.5].iter() {
+            timeline.apply(&mut desc, frame);
+            let material = desc.material_mut("green").unwrap();
+            assert!(material.reflect >= 0.0 && material.reflect <= 1.0);
+            assert!(material.roughness >= 0.0 && material.roughness <= 1.0);
+        }
+    }
+}
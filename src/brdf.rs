@@ -0,0 +1,176 @@
+//! Metallic/roughness microfacet BRDF, the material model of most modelling
+//! tools and of glTF. The specular lobe uses the GGX (Trowbridge-Reitz)
+//! distribution of microfacet normals with Smith shadowing-masking and Schlick's
+//! approximation of the Fresnel term. The diffuse lobe only receives the light
+//! the specular lobe didn't reflect, so the Material never reflects more light
+//! than it receives. Metals have no diffuse lobe and tint their reflections with
+//! the base color.
+//!
+//! Directions point away from the surface: 'wo' towards the viewer and 'wi'
+//! towards where the light comes from.
+
+use na;
+use na::{Norm, Vec3};
+
+use precision::{consts::PI, Float};
+use {largest_of, orthonormal_basis};
+
+/// Smallest GGX alpha, perfectly smooth surfaces would have an infinitely
+/// narrow highlight
+const MIN_ALPHA: Float = 0.002;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Pbr {
+    /// 0 for dielectrics, 1 for metals
+    pub metallic: Float,
+    /// Perceptual roughness from 0 (mirror) to 1
+    pub roughness: Float,
+    /// Reflectance of dielectrics at normal incidence, 0.5 is 4% like most
+    /// non-metals
+    pub specular: Float,
+}
+
+impl Pbr {
+    fn alpha(&self) -> Float {
+        alpha(self.roughness)
+    }
+
+    /// Reflectance at normal incidence
+    pub fn f0(&self, base: Vec3<Float>) -> Vec3<Float> {
+        let dielectric = 0.08 * self.specular;
+        Vec3::new(dielectric, dielectric, dielectric) * (1.0 - self.metallic) + base * self.metallic
+    }
+
+    /// Fraction of the light arriving at an angle with cosine 'cos' to the
+    /// normal that the specular lobe reflects
+    pub fn fresnel(&self, base: Vec3<Float>, cos: Float) -> Vec3<Float> {
+        schlick(self.f0(base), cos)
+    }
+
+    /// BRDF times the cosine between 'wi' and the normal 'n'
+    pub fn eval(
+        &self,
+        base: Vec3<Float>,
+        n: Vec3<Float>,
+        wo: Vec3<Float>,
+        wi: Vec3<Float>,
+    ) -> Vec3<Float> {
+        let n_wi = na::dot(&n, &wi);
+        let n_wo = na::dot(&n, &wo);
+        if n_wi <= 0.0 || n_wo <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let h = (wi + wo).normalize();
+        let alpha = self.alpha();
+        let f = schlick(self.f0(base), largest_of(na::dot(&wi, &h)));
+        let specular =
+            f * (ggx(na::dot(&n, &h), alpha) * smith(n_wo, n_wi, alpha) / (4.0 * n_wo * n_wi));
+        // The diffuse lobe gets what the specular one lets through on the way in
+        // and on the way out
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let f0 = self.f0(base);
+        let diffuse = base * (1.0 - self.metallic) / PI
+            * (white - schlick(f0, n_wi))
+            * (white - schlick(f0, n_wo));
+        (diffuse + specular) * n_wi
+    }
+
+    /// Probability of sampling the specular instead of the diffuse lobe
+    fn specular_probability(&self, base: Vec3<Float>, n: Vec3<Float>, wo: Vec3<Float>) -> Float {
+        let average = |c: Vec3<Float>| (c.x + c.y + c.z) / 3.0;
+        let specular = average(self.fresnel(base, largest_of(na::dot(&n, &wo))));
+        let diffuse = average(base) * (1.0 - self.metallic);
+        if specular + diffuse <= 0.0 {
+            return 1.0;
+        }
+        (specular / (specular + diffuse)).max(0.1).min(1.0)
+    }
+
+    /// Probability density per solid angle of 'sample' picking 'wi'
+    pub fn pdf(
+        &self,
+        base: Vec3<Float>,
+        n: Vec3<Float>,
+        wo: Vec3<Float>,
+        wi: Vec3<Float>,
+    ) -> Float {
+        let n_wi = na::dot(&n, &wi);
+        if n_wi <= 0.0 {
+            return 0.0;
+        }
+        let p = self.specular_probability(base, n, wo);
+        let h = (wi + wo).normalize();
+        let wo_h = na::dot(&wo, &h);
+        let specular = if wo_h > 0.0 {
+            ggx(na::dot(&n, &h), self.alpha()) * na::dot(&n, &h) / (4.0 * wo_h)
+        } else {
+            0.0
+        };
+        p * specular + (1.0 - p) * n_wi / PI
+    }
+
+    /// Picks the direction light is reflected from, using a uniform sample 'u'
+    /// in [0, 1)^3. Returns the direction and the BRDF times the cosine divided
+    /// by the probability density, or None if the sample goes below the surface.
+    pub fn sample(
+        &self,
+        base: Vec3<Float>,
+        n: Vec3<Float>,
+        wo: Vec3<Float>,
+        u: (Float, Float, Float),
+    ) -> Option<(Vec3<Float>, Vec3<Float>)> {
+        let wi = if u.0 < self.specular_probability(base, n, wo) {
+            let h = sample_ggx(n, self.roughness, (u.1, u.2));
+            (h * (2.0 * na::dot(&wo, &h)) - wo).normalize()
+        } else {
+            let (tangent, bitangent) = orthonormal_basis(n);
+            let phi = 2.0 * PI * u.2;
+            let r = u.1.sqrt();
+            let z = largest_of(1.0 - r * r).sqrt();
+            (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + n * z).normalize()
+        };
+        let pdf = self.pdf(base, n, wo, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((wi, self.eval(base, n, wo, wi) / pdf))
+    }
+}
+
+/// Microfacet normal around 'n' picked in proportion to the GGX distribution
+/// times its cosine to 'n', from a uniform sample 'u' in [0, 1)^2
+pub fn sample_ggx(n: Vec3<Float>, roughness: Float, u: (Float, Float)) -> Vec3<Float> {
+    let (tangent, bitangent) = orthonormal_basis(n);
+    let a2 = alpha(roughness) * alpha(roughness);
+    let cos = ((1.0 - u.0) / (1.0 + (a2 - 1.0) * u.0)).sqrt();
+    let sin = largest_of(1.0 - cos * cos).sqrt();
+    let phi = 2.0 * PI * u.1;
+    (tangent * (sin * phi.cos()) + bitangent * (sin * phi.sin()) + n * cos).normalize()
+}
+
+fn alpha(roughness: Float) -> Float {
+    (roughness * roughness).max(MIN_ALPHA)
+}
+
+/// GGX distribution of microfacet normals with cosine 'n_h' to the normal
+fn ggx(n_h: Float, alpha: Float) -> Float {
+    if n_h <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let d = n_h * n_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+/// Separable Smith shadowing-masking for GGX
+fn smith(n_wo: Float, n_wi: Float, alpha: Float) -> Float {
+    let a2 = alpha * alpha;
+    let g1 = |c: Float| 2.0 * c / (c + (a2 + (1.0 - a2) * c * c).sqrt());
+    g1(n_wo) * g1(n_wi)
+}
+
+fn schlick(f0: Vec3<Float>, cos: Float) -> Vec3<Float> {
+    let m = (1.0 - cos).max(0.0).min(1.0);
+    let m5 = m * m * m * m * m;
+    f0 + (Vec3::new(1.0, 1.0, 1.0) - f0) * m5
+}
@@ -0,0 +1,429 @@
+//! Camera models used to generate the primary rays of the Ray Tracer.
+//! Every Camera maps a point on the image plane, given in normalized
+//! coordinates in [-1, 1] with +y pointing up, to a Ray in world space.
+//! Cameras may move while the shutter is open, their position is given
+//! at the start (time 0) and the end (time 1) of the frame.
+
+use na;
+use na::{Norm, Rot3, Vec3};
+
+use precision::{consts::PI, Float};
+use {Ray, UP};
+
+/// Trait for the different projections supported by the renderer
+pub trait Camera: Sync + Send {
+    /// Generates the Ray through the normalized image coordinates (x, y).
+    /// 'lens' is a uniform random sample in [0, 1) x [0, 1) used by cameras with
+    /// a finite aperture to pick the point on the lens the Ray passes through.
+    /// 'time' is the point in the frame the Ray is traced at and is stored in the Ray.
+    /// Returns None if the point lies outside of the area the camera can see
+    /// (e.g. the corners outside of the image circle of a fisheye lens).
+    fn generate_ray(&self, x: Float, y: Float, lens: (Float, Float), time: Float) -> Option<Ray>;
+
+    /// Camera space depth of the point 't' along a Ray created by this camera.
+    /// Defaults to the distance from the camera which is the only meaningful
+    /// measure for the panoramic projections.
+    fn depth(&self, ray: &Ray, t: Float) -> Float {
+        t * ray.dir.norm()
+    }
+
+    /// Whether the camera has no aperture, so the 'lens' sample doesn't change
+    /// the Rays it generates
+    fn pinhole(&self) -> bool {
+        true
+    }
+}
+
+/// Replacement up directions for a camera that looks along its up direction.
+/// The first one that isn't close to the viewing direction is used.
+const FALLBACK_UP: [Vec3<Float>; 2] = [
+    Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    },
+    Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    },
+];
+
+/// Where the camera is, the point it is looking at and which way is up
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct View {
+    pub eye: Vec3<Float>,
+    pub look: Vec3<Float>,
+    /// Direction that points up in the image. It doesn't have to be
+    /// perpendicular to the viewing direction, only not parallel to it.
+    pub up: Vec3<Float>,
+    /// Rotation of the camera about the viewing direction in degrees, positive
+    /// angles turn the up direction of the camera to the right
+    pub roll: Float,
+}
+
+impl View {
+    /// Camera at 'eye' looking at 'look' with the Y axis up
+    pub fn look_at(eye: Vec3<Float>, look: Vec3<Float>) -> View {
+        View {
+            eye: eye,
+            look: look,
+            up: UP,
+            roll: 0.0,
+        }
+    }
+
+    /// Camera at 'eye' that is turned by 'orientation' from looking along -Z
+    /// with the Y axis up, the convention of glTF and most modeling programs
+    pub fn oriented(eye: Vec3<Float>, orientation: &Orientation) -> View {
+        View {
+            eye: eye,
+            look: eye + orientation.rotate(Vec3::new(0.0, 0.0, -1.0)),
+            up: orientation.rotate(UP),
+            roll: 0.0,
+        }
+    }
+}
+
+/// Rotation of a camera, as other programs store it
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Orientation {
+    /// Unit quaternion [x, y, z, w], it is normalized before use
+    Quaternion([Float; 4]),
+    /// Euler angles in degrees, applied about X, then Y, then Z like the
+    /// rotation of Objects
+    Euler([Float; 3]),
+}
+
+impl Orientation {
+    pub fn rotate(&self, v: Vec3<Float>) -> Vec3<Float> {
+        match *self {
+            Orientation::Quaternion(q) => {
+                let length = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+                let axis = Vec3::new(q[0], q[1], q[2]) / length;
+                let w = q[3] / length;
+                // v + 2w (q x v) + 2 q x (q x v)
+                let t = na::cross(&axis, &v) * 2.0;
+                v + t * w + na::cross(&axis, &t)
+            }
+            Orientation::Euler(angles) => {
+                Rot3::new_with_euler_angles(
+                    angles[0].to_radians(),
+                    angles[1].to_radians(),
+                    angles[2].to_radians(),
+                ) * v
+            }
+        }
+    }
+}
+
+/// Selects the Camera used for a render along with its parameters
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Projection {
+    /// Camera with the given vertical field of view in radians. Acts as a
+    /// pinhole camera unless the lens has an aperture.
+    Perspective { fov: Float, lens: Lens },
+    /// Parallel projection that covers 'width' world units horizontally and vertically
+    Orthographic { width: Float },
+    /// Equidistant fisheye, 'fov' is the angle in radians covered by the image circle
+    Fisheye { fov: Float },
+    /// Full 360 by 180 degree panorama
+    Equirectangular,
+}
+
+impl Projection {
+    /// Field of view of a camera with the given focal length and sensor size (both in mm)
+    pub fn fov_from_focal_length(focal_length: Float, sensor_size: Float) -> Float {
+        2.0 * (sensor_size / (2.0 * focal_length)).atan()
+    }
+
+    /// Creates the Camera that moves from the 'open' View at the start of the
+    /// frame to the 'close' View at the end of the frame.
+    pub fn camera(&self, open: View, close: View) -> Box<Camera> {
+        let basis = MovingBasis {
+            start: Basis::new(&open),
+            end: Basis::new(&close),
+        };
+        match *self {
+            Projection::Perspective { fov, lens } => {
+                // Autofocus on the point the camera is looking at
+                let focus_distance = lens
+                    .focus_distance
+                    .unwrap_or_else(|| na::dot(&(open.look - open.eye), &basis.start.w));
+                Box::new(PerspectiveCamera::new(basis, fov, lens, focus_distance))
+            }
+            Projection::Orthographic { width } => Box::new(OrthographicCamera::new(basis, width)),
+            Projection::Fisheye { fov } => Box::new(FisheyeCamera::new(basis, fov)),
+            Projection::Equirectangular => Box::new(EquirectangularCamera::new(basis)),
+        }
+    }
+}
+
+/// Thin lens parameters of the perspective camera
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lens {
+    /// Radius of the aperture. A radius of 0 gives a pinhole camera where everything is in focus.
+    pub aperture: Float,
+    /// Distance from the eye to the plane in focus, None focuses on the look point
+    pub focus_distance: Option<Float>,
+    /// Number of aperture blades. Less than 3 gives a circular aperture,
+    /// otherwise the aperture is a regular polygon.
+    pub blades: u32,
+    /// Rotation of the polygonal aperture in radians
+    pub rotation: Float,
+}
+
+impl Lens {
+    /// A lens with no aperture
+    pub fn pinhole() -> Lens {
+        Lens {
+            aperture: 0.0,
+            focus_distance: None,
+            blades: 0,
+            rotation: 0.0,
+        }
+    }
+
+    /// Maps a uniform sample in the unit square to a uniformly distributed
+    /// point on the aperture, relative to its center.
+    fn sample_aperture(&self, sample: (Float, Float)) -> (Float, Float) {
+        let (s, t) = sample;
+        if self.blades < 3 {
+            let r = self.aperture * s.sqrt();
+            let phi = 2.0 * PI * t;
+            return (r * phi.cos(), r * phi.sin());
+        }
+        // Pick one of the triangles fanning out from the center of the polygon and
+        // reuse the remainder of the sample to pick a point within that triangle
+        let blades = self.blades as Float;
+        let scaled = s * blades;
+        let blade = scaled.floor().min(blades - 1.0);
+        let s = scaled - blade;
+        let step = 2.0 * PI / blades;
+        let a0 = self.rotation + blade * step;
+        let a1 = a0 + step;
+        let r = s.sqrt() * self.aperture;
+        (
+            r * ((1.0 - t) * a0.cos() + t * a1.cos()),
+            r * ((1.0 - t) * a0.sin() + t * a1.sin()),
+        )
+    }
+}
+
+/// Position and orthonormal U,V,W basis of a camera. W is the viewing direction,
+/// U points to the right and V points up.
+#[derive(Copy, Clone)]
+pub struct Basis {
+    pub eye: Vec3<Float>,
+    pub u: Vec3<Float>,
+    pub v: Vec3<Float>,
+    pub w: Vec3<Float>,
+}
+
+impl Basis {
+    pub fn new(view: &View) -> Basis {
+        let eye_at = (view.eye - view.look).normalize();
+        // Looking straight along the up direction leaves the sides undefined
+        let mut up = view.up.normalize();
+        if na::norm(&na::cross(&eye_at, &up)) <= 1e-6 {
+            up = FALLBACK_UP
+                .iter()
+                .cloned()
+                .find(|axis| na::dot(axis, &eye_at).abs() < 0.9)
+                .unwrap_or(FALLBACK_UP[0]);
+        }
+        let u = na::cross(&eye_at, &up).normalize();
+        let v = na::cross(&u, &eye_at).normalize();
+        let (sin, cos) = view.roll.to_radians().sin_cos();
+        let (u, v) = (u * cos - v * sin, v * cos + u * sin);
+        let w = na::cross(&u, &v).normalize();
+        Basis {
+            eye: view.eye,
+            u: u,
+            v: v,
+            w: w,
+        }
+    }
+}
+
+/// Basis of a camera that moves linearly over the frame. The basis vectors are
+/// interpolated and re-orthogonalized, which is accurate for the small rotations
+/// that happen between two frames.
+#[derive(Copy, Clone)]
+pub struct MovingBasis {
+    pub start: Basis,
+    pub end: Basis,
+}
+
+impl MovingBasis {
+    pub fn at(&self, time: Float) -> Basis {
+        if time <= 0.0 {
+            return self.start;
+        }
+        let lerp = |a: Vec3<Float>, b: Vec3<Float>| a * (1.0 - time) + b * time;
+        let w = lerp(self.start.w, self.end.w).normalize();
+        let v = lerp(self.start.v, self.end.v);
+        let u = na::cross(&v, &w).normalize();
+        Basis {
+            eye: lerp(self.start.eye, self.end.eye),
+            u: u,
+            v: na::cross(&w, &u),
+            w: w,
+        }
+    }
+}
+
+/// Perspective camera using the thin lens model. The image plane is placed at
+/// distance 1 in front of the eye and scaled to cover the field of view. With an
+/// aperture the Ray origins are spread over the lens and all Rays through a pixel
+/// converge on the plane in focus, blurring everything in front of and behind it.
+pub struct PerspectiveCamera {
+    basis: MovingBasis,
+    scale: Float,
+    lens: Lens,
+    focus_distance: Float,
+}
+
+impl PerspectiveCamera {
+    pub fn new(
+        basis: MovingBasis,
+        fov: Float,
+        lens: Lens,
+        focus_distance: Float,
+    ) -> PerspectiveCamera {
+        PerspectiveCamera {
+            basis: basis,
+            scale: (fov / 2.0).tan(),
+            lens: lens,
+            focus_distance: focus_distance,
+        }
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn generate_ray(&self, x: Float, y: Float, lens: (Float, Float), time: Float) -> Option<Ray> {
+        let b = &self.basis.at(time);
+        let dir = b.u * (x * self.scale) + b.v * (y * self.scale) + b.w;
+        if self.lens.aperture <= 0.0 {
+            return Some(Ray {
+                src: b.eye,
+                dir: dir.normalize(),
+                time: time,
+            });
+        }
+        // 'dir' has unit length along W so this is the point on the plane in focus
+        let focus = b.eye + dir * self.focus_distance;
+        let (lx, ly) = self.lens.sample_aperture(lens);
+        let src = b.eye + b.u * lx + b.v * ly;
+        Some(Ray {
+            src: src,
+            dir: (focus - src).normalize(),
+            time: time,
+        })
+    }
+
+    fn depth(&self, ray: &Ray, t: Float) -> Float {
+        t * na::dot(&ray.dir, &self.basis.at(ray.time).w)
+    }
+
+    fn pinhole(&self) -> bool {
+        self.lens.aperture <= 0.0
+    }
+}
+
+/// Orthographic camera. All rays are parallel to the viewing direction and
+/// start on a plane through the eye.
+pub struct OrthographicCamera {
+    basis: MovingBasis,
+    half_width: Float,
+}
+
+impl OrthographicCamera {
+    pub fn new(basis: MovingBasis, width: Float) -> OrthographicCamera {
+        OrthographicCamera {
+            basis: basis,
+            half_width: width / 2.0,
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn generate_ray(&self, x: Float, y: Float, _lens: (Float, Float), time: Float) -> Option<Ray> {
+        let b = &self.basis.at(time);
+        Some(Ray {
+            src: b.eye + b.u * (x * self.half_width) + b.v * (y * self.half_width),
+            dir: b.w,
+            time: time,
+        })
+    }
+
+    fn depth(&self, _ray: &Ray, t: Float) -> Float {
+        t
+    }
+}
+
+/// Equidistant fisheye. The angle from the viewing direction is proportional
+/// to the distance from the center of the image.
+pub struct FisheyeCamera {
+    basis: MovingBasis,
+    half_fov: Float,
+}
+
+impl FisheyeCamera {
+    pub fn new(basis: MovingBasis, fov: Float) -> FisheyeCamera {
+        FisheyeCamera {
+            basis: basis,
+            half_fov: fov / 2.0,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn generate_ray(&self, x: Float, y: Float, _lens: (Float, Float), time: Float) -> Option<Ray> {
+        let b = &self.basis.at(time);
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = r * self.half_fov;
+        let dir = if r > 0.0 {
+            (b.u * (x / r) + b.v * (y / r)) * theta.sin() + b.w * theta.cos()
+        } else {
+            b.w
+        };
+        Some(Ray {
+            src: b.eye,
+            dir: dir.normalize(),
+            time: time,
+        })
+    }
+}
+
+/// Equirectangular (latitude / longitude) panorama. The horizontal axis covers
+/// 360 degrees of longitude and the vertical axis 180 degrees of latitude, with
+/// the viewing direction in the center of the image.
+pub struct EquirectangularCamera {
+    basis: MovingBasis,
+}
+
+impl EquirectangularCamera {
+    pub fn new(basis: MovingBasis) -> EquirectangularCamera {
+        EquirectangularCamera { basis: basis }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn generate_ray(&self, x: Float, y: Float, _lens: (Float, Float), time: Float) -> Option<Ray> {
+        let b = &self.basis.at(time);
+        let phi = x * PI;
+        let theta = y * PI / 2.0;
+        let dir = (b.u * phi.sin() + b.w * phi.cos()) * theta.cos() + b.v * theta.sin();
+        Some(Ray {
+            src: b.eye,
+            dir: dir.normalize(),
+            time: time,
+        })
+    }
+}
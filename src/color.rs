@@ -0,0 +1,159 @@
+//! Turns the linear radiance the renderer computes into 8 bit pixels. The
+//! color is scaled by the exposure, compressed into the displayable range by a
+//! tone mapping operator, encoded with the sRGB transfer function and finally
+//! quantized, optionally with dithering to break up banding in smooth gradients.
+
+use na::Vec3;
+
+use largest_of;
+use precision::Float;
+
+/// Operator that maps the unbounded radiance to [0, 1]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ToneMap {
+    /// Cuts off everything above 1
+    Clamp,
+    /// x / (1 + x), compresses highlights but flattens contrast
+    Reinhard,
+    /// John Hable's filmic curve from Uncharted 2, with a toe and a shoulder
+    Filmic,
+    /// Stephen Hill's fit of the ACES reference and output transforms
+    Aces,
+}
+
+impl ToneMap {
+    pub fn parse(name: &str) -> Option<ToneMap> {
+        match name {
+            "clamp" => Some(ToneMap::Clamp),
+            "reinhard" => Some(ToneMap::Reinhard),
+            "filmic" => Some(ToneMap::Filmic),
+            "aces" => Some(ToneMap::Aces),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, c: Vec3<Float>) -> Vec3<Float> {
+        let c = Vec3::new(largest_of(c.x), largest_of(c.y), largest_of(c.z));
+        match *self {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => Vec3::new(c.x / (1.0 + c.x), c.y / (1.0 + c.y), c.z / (1.0 + c.z)),
+            ToneMap::Filmic => {
+                // The curve is normalized so that the white point maps to 1
+                const EXPOSURE_BIAS: Float = 2.0;
+                const WHITE: Float = 11.2;
+                let scale = 1.0 / hable(WHITE);
+                Vec3::new(
+                    hable(c.x * EXPOSURE_BIAS) * scale,
+                    hable(c.y * EXPOSURE_BIAS) * scale,
+                    hable(c.z * EXPOSURE_BIAS) * scale,
+                )
+            }
+            ToneMap::Aces => {
+                let input = [
+                    [0.59719, 0.35458, 0.04823],
+                    [0.07600, 0.90834, 0.01566],
+                    [0.02840, 0.13383, 0.83777],
+                ];
+                let output = [
+                    [1.60475, -0.53108, -0.07367],
+                    [-0.10208, 1.10813, -0.00605],
+                    [-0.00327, -0.07276, 1.07602],
+                ];
+                let v = multiply(&input, c);
+                let fit = |x: Float| {
+                    (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.432951) + 0.238081)
+                };
+                multiply(&output, Vec3::new(fit(v.x), fit(v.y), fit(v.z)))
+            }
+        }
+    }
+}
+
+/// Everything that happens to a color between the renderer and the image file
+#[derive(Copy, Clone)]
+pub struct ColorPipeline {
+    /// Exposure adjustment in stops, each one doubles the brightness
+    pub exposure: Float,
+    pub tone_map: ToneMap,
+    /// Encode with the sRGB transfer function, otherwise the linear values are
+    /// written as they are
+    pub srgb: bool,
+    /// Add noise of one quantization step before rounding
+    pub dither: bool,
+}
+
+impl ColorPipeline {
+    /// 8 bit value of the linear 'color' of the pixel at 'x', 'y'. The position
+    /// only seeds the dither so every frame gets the same pattern.
+    pub fn encode(&self, color: Vec3<Float>, x: u32, y: u32) -> [u8; 3] {
+        let mapped = self.tone_map.apply(color * self.exposure.exp2());
+        let encode = |v: Float| {
+            let v = v.max(0.0).min(1.0);
+            if self.srgb {
+                linear_to_srgb(v)
+            } else {
+                v
+            }
+        };
+        let offset = if self.dither {
+            triangular_noise(x, y)
+        } else {
+            [0.0; 3]
+        };
+        let quantize =
+            |v: Float, noise: Float| (v * 255.0 + noise).round().max(0.0).min(255.0) as u8;
+        [
+            quantize(encode(mapped.x), offset[0]),
+            quantize(encode(mapped.y), offset[1]),
+            quantize(encode(mapped.z), offset[2]),
+        ]
+    }
+}
+
+fn hable(x: Float) -> Float {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn multiply(m: &[[Float; 3]; 3], v: Vec3<Float>) -> Vec3<Float> {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+}
+
+fn linear_to_srgb(v: Float) -> Float {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Noise in (-1, 1) with a triangular distribution for each channel, hashed
+/// from the pixel position
+fn triangular_noise(x: u32, y: u32) -> [Float; 3] {
+    let mut noise = [0.0; 3];
+    for (channel, n) in noise.iter_mut().enumerate() {
+        let a = hash(x, y, 2 * channel as u32);
+        let b = hash(x, y, 2 * channel as u32 + 1);
+        *n = a + b - 1.0;
+    }
+    noise
+}
+
+/// Uniform value in [0, 1) for the pixel and 'seed'
+fn hash(x: u32, y: u32, seed: u32) -> Float {
+    let mut h = x
+        .wrapping_mul(0x8da6_b343)
+        .wrapping_add(y.wrapping_mul(0xd816_3841))
+        .wrapping_add(seed.wrapping_mul(0xcb1a_b31f));
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    (h >> 8) as Float / (1u32 << 24) as Float
+}
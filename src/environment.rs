@@ -0,0 +1,345 @@
+//! Environment surrounding the scene. It is seen by every Ray that misses all
+//! of the Surfaces and lights the scene in the AO and path traced render modes.
+//! The environment is either a solid color, a vertical gradient or an
+//! equirectangular (latitude / longitude) image or an analytic daylight sky,
+//! and can be rotated about the UP axis and scaled in intensity. Image
+//! environments are importance sampled so the bright parts of the image, like
+//! the sun, are found with few samples.
+
+use image;
+use image::hdr::HDRDecoder;
+use na;
+use na::{Norm, Vec3};
+use std::fs::File;
+use std::io::BufReader;
+
+use largest_of;
+use precision::{consts::PI, Float};
+use sky::Sky;
+
+/// What the environment looks like before it is rotated and scaled
+pub enum Background {
+    Solid(Vec3<Float>),
+    /// Blends from 'bottom' straight down to 'top' straight up
+    Gradient {
+        bottom: Vec3<Float>,
+        top: Vec3<Float>,
+    },
+    Image(EnvironmentMap),
+    Sky(Sky),
+}
+
+pub struct Environment {
+    background: Background,
+    /// Rotation about the UP axis in radians
+    rotation: Float,
+    intensity: Float,
+}
+
+impl Environment {
+    pub fn new(background: Background, rotation: Float, intensity: Float) -> Environment {
+        Environment {
+            background: background,
+            rotation: rotation,
+            intensity: intensity,
+        }
+    }
+
+    /// Radiance arriving from the direction 'dir'
+    pub fn radiance(&self, dir: Vec3<Float>) -> Vec3<Float> {
+        let dir = rotate_y(dir.normalize(), -self.rotation);
+        let color = match self.background {
+            Background::Solid(color) => color,
+            Background::Gradient { bottom, top } => {
+                let t = (dir.y + 1.0) * 0.5;
+                bottom * (1.0 - t) + top * t
+            }
+            Background::Image(ref map) => map.lookup(dir),
+            Background::Sky(ref sky) => sky.radiance(dir),
+        };
+        color * self.intensity
+    }
+
+    /// Direction towards and color of the sun that goes with a sky background,
+    /// which is added to the scene as a directional light
+    pub fn sun(&self) -> Option<(Vec3<Float>, Vec3<Float>)> {
+        match self.background {
+            Background::Sky(ref sky) => Some((
+                rotate_y(sky.sun_direction(), self.rotation),
+                sky.sun_color() * self.intensity,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Picks a direction to sample the environment in from a uniform sample in
+    /// [0, 1) x [0, 1). Returns the direction and its probability density per
+    /// solid angle. Images are sampled in proportion to their brightness, the
+    /// other backgrounds uniformly over the sphere.
+    pub fn sample(&self, u: (Float, Float)) -> (Vec3<Float>, Float) {
+        match self.background {
+            Background::Image(ref map) => {
+                let (dir, pdf) = map.sample(u);
+                (rotate_y(dir, self.rotation), pdf)
+            }
+            _ => {
+                let y = 1.0 - 2.0 * u.0;
+                let r = largest_of(1.0 - y * y).sqrt();
+                let phi = 2.0 * PI * u.1;
+                (Vec3::new(r * phi.sin(), y, r * phi.cos()), 1.0 / (4.0 * PI))
+            }
+        }
+    }
+
+    /// Probability density per solid angle of 'sample' picking 'dir'
+    pub fn pdf(&self, dir: Vec3<Float>) -> Float {
+        match self.background {
+            Background::Image(ref map) => map.pdf(rotate_y(dir.normalize(), -self.rotation)),
+            _ => 1.0 / (4.0 * PI),
+        }
+    }
+}
+
+/// Equirectangular image together with the distributions used to importance
+/// sample it. The left and right edges of the image are straight behind (-Z),
+/// the center is straight ahead (+Z) and the top row is straight up.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3<Float>>,
+    /// Cumulative distribution of picking each row, 'height + 1' entries
+    marginal: Vec<Float>,
+    /// Cumulative distribution of picking each pixel within its row,
+    /// 'width + 1' entries per row
+    conditional: Vec<Float>,
+    /// Sum of the weights of all pixels
+    total: Float,
+}
+
+impl EnvironmentMap {
+    /// Loads a Radiance HDR image, or any other image format which is then
+    /// assumed to be sRGB encoded and converted to linear values.
+    pub fn load(path: &str) -> Result<EnvironmentMap, String> {
+        let error = |e: &::std::fmt::Display| format!("Can't load environment '{}': {}", path, e);
+        let (width, height, pixels) = if path.to_lowercase().ends_with(".hdr") {
+            let file = File::open(path).map_err(|e| error(&e))?;
+            let decoder = HDRDecoder::new(BufReader::new(file)).map_err(|e| error(&e))?;
+            let meta = decoder.metadata();
+            let data = decoder.read_image_hdr().map_err(|e| error(&e))?;
+            let pixels = data
+                .iter()
+                .map(|p| Vec3::new(p[0] as Float, p[1] as Float, p[2] as Float))
+                .collect();
+            (meta.width as usize, meta.height as usize, pixels)
+        } else {
+            let img = image::open(path).map_err(|e| error(&e))?.to_rgb();
+            let pixels = img
+                .pixels()
+                .map(|p| {
+                    Vec3::new(
+                        srgb_to_linear(p[0]),
+                        srgb_to_linear(p[1]),
+                        srgb_to_linear(p[2]),
+                    )
+                })
+                .collect();
+            (img.width() as usize, img.height() as usize, pixels)
+        };
+        if width == 0 || height == 0 {
+            return Err(format!("Environment '{}' is empty", path));
+        }
+        Ok(EnvironmentMap::new(width, height, pixels))
+    }
+
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3<Float>>) -> EnvironmentMap {
+        // Pixels near the poles cover less solid angle, weigh them by sin(theta)
+        let mut conditional = Vec::with_capacity((width + 1) * height);
+        let mut marginal = Vec::with_capacity(height + 1);
+        marginal.push(0.0);
+        for y in 0..height {
+            let sin_theta = (PI * (y as Float + 0.5) / height as Float).sin();
+            let mut sum = 0.0;
+            conditional.push(0.0);
+            for x in 0..width {
+                sum += luminance(pixels[y * width + x]) * sin_theta;
+                conditional.push(sum);
+            }
+            let last = marginal[y];
+            marginal.push(last + sum);
+        }
+        let total = marginal[height];
+        EnvironmentMap {
+            width: width,
+            height: height,
+            pixels: pixels,
+            marginal: marginal,
+            conditional: conditional,
+            total: total,
+        }
+    }
+
+    fn texel(&self, dir: Vec3<Float>) -> (usize, usize) {
+        let u = 0.5 + dir.x.atan2(dir.z) / (2.0 * PI);
+        let v = dir.y.max(-1.0).min(1.0).acos() / PI;
+        let x = ((u * self.width as Float) as usize).min(self.width - 1);
+        let y = ((v * self.height as Float) as usize).min(self.height - 1);
+        (x, y)
+    }
+
+    fn lookup(&self, dir: Vec3<Float>) -> Vec3<Float> {
+        let (x, y) = self.texel(dir);
+        self.pixels[y * self.width + x]
+    }
+
+    fn sample(&self, u: (Float, Float)) -> (Vec3<Float>, Float) {
+        if self.total <= 0.0 {
+            // Completely black image, any direction will do
+            return (Vec3::new(0.0, 1.0, 0.0), 1.0 / (4.0 * PI));
+        }
+        let y = search(&self.marginal, u.0 * self.total);
+        let row = &self.conditional[y * (self.width + 1)..(y + 1) * (self.width + 1)];
+        let x = search(row, u.1 * row[self.width]);
+
+        // Reuse what is left of the samples to pick a point within the pixel
+        let within = |cdf: &[Float], i: usize, target: Float| {
+            let span = cdf[i + 1] - cdf[i];
+            if span > 0.0 {
+                ((target - cdf[i]) / span).max(0.0).min(1.0)
+            } else {
+                0.5
+            }
+        };
+        let fy = (y as Float + within(&self.marginal, y, u.0 * self.total)) / self.height as Float;
+        let fx = (x as Float + within(row, x, u.1 * row[self.width])) / self.width as Float;
+        let theta = fy * PI;
+        let phi = (fx - 0.5) * 2.0 * PI;
+        let dir = Vec3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            theta.sin() * phi.cos(),
+        );
+        (dir, self.pdf(dir))
+    }
+
+    fn pdf(&self, dir: Vec3<Float>) -> Float {
+        if self.total <= 0.0 {
+            return 1.0 / (4.0 * PI);
+        }
+        let (x, y) = self.texel(dir);
+        let sin_theta = (PI * (y as Float + 0.5) / self.height as Float).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let row = y * (self.width + 1);
+        let weight = self.conditional[row + x + 1] - self.conditional[row + x];
+        // Density over the image is weight / total per pixel, the image covers
+        // 2 PI by PI radians and a pixel covers sin(theta) less solid angle
+        let pdf_image = weight / self.total * (self.width * self.height) as Float;
+        pdf_image / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+/// Index 'i' of the interval with cdf[i] <= target < cdf[i + 1], skipping
+/// intervals that have no weight.
+fn search(cdf: &[Float], target: Float) -> usize {
+    let count = cdf.len() - 1;
+    let mut lo = 0;
+    let mut hi = count;
+    while lo + 1 < hi {
+        let mid = (lo + hi) / 2;
+        if cdf[mid] <= target {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    // Never return an interval that can't be sampled
+    while lo + 1 < count && cdf[lo + 1] <= cdf[lo] {
+        lo += 1;
+    }
+    lo
+}
+
+fn rotate_y(v: Vec3<Float>, angle: Float) -> Vec3<Float> {
+    let (sin, cos) = angle.sin_cos();
+    Vec3::new(v.x * cos + v.z * sin, v.y, -v.x * sin + v.z * cos)
+}
+
+fn luminance(c: Vec3<Float>) -> Float {
+    na::dot(&c, &Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// Linear value of an 8 bit sRGB encoded channel
+pub fn srgb_to_linear(v: u8) -> Float {
+    let c = v as Float / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_skips_intervals_without_weight() {
+        let cdf = [0.0, 0.0, 1.0, 1.0, 3.0];
+        assert_eq!(search(&cdf, 0.0), 1);
+        assert_eq!(search(&cdf, 0.5), 1);
+        assert_eq!(search(&cdf, 1.0), 3);
+        assert_eq!(search(&cdf, 2.9), 3);
+    }
+
+    /// Image whose pixels get brighter to the right and towards the top
+    fn ramp(width: usize, height: usize) -> EnvironmentMap {
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let v = 0.1 + (x + 2 * (height - y)) as Float;
+                pixels.push(Vec3::new(v, v, v));
+            }
+        }
+        EnvironmentMap::new(width, height, pixels)
+    }
+
+    #[test]
+    fn pdf_of_a_uniform_image_is_uniform() {
+        let map = EnvironmentMap::new(32, 64, vec![Vec3::new(1.0, 1.0, 1.0); 32 * 64]);
+        let uniform = 1.0 / (4.0 * PI);
+        for &dir in [
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.5, 0.0),
+            Vec3::new(0.0, -0.9, -0.4),
+        ]
+        .iter()
+        {
+            let pdf = map.pdf(dir.normalize());
+            assert!((pdf / uniform - 1.0).abs() < 0.01, "{}", pdf);
+        }
+    }
+
+    #[test]
+    fn sampling_covers_the_sphere() {
+        // Averaging 1 / pdf over directions sampled with that pdf gives the
+        // solid angle of the whole sphere
+        let map = ramp(16, 8);
+        let n = 64;
+        let mut sum = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let u = (
+                    (i as Float + 0.5) / n as Float,
+                    (j as Float + 0.5) / n as Float,
+                );
+                let (dir, pdf) = map.sample(u);
+                assert!(pdf > 0.0);
+                assert!((pdf - map.pdf(dir)).abs() <= 1e-3 * pdf);
+                sum += 1.0 / pdf;
+            }
+        }
+        let area = sum / (n * n) as Float;
+        assert!((area / (4.0 * PI) - 1.0).abs() < 0.02, "{}", area);
+    }
+}
@@ -0,0 +1,483 @@
+//! Encoders that assemble the rendered frames into an animation. GIF and APNG
+//! are encoded without relying on any external tools, videos are encoded by an
+//! external program the frames are piped into. Frames are handed to the encoder
+//! one at a time as soon as they are rendered, so the whole sequence never has
+//! to be kept in memory.
+
+use color_quant::NeuQuant;
+use deflate;
+use gif;
+use image::RgbImage;
+use png;
+use png::HasParameters;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::thread;
+
+use precision::Float;
+
+/// Quality of the NeuQuant palette, 1 is the best and slowest and 30 the fastest
+const QUANTIZER_SAMPLING: i32 = 10;
+
+/// Trait for the animation formats the frames can be exported to
+pub trait AnimationEncoder {
+    /// Appends the next frame of the animation
+    fn add_frame(&mut self, frame: &RgbImage) -> io::Result<()>;
+
+    /// Completes the file once every frame has been added
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Animated GIF. Every frame is quantized to its own palette of 256 colors,
+/// optionally with Floyd-Steinberg dithering to hide the banding in gradients.
+pub struct GifEncoder {
+    encoder: Option<gif::Encoder<BufWriter<File>>>,
+    path: String,
+    framerate: u32,
+    /// Number of frames written so far and the hundredths of a second they
+    /// are shown for in total
+    frames: u64,
+    shown: u64,
+    dither: bool,
+}
+
+impl GifEncoder {
+    pub fn new(path: &str, framerate: u32, dither: bool) -> GifEncoder {
+        GifEncoder {
+            encoder: None,
+            path: path.to_string(),
+            framerate: framerate,
+            frames: 0,
+            shown: 0,
+            dither: dither,
+        }
+    }
+
+    /// Delay of the next frame in hundredths of a second. GIF delays are whole
+    /// hundredths, so the rounding error is carried over to the next frame and
+    /// 60 fps alternates between delays of 2 and 1 instead of playing at 50 fps.
+    fn next_delay(&mut self) -> u16 {
+        self.frames += 1;
+        let framerate = self.framerate as u64;
+        // Time the frame ends at with the exact framerate, rounded to the nearest hundredth
+        let end = (self.frames * 200 + framerate) / (2 * framerate);
+        // Delays can't be shorter than 1/100 of a second, faster animations play slower
+        let delay = end.saturating_sub(self.shown).max(1);
+        self.shown += delay;
+        delay as u16
+    }
+}
+
+impl AnimationEncoder for GifEncoder {
+    fn add_frame(&mut self, frame: &RgbImage) -> io::Result<()> {
+        let (width, height) = (frame.width() as u16, frame.height() as u16);
+        let rgba: Vec<u8> = frame
+            .pixels()
+            .flat_map(|p| vec![p[0], p[1], p[2], 255])
+            .collect();
+        let quantizer = NeuQuant::new(QUANTIZER_SAMPLING, 256, &rgba);
+        let palette = quantizer.color_map_rgb();
+        let indices = if self.dither {
+            dither(frame, &quantizer)
+        } else {
+            rgba.chunks(4)
+                .map(|p| quantizer.index_of(p) as u8)
+                .collect()
+        };
+
+        if self.encoder.is_none() {
+            // The palette of the first frame doubles as the global palette
+            let file = File::create(&self.path)?;
+            let mut encoder = gif::Encoder::new(BufWriter::new(file), width, height, &palette)?;
+            encoder.write_extension(gif::ExtensionData::Repetitions(gif::Repeat::Infinite))?;
+            self.encoder = Some(encoder);
+        }
+        let gif_frame = gif::Frame {
+            delay: self.next_delay(),
+            width: width,
+            height: height,
+            palette: Some(palette),
+            buffer: indices.into(),
+            ..gif::Frame::default()
+        };
+        match self.encoder {
+            Some(ref mut encoder) => encoder.write_frame(&gif_frame),
+            None => unreachable!(),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Dropping the encoder writes the trailer
+        match self.encoder.take() {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("No frames were written to '{}'", self.path),
+            )),
+        }
+    }
+}
+
+/// Maps every pixel to the palette while diffusing the quantization error onto
+/// the neighbouring pixels that have not been mapped yet (Floyd-Steinberg).
+fn dither(frame: &RgbImage, quantizer: &NeuQuant) -> Vec<u8> {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let palette = quantizer.color_map_rgb();
+    let mut error: Vec<[Float; 3]> = frame
+        .pixels()
+        .map(|p| [p[0] as Float, p[1] as Float, p[2] as Float])
+        .collect();
+    let mut indices = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let wanted = error[y * width + x];
+            let pixel = [
+                clamp_u8(wanted[0]),
+                clamp_u8(wanted[1]),
+                clamp_u8(wanted[2]),
+                255,
+            ];
+            let index = quantizer.index_of(&pixel);
+            indices.push(index as u8);
+            let chosen = &palette[index * 3..index * 3 + 3];
+            let diff = [
+                wanted[0] - chosen[0] as Float,
+                wanted[1] - chosen[1] as Float,
+                wanted[2] - chosen[2] as Float,
+            ];
+            let mut spread = |dx: isize, dy: usize, weight: Float| {
+                let nx = x as isize + dx;
+                let ny = y + dy;
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    let e = &mut error[ny * width + nx as usize];
+                    for c in 0..3 {
+                        e[c] += diff[c] * weight;
+                    }
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
+    indices
+}
+
+fn clamp_u8(v: Float) -> u8 {
+    v.round().max(0.0).min(255.0) as u8
+}
+
+/// Animated PNG. Frames are stored losslessly in full color and the file plays
+/// as a still image of the first frame in viewers without APNG support.
+/// The number of frames has to be known up front since it is part of the header.
+pub struct ApngEncoder {
+    writer: Option<png::Writer<BufWriter<File>>>,
+    path: String,
+    frames: u32,
+    written: u32,
+    /// Sequence number shared by the frame control and frame data chunks
+    sequence: u32,
+    framerate: u32,
+}
+
+impl ApngEncoder {
+    pub fn new(path: &str, frames: u32, framerate: u32) -> ApngEncoder {
+        ApngEncoder {
+            writer: None,
+            path: path.to_string(),
+            frames: frames,
+            written: 0,
+            sequence: 0,
+            framerate: framerate,
+        }
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence += 1;
+        self.sequence - 1
+    }
+}
+
+impl AnimationEncoder for ApngEncoder {
+    fn add_frame(&mut self, frame: &RgbImage) -> io::Result<()> {
+        let (width, height) = (frame.width(), frame.height());
+        if self.writer.is_none() {
+            let file = File::create(&self.path)?;
+            let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+            encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().map_err(png_error)?;
+            // Animation control: number of frames, 0 plays forever
+            let mut actl = Vec::with_capacity(8);
+            actl.extend_from_slice(&be_u32(self.frames));
+            actl.extend_from_slice(&be_u32(0));
+            writer.write_chunk(*b"acTL", &actl).map_err(png_error)?;
+            self.writer = Some(writer);
+        }
+
+        // Frame control: the frame covers the whole image and replaces the previous one
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend_from_slice(&be_u32(self.next_sequence()));
+        fctl.extend_from_slice(&be_u32(width));
+        fctl.extend_from_slice(&be_u32(height));
+        fctl.extend_from_slice(&be_u32(0));
+        fctl.extend_from_slice(&be_u32(0));
+        fctl.extend_from_slice(&[0, 1]);
+        fctl.extend_from_slice(&[(self.framerate >> 8) as u8, self.framerate as u8]);
+        fctl.extend_from_slice(&[0, 0]);
+        let data = compress(frame);
+
+        // The first frame is stored as the default image, the others in frame data chunks
+        let (name, body) = if self.written == 0 {
+            (*b"IDAT", data)
+        } else {
+            let mut fdat = be_u32(self.next_sequence()).to_vec();
+            fdat.extend_from_slice(&data);
+            (*b"fdAT", fdat)
+        };
+        match self.writer {
+            Some(ref mut writer) => {
+                writer.write_chunk(*b"fcTL", &fctl).map_err(png_error)?;
+                writer.write_chunk(name, &body).map_err(png_error)?;
+            }
+            None => unreachable!(),
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Dropping the writer writes the end chunk
+        self.writer.take();
+        if self.written != self.frames {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "'{}' should have {} frames but {} were written",
+                    self.path, self.frames, self.written
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Filters each scanline with the PNG 'Sub' filter and deflates the result
+fn compress(frame: &RgbImage) -> Vec<u8> {
+    let stride = frame.width() as usize * 3;
+    let mut filtered = Vec::with_capacity((stride + 1) * frame.height() as usize);
+    for line in frame.chunks(stride) {
+        filtered.push(1);
+        for i in 0..stride {
+            let left = if i >= 3 { line[i - 3] } else { 0 };
+            filtered.push(line[i].wrapping_sub(left));
+        }
+    }
+    deflate::deflate_bytes_zlib(&filtered)
+}
+
+fn be_u32(v: u32) -> [u8; 4] {
+    [(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+}
+
+fn png_error(e: png::EncodingError) -> io::Error {
+    match e {
+        png::EncodingError::IoError(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// Format of the frames written to the encoder's stdin
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PipeFormat {
+    /// YUV4MPEG2 with full resolution chroma (4:4:4). The stream describes its
+    /// own size and framerate.
+    Y4m,
+    /// Bare 8 bit RGB pixels, the encoder has to be told the size and framerate
+    Raw,
+}
+
+/// Streams the frames into the stdin of an ffmpeg compatible encoder while they
+/// are rendered. Everything the encoder writes to stderr is collected and
+/// reported if it fails.
+pub struct PipeEncoder {
+    child: Option<Child>,
+    stdin: Option<BufWriter<ChildStdin>>,
+    stderr: Option<thread::JoinHandle<String>>,
+    program: String,
+    format: PipeFormat,
+}
+
+impl PipeEncoder {
+    /// Starts 'program' so that it reads the frames from stdin and writes the
+    /// video encoded with 'codec_args' to 'output'.
+    pub fn new(
+        program: &str,
+        codec_args: &[String],
+        output: &str,
+        format: PipeFormat,
+        size: (u32, u32),
+        framerate: u32,
+    ) -> io::Result<PipeEncoder> {
+        let (width, height) = size;
+        let mut args: Vec<String> = vec!["-y", "-loglevel", "error"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        match format {
+            PipeFormat::Y4m => args.extend(vec!["-f".to_string(), "yuv4mpegpipe".to_string()]),
+            PipeFormat::Raw => args.extend(vec![
+                "-f".to_string(),
+                "rawvideo".to_string(),
+                "-pix_fmt".to_string(),
+                "rgb24".to_string(),
+                "-video_size".to_string(),
+                format!("{}x{}", width, height),
+                "-framerate".to_string(),
+                framerate.to_string(),
+            ]),
+        }
+        args.extend(vec!["-i".to_string(), "-".to_string()]);
+        args.extend(codec_args.iter().cloned());
+        args.push(output.to_string());
+
+        let mut child = Command::new(program)
+            .args(&args)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't start '{}': {}", program, e)))?;
+
+        // Drain stderr on its own thread so a chatty encoder can't block on a full pipe
+        let mut stderr = child.stderr.take().unwrap();
+        let collect = thread::spawn(move || {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text);
+            text
+        });
+        let mut stdin = BufWriter::new(child.stdin.take().unwrap());
+        let mut encoder = PipeEncoder {
+            child: Some(child),
+            stdin: None,
+            stderr: Some(collect),
+            program: program.to_string(),
+            format: format,
+        };
+        if format == PipeFormat::Y4m {
+            let header = format!(
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444\n",
+                width, height, framerate
+            );
+            if let Err(e) = stdin.write_all(header.as_bytes()) {
+                return Err(encoder.failure(e));
+            }
+        }
+        encoder.stdin = Some(stdin);
+        Ok(encoder)
+    }
+
+    /// Waits for the encoder to exit and returns an error describing why it
+    /// failed, falling back to 'cause' if it exited successfully.
+    fn failure(&mut self, cause: io::Error) -> io::Error {
+        match self.wait() {
+            Ok(()) => cause,
+            Err(e) => e,
+        }
+    }
+
+    /// Closes stdin so the encoder sees the end of the stream, then waits for it
+    /// to exit and checks its exit status.
+    fn wait(&mut self) -> io::Result<()> {
+        self.stdin.take();
+        let status = match self.child.take() {
+            Some(mut child) => child.wait()?,
+            None => return Ok(()),
+        };
+        let stderr = self
+            .stderr
+            .take()
+            .and_then(|t| t.join().ok())
+            .unwrap_or_default();
+        if status.success() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("'{}' failed ({}): {}", self.program, status, stderr.trim()),
+        ))
+    }
+}
+
+impl AnimationEncoder for PipeEncoder {
+    fn add_frame(&mut self, frame: &RgbImage) -> io::Result<()> {
+        let result = match self.stdin {
+            Some(ref mut stdin) => match self.format {
+                PipeFormat::Y4m => stdin
+                    .write_all(b"FRAME\n")
+                    .and_then(|_| stdin.write_all(&to_yuv444(frame))),
+                PipeFormat::Raw => stdin.write_all(frame),
+            },
+            None => return Ok(()),
+        };
+        result.map_err(|e| self.failure(e))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(ref mut stdin) = self.stdin {
+            if let Err(e) = stdin.flush() {
+                return Err(self.failure(e));
+            }
+        }
+        self.wait()
+    }
+}
+
+/// Converts the frame to planar Y'CbCr using the BT.601 studio range that Y4M
+/// streams are expected to use.
+fn to_yuv444(frame: &RgbImage) -> Vec<u8> {
+    let count = (frame.width() * frame.height()) as usize;
+    let mut planes = vec![0u8; count * 3];
+    for (i, p) in frame.pixels().enumerate() {
+        let (r, g, b) = (p[0] as Float, p[1] as Float, p[2] as Float);
+        let y = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+        let cb = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+        let cr = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+        planes[i] = clamp_u8(y);
+        planes[count + i] = clamp_u8(cb);
+        planes[2 * count + i] = clamp_u8(cr);
+    }
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn yuv444_uses_the_studio_range() {
+        let mut frame = RgbImage::new(3, 1);
+        frame.put_pixel(0, 0, Rgb([0, 0, 0]));
+        frame.put_pixel(1, 0, Rgb([255, 255, 255]));
+        frame.put_pixel(2, 0, Rgb([255, 0, 0]));
+        // One plane after the other: Y', then Cb, then Cr
+        assert_eq!(
+            to_yuv444(&frame),
+            vec![16, 235, 81, 128, 128, 90, 128, 128, 240]
+        );
+    }
+
+    #[test]
+    fn gif_delays_keep_the_framerate() {
+        let delays = |framerate| {
+            let mut gif = GifEncoder::new("unused.gif", framerate, false);
+            (0..6).map(|_| gif.next_delay()).collect::<Vec<u16>>()
+        };
+        assert_eq!(delays(60), vec![2, 1, 2, 2, 1, 2]);
+        assert_eq!(delays(25), vec![4; 6]);
+        assert_eq!(delays(30), vec![3, 4, 3, 3, 4, 3]);
+        assert_eq!(delays(200), vec![1; 6]);
+    }
+}
@@ -0,0 +1,523 @@
+//! Imports glTF 2.0 scenes, both the '.gltf' JSON with external or embedded
+//! buffers and the binary '.glb' container. The triangle meshes of the default
+//! scene become Mesh Primitives with their vertex normals, texture coordinates,
+//! tangents and colors; metallic/roughness materials become Materials with
+//! microfacet reflection and their textures. Every node with a mesh becomes an
+//! Object named after the node, with the transforms of the node hierarchy
+//! baked into its vertices. The first camera and the first punctual light
+//! (KHR_lights_punctual) are imported as well.
+//!
+//! Parts of glTF the renderer has no equivalent for, like transparency,
+//! skinning and morph targets, are skipped with a warning.
+
+use base64;
+use gltf;
+use gltf::camera::Projection as GltfProjection;
+use gltf::khr_lights_punctual::Kind;
+use gltf::mesh::Mode;
+use gltf::texture::WrappingMode;
+use image;
+use na;
+use na::{Norm, Vec3};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use brdf::Pbr;
+use camera::{Lens, Projection, View};
+use precision::{consts::PI, Float};
+use scene::{Light, Maps, Mesh, Object, Primitive, Transform};
+use texture::{Texture, Wrap};
+use Material;
+
+/// Column major 4x4 matrix, as glTF stores them
+type Matrix = [[Float; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// Name of the Material used by primitives that don't have one
+const DEFAULT_MATERIAL: &str = "default";
+
+/// Everything imported from a glTF file
+pub struct Imported {
+    pub materials: Vec<(String, Material)>,
+    pub objects: Vec<Object>,
+    pub camera: Option<(View, Projection)>,
+    pub light: Option<Light>,
+    /// Parts of the file that were skipped
+    pub warnings: Vec<String>,
+}
+
+/// Reads the default scene of the glTF file at 'path'
+pub fn load(path: &str) -> Result<Imported, String> {
+    let gltf = gltf::Gltf::open(path).map_err(|e| format!("Can't open '{}': {}", path, e))?;
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let buffers = gltf
+        .buffers()
+        .map(|buffer| match buffer.source() {
+            gltf::buffer::Source::Bin => gltf
+                .blob
+                .clone()
+                .ok_or_else(|| format!("'{}' has no binary chunk", path)),
+            gltf::buffer::Source::Uri(uri) => read_uri(base, uri),
+        })
+        .collect::<Result<Vec<Vec<u8>>, String>>()?;
+
+    let mut importer = Importer {
+        buffers: buffers,
+        base: base,
+        textures: HashMap::new(),
+        imported: Imported {
+            materials: Vec::new(),
+            objects: Vec::new(),
+            camera: None,
+            light: None,
+            warnings: Vec::new(),
+        },
+        maps: Vec::new(),
+        lights: Vec::new(),
+    };
+    for material in gltf.materials() {
+        importer.material(&material)?;
+    }
+
+    let scene = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+        Some(scene) => scene,
+        None => return Err(format!("'{}' has no scene", path)),
+    };
+    for node in scene.nodes() {
+        importer.node(&node, &IDENTITY)?;
+    }
+    importer.place_light();
+
+    if gltf.animations().next().is_some() {
+        importer.warn("animations are not imported".to_string());
+    }
+    if importer.imported.objects.is_empty() {
+        return Err(format!("'{}' has no triangle meshes", path));
+    }
+    Ok(importer.imported)
+}
+
+/// Contents of a buffer or image URI, either embedded as base64 or a file
+/// relative to the glTF file
+fn read_uri(base: &Path, uri: &str) -> Result<Vec<u8>, String> {
+    if uri.starts_with("data:") {
+        let data = match uri.find(";base64,") {
+            Some(start) => &uri[start + ";base64,".len()..],
+            None => return Err("Only base64 data URIs are supported".to_string()),
+        };
+        base64::decode(data).map_err(|e| format!("Can't decode data URI: {}", e))
+    } else {
+        let path = base.join(uri.replace("%20", " "));
+        fs::read(&path).map_err(|e| format!("Can't read '{}': {}", path.display(), e))
+    }
+}
+
+struct Importer<'a> {
+    buffers: Vec<Vec<u8>>,
+    base: &'a Path,
+    /// Decoded textures by image index and whether they are sRGB encoded
+    textures: HashMap<(usize, bool), Arc<Texture>>,
+    imported: Imported,
+    /// Textures of each glTF material, in the order of the imported Materials
+    maps: Vec<Maps>,
+    /// Every punctual light found with its world transform
+    lights: Vec<(gltf::khr_lights_punctual::Light<'a>, Matrix)>,
+}
+
+impl<'a> Importer<'a> {
+    fn warn(&mut self, warning: String) {
+        if !self.imported.warnings.contains(&warning) {
+            self.imported.warnings.push(warning);
+        }
+    }
+
+    fn texture(&mut self, texture: &gltf::Texture, srgb: bool) -> Result<Arc<Texture>, String> {
+        let image = texture.source();
+        if let Some(texture) = self.textures.get(&(image.index(), srgb)) {
+            return Ok(texture.clone());
+        }
+        let bytes = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                let buffer = &self.buffers[view.buffer().index()];
+                buffer[view.offset()..view.offset() + view.length()].to_vec()
+            }
+            gltf::image::Source::Uri { uri, .. } => read_uri(self.base, uri)?,
+        };
+        let decoded = image::load_from_memory(&bytes)
+            .map_err(|e| format!("Can't decode image {}: {}", image.index(), e))?;
+        let sampler = texture.sampler();
+        let wrap = |mode| match mode {
+            WrappingMode::ClampToEdge => Wrap::Clamp,
+            WrappingMode::MirroredRepeat => Wrap::Mirror,
+            WrappingMode::Repeat => Wrap::Repeat,
+        };
+        let texture = Arc::new(Texture::new(
+            &decoded,
+            srgb,
+            (wrap(sampler.wrap_s()), wrap(sampler.wrap_t())),
+        ));
+        self.textures.insert((image.index(), srgb), texture.clone());
+        Ok(texture)
+    }
+
+    /// Texture of 'info' if it uses the first set of texture coordinates
+    fn info(
+        &mut self,
+        info: Option<gltf::texture::Info>,
+        srgb: bool,
+    ) -> Result<Option<Arc<Texture>>, String> {
+        match info {
+            Some(ref info) if info.tex_coord() != 0 => {
+                self.warn("only the first set of texture coordinates is used".to_string());
+                Ok(None)
+            }
+            Some(ref info) => self.texture(&info.texture(), srgb).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn material(&mut self, material: &gltf::Material) -> Result<(), String> {
+        let pbr = material.pbr_metallic_roughness();
+        let base = pbr.base_color_factor();
+        let emissive = material.emissive_factor();
+        let name = match material.name() {
+            Some(name) => name.to_string(),
+            None => format!("material{}", self.imported.materials.len()),
+        };
+        if material.alpha_mode() != gltf::material::AlphaMode::Opaque {
+            self.warn(format!("material '{}' is rendered opaque", name));
+        }
+        let normal = match material.normal_texture() {
+            Some(ref normal) if normal.tex_coord() != 0 => {
+                self.warn("only the first set of texture coordinates is used".to_string());
+                None
+            }
+            Some(ref normal) => Some((
+                self.texture(&normal.texture(), false)?,
+                normal.scale() as Float,
+            )),
+            None => None,
+        };
+        let maps = Maps {
+            base_color: self.info(pbr.base_color_texture(), true)?,
+            metallic_roughness: self.info(pbr.metallic_roughness_texture(), false)?,
+            normal: normal,
+            emissive: self.info(material.emissive_texture(), true)?,
+        };
+        self.imported.materials.push((
+            name,
+            Material {
+                amb: vec3([base[0], base[1], base[2]]),
+                reflect: 0.0,
+                roughness: 0.0,
+                emission: vec3(emissive),
+                medium: None,
+                optics: None,
+                pbr: Some(Pbr {
+                    metallic: pbr.metallic_factor() as Float,
+                    roughness: pbr.roughness_factor() as Float,
+                    specular: 0.5,
+                }),
+            },
+        ));
+        self.maps.push(maps);
+        Ok(())
+    }
+
+    /// Index of the Material for primitives without one, which glTF defines
+    /// as a white rough metal
+    fn default_material(&mut self) -> usize {
+        if let Some(index) = self
+            .imported
+            .materials
+            .iter()
+            .position(|m| m.0 == DEFAULT_MATERIAL)
+        {
+            return index;
+        }
+        self.imported.materials.push((
+            DEFAULT_MATERIAL.to_string(),
+            Material {
+                amb: Vec3::new(1.0, 1.0, 1.0),
+                reflect: 0.0,
+                roughness: 0.0,
+                emission: Vec3::new(0.0, 0.0, 0.0),
+                medium: None,
+                optics: None,
+                pbr: Some(Pbr {
+                    metallic: 1.0,
+                    roughness: 1.0,
+                    specular: 0.5,
+                }),
+            },
+        ));
+        self.maps.push(Maps::default());
+        self.imported.materials.len() - 1
+    }
+
+    fn node(&mut self, node: &gltf::Node<'a>, parent: &Matrix) -> Result<(), String> {
+        let m = multiply(parent, &matrix(node.transform().matrix()));
+        if node.skin().is_some() {
+            self.warn("skins are not imported".to_string());
+        }
+        if let Some(camera) = node.camera() {
+            if self.imported.camera.is_none() {
+                let eye = transform_point(&m, Vec3::new(0.0, 0.0, 0.0));
+                let forward = transform_vector(&m, Vec3::new(0.0, 0.0, -1.0)).normalize();
+                let up = transform_vector(&m, Vec3::new(0.0, 1.0, 0.0)).normalize();
+                let projection = match camera.projection() {
+                    GltfProjection::Perspective(p) => Projection::Perspective {
+                        fov: p.yfov() as Float,
+                        lens: Lens::pinhole(),
+                    },
+                    GltfProjection::Orthographic(o) => Projection::Orthographic {
+                        width: 2.0 * o.xmag() as Float,
+                    },
+                };
+                self.imported.camera = Some((
+                    View {
+                        eye: eye,
+                        look: eye + forward,
+                        up: up,
+                        roll: 0.0,
+                    },
+                    projection,
+                ));
+            } else {
+                self.warn("only the first camera is used".to_string());
+            }
+        }
+        if let Some(light) = node.light() {
+            self.lights.push((light, m));
+        }
+        if let Some(mesh) = node.mesh() {
+            let mut primitives = Vec::new();
+            for primitive in mesh.primitives() {
+                if let Some(p) = self.primitive(&primitive, &m)? {
+                    primitives.push(p);
+                }
+            }
+            if !primitives.is_empty() {
+                let name = match node.name() {
+                    Some(name) => name.to_string(),
+                    None => format!("node{}", node.index()),
+                };
+                self.imported.objects.push(Object {
+                    name: name,
+                    pivot: transform_point(&m, Vec3::new(0.0, 0.0, 0.0)),
+                    transform: Transform::identity(),
+                    primitives: primitives,
+                });
+            }
+        }
+        for child in node.children() {
+            self.node(&child, &m)?;
+        }
+        Ok(())
+    }
+
+    /// Mesh Primitive of a glTF primitive, with the node transform 'm' applied
+    fn primitive(
+        &mut self,
+        primitive: &gltf::Primitive,
+        m: &Matrix,
+    ) -> Result<Option<Primitive>, String> {
+        if primitive.mode() != Mode::Triangles {
+            self.warn(format!(
+                "{:?} primitives are not imported",
+                primitive.mode()
+            ));
+            return Ok(None);
+        }
+        if primitive.morph_targets().next().is_some() {
+            self.warn("morph targets are not imported".to_string());
+        }
+        let buffers = &self.buffers;
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()][..]));
+        let positions: Vec<Vec3<Float>> = match reader.read_positions() {
+            Some(positions) => positions.map(|p| transform_point(m, vec3(p))).collect(),
+            None => return Ok(None),
+        };
+
+        // A mirroring transform turns the faces inside out
+        let columns = columns(m);
+        let det = na::dot(&columns.0, &na::cross(&columns.1, &columns.2));
+        let flip = det < 0.0;
+        let normals = reader
+            .read_normals()
+            .map(|normals| normals.map(|n| transform_normal(m, vec3(n))).collect());
+        let tangents = reader.read_tangents().map(|tangents| {
+            tangents
+                .map(|t| {
+                    let v = transform_vector(m, vec3([t[0], t[1], t[2]]));
+                    let v = if na::norm(&v) > 0.0 { v.normalize() } else { v };
+                    [v.x, v.y, v.z, if flip { -t[3] } else { t[3] } as Float]
+                })
+                .collect()
+        });
+        let uvs = reader.read_tex_coords(0).map(|uvs| {
+            uvs.into_f32()
+                .map(|uv| [uv[0] as Float, uv[1] as Float])
+                .collect()
+        });
+        let colors = reader
+            .read_colors(0)
+            .map(|colors| colors.into_rgb_f32().map(vec3).collect());
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        if indices.iter().any(|&i| i >= positions.len()) {
+            return Err("Mesh has an index past the end of its vertices".to_string());
+        }
+        let triangles = indices
+            .chunks(3)
+            .filter(|t| t.len() == 3)
+            .map(|t| {
+                if flip {
+                    [t[0], t[2], t[1]]
+                } else {
+                    [t[0], t[1], t[2]]
+                }
+            })
+            .collect();
+
+        let material = match primitive.material().index() {
+            Some(index) => index,
+            None => self.default_material(),
+        };
+        Ok(Some(Primitive::Mesh {
+            mesh: Arc::new(Mesh {
+                positions: positions,
+                normals: normals,
+                uvs: uvs,
+                tangents: tangents,
+                colors: colors,
+                color_is_albedo: false,
+                triangles: triangles,
+                maps: self.maps[material].clone(),
+            }),
+            material: material,
+        }))
+    }
+
+    /// Turns the first punctual light into the Light of the scene. Its
+    /// intensity is converted to the irradiance it gives, and as the Light
+    /// doesn't fall off with distance point lights are given the irradiance
+    /// they have at the center of the scene.
+    fn place_light(&mut self) {
+        if self.lights.len() > 1 {
+            self.warn("only the first light is used".to_string());
+        }
+        let (color, intensity, kind, m) = match self.lights.first() {
+            Some(&(ref light, m)) => (light.color(), light.intensity(), light.kind(), m),
+            None => return,
+        };
+        let color = vec3(color) * intensity as Float / PI;
+        let position = transform_point(&m, Vec3::new(0.0, 0.0, 0.0));
+        if let Kind::Spot { .. } = kind {
+            self.warn("spot lights are imported as point lights".to_string());
+        }
+        self.imported.light = Some(match kind {
+            Kind::Directional => Light {
+                position: position,
+                color: color,
+                direction: Some(transform_vector(&m, Vec3::new(0.0, 0.0, 1.0)).normalize()),
+            },
+            Kind::Point | Kind::Spot { .. } => {
+                let distance = na::norm(&(self.center() - position)).max(1.0);
+                Light {
+                    position: position,
+                    color: color / (distance * distance),
+                    direction: None,
+                }
+            }
+        });
+    }
+
+    /// Average of the vertices of all of the imported Meshes
+    fn center(&self) -> Vec3<Float> {
+        let mut sum = Vec3::new(0.0, 0.0, 0.0);
+        let mut count = 0;
+        for object in self.imported.objects.iter() {
+            for primitive in object.primitives.iter() {
+                if let Primitive::Mesh { ref mesh, .. } = *primitive {
+                    for p in mesh.positions.iter() {
+                        sum = sum + *p;
+                        count += 1;
+                    }
+                }
+            }
+        }
+        if count == 0 {
+            sum
+        } else {
+            sum / count as Float
+        }
+    }
+}
+
+/// Vector read from the file in the precision of the renderer
+fn vec3(v: [f32; 3]) -> Vec3<Float> {
+    Vec3::new(v[0] as Float, v[1] as Float, v[2] as Float)
+}
+
+fn matrix(m: [[f32; 4]; 4]) -> Matrix {
+    let mut converted = IDENTITY;
+    for (column, m_column) in converted.iter_mut().zip(m.iter()) {
+        for (value, m_value) in column.iter_mut().zip(m_column.iter()) {
+            *value = *m_value as Float;
+        }
+    }
+    converted
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0; 4]; 4];
+    for (column, b_column) in m.iter_mut().zip(b.iter()) {
+        for (row, value) in column.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[k][row] * b_column[k]).sum();
+        }
+    }
+    m
+}
+
+/// The first three columns of the upper 3x3 part of 'm'
+fn columns(m: &Matrix) -> (Vec3<Float>, Vec3<Float>, Vec3<Float>) {
+    (
+        Vec3::new(m[0][0], m[0][1], m[0][2]),
+        Vec3::new(m[1][0], m[1][1], m[1][2]),
+        Vec3::new(m[2][0], m[2][1], m[2][2]),
+    )
+}
+
+fn transform_vector(m: &Matrix, v: Vec3<Float>) -> Vec3<Float> {
+    let (x, y, z) = columns(m);
+    x * v.x + y * v.y + z * v.z
+}
+
+fn transform_point(m: &Matrix, p: Vec3<Float>) -> Vec3<Float> {
+    transform_vector(m, p) + Vec3::new(m[3][0], m[3][1], m[3][2])
+}
+
+/// Normals are transformed by the inverse transpose, which is the cofactor
+/// matrix divided by the determinant
+fn transform_normal(m: &Matrix, n: Vec3<Float>) -> Vec3<Float> {
+    let (x, y, z) = columns(m);
+    let det = na::dot(&x, &na::cross(&y, &z));
+    let cofactor = na::cross(&y, &z) * n.x + na::cross(&z, &x) * n.y + na::cross(&x, &y) * n.z;
+    let normal = cofactor * det.signum();
+    if na::norm(&normal) > 0.0 {
+        normal.normalize()
+    } else {
+        normal
+    }
+}
@@ -2,42 +2,95 @@
 /// Rust RayTracer
 /// Version 0.6
 /// Date: 06/Oct/2016
-
-#[macro_use]
-extern crate lazy_static;
+extern crate base64;
+extern crate color_quant;
+extern crate deflate;
+extern crate getopts;
+extern crate gif;
+extern crate gltf;
 extern crate image;
 extern crate nalgebra as na;
+extern crate png;
 extern crate rand;
 extern crate rayon;
+extern crate ron;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate time;
 
+mod animation;
+mod brdf;
+mod camera;
+mod color;
+mod environment;
+mod export;
+mod gltf_import;
+mod medium;
+mod ply;
+mod precision;
+mod scene;
+mod scene_file;
+mod sky;
+mod spectrum;
+mod stl;
+mod texture;
+mod validate;
+
+use animation::Timeline;
+use brdf::Pbr;
+use camera::{Camera, Lens, Orientation, Projection, View};
+use color::{ColorPipeline, ToneMap};
+use environment::Environment;
+use export::{AnimationEncoder, ApngEncoder, GifEncoder, PipeEncoder, PipeFormat};
+use getopts::Options;
+use image::{ImageBuffer, RgbImage};
+use medium::{Fog, Medium};
 use na::{Norm, Vec3};
-//use std::env;
-//use std::str::FromStr;
-use image::ImageBuffer;
+use precision::{abs, consts, gamma, offset_origin, to_f32, Float};
 use rand::distributions::{Range, Sample};
 use rayon::prelude::*;
-use std::{fs::File, process::Command, thread};
-// use getopts::{optopt,optflag,getopts,OptGroup};
+use scene::{Light, Mesh, Object, Primitive, Scene, SceneDesc, Transform};
+use scene_file::{is_scene_file, BackgroundDesc, EnvironmentDesc, SceneFile};
+use spectrum::{Film, Optics};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, fs::File, io, sync::Arc, thread};
+use validate::Report;
 
 // Define various constants used throughout the program
 
 const MAX_DEPTH: i32 = 10;
-const NEAR: f32 = 1.0;
-const EPSILON: f32 = 1.0 / 10000.0;
+/// Points on emissive Surfaces closer than this aren't sampled for their light
+const MIN_LIGHT_DISTANCE: Float = 1.0 / 10000.0;
 
 // Current version of program super-samples to reduce aliasing
 // so the effective DIM of the final image will be DIM / 2
 const DIM: i32 = 2000;
 const HALFDIM: i32 = DIM / 2;
-const T0: f32 = 0.0;
-const T1: f32 = 100000.0;
+const T0: Float = 0.0;
+const T1: Float = 100000.0;
 const FLIP: usize = (HALFDIM - 1) as usize;
 
-const UP: Vec3<f32> = Vec3 {
-    x: 0.0f32,
-    y: 1.0f32,
-    z: 0.0f32,
+// Default camera. A 90 degree vertical field of view places the image plane
+// spanning [-1, 1] at a distance of 1 from the eye.
+const DEFAULT_FOV: Float = 90.0;
+const DEFAULT_SENSOR_SIZE: Float = 24.0;
+const DEFAULT_FISHEYE_FOV: Float = 180.0;
+
+// Distance below which the corners of STL triangles are welded into one vertex
+const DEFAULT_WELD: Float = 1e-5;
+
+// Number of rays traced for each of the super samples of a pixel. More samples
+// are needed to reduce noise when using depth of field.
+const PIXEL_SAMPLES: i32 = 1;
+
+const UP: Vec3<Float> = Vec3 {
+    x: 0.0,
+    y: 1.0,
+    z: 0.0,
 };
 
 // Define Materials
@@ -48,6 +101,15 @@ const BLUE: Material = Material {
         z: 0.85,
     },
     reflect: 0.0,
+    roughness: 0.0,
+    emission: Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    },
+    medium: None,
+    optics: None,
+    pbr: None,
 };
 const GREEN: Material = Material {
     amb: Vec3 {
@@ -56,6 +118,15 @@ const GREEN: Material = Material {
         z: 0.1,
     },
     reflect: 0.675,
+    roughness: 0.0,
+    emission: Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    },
+    medium: None,
+    optics: None,
+    pbr: None,
 };
 const RED: Material = Material {
     amb: Vec3 {
@@ -64,6 +135,15 @@ const RED: Material = Material {
         z: 0.1,
     },
     reflect: 0.0,
+    roughness: 0.0,
+    emission: Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    },
+    medium: None,
+    optics: None,
+    pbr: None,
 };
 const MIRROR: Material = Material {
     amb: Vec3 {
@@ -72,6 +152,15 @@ const MIRROR: Material = Material {
         z: 0.15,
     },
     reflect: 0.9,
+    roughness: 0.0,
+    emission: Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    },
+    medium: None,
+    optics: None,
+    pbr: None,
 };
 const FLOOR_MAT: Material = Material {
     amb: Vec3 {
@@ -80,6 +169,15 @@ const FLOOR_MAT: Material = Material {
         z: 0.20725,
     },
     reflect: 0.085,
+    roughness: 0.0,
+    emission: Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    },
+    medium: None,
+    optics: None,
+    pbr: None,
 };
 const BRASS: Material = Material {
     amb: Vec3 {
@@ -88,20 +186,29 @@ const BRASS: Material = Material {
         z: 0.027451,
     },
     reflect: 0.0,
+    roughness: 0.0,
+    emission: Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    },
+    medium: None,
+    optics: Some(spectrum::BRASS),
+    pbr: None,
 };
 
 // This is the default background color
-const BKG_COLOR: Vec3<f32> = Vec3 {
-    x: 0.4f32,
-    y: 0.698f32,
-    z: 1.0f32,
+const BKG_COLOR: Vec3<Float> = Vec3 {
+    x: 0.4,
+    y: 0.698,
+    z: 1.0,
 };
 
-const SPHERE_RADIUS :f32 = 1.0f32;
-const SPHERE_LOCATION: Vec3<f32> = na::Vec3{x: 3.5, y: SPHERE_RADIUS, z: 3.5};
+const SPHERE_RADIUS :Float = 1.0;
+const SPHERE_LOCATION: Vec3<Float> = na::Vec3{x: 3.5, y: SPHERE_RADIUS, z: 3.5};
 
 // Define Vertices for various Surfaces
-const FLOOR_VERTS: [Vec3<f32>; 4] = [
+const FLOOR_VERTS: [Vec3<Float>; 4] = [
     Vec3 {
         x: -10.0,
         y: 0.0,
@@ -124,7 +231,7 @@ const FLOOR_VERTS: [Vec3<f32>; 4] = [
     },
 ];
 
-const CUBE: [Vec3<f32>; 8] = [
+const CUBE: [Vec3<Float>; 8] = [
     Vec3 {
         x: 1.0,
         y: 0.0,
@@ -167,48 +274,130 @@ const CUBE: [Vec3<f32>; 8] = [
     },
 ]; // 7
 
-//const LIGHT_POS:Vec3<f32> = Vec3{x:-25f32,y:40.0f32,z:-25f32};
-const LIGHT_RADIUS: f32 = 10f32;
+//const LIGHT_POS:Vec3<Float> = Vec3{x:-25.0,y:40.0,z:-25.0};
+const LIGHT_RADIUS: Float = 10.0;
 const SHADOW_SAMPLES: i32 = 50;
+// Number of steps the inside of a volume is ray marched in for the shaded mode
+const MARCH_STEPS: i32 = 32;
+// Number of points on emissive Surfaces sampled per shaded point
+const EMITTER_SAMPLES: i32 = 16;
+// Number of rays a rough mirror seen from the camera reflects in the shaded
+// mode, deeper reflections cast a single ray
+const GLOSSY_SAMPLES: i32 = 8;
+
+// Directory the AOV passes are written to
+const AOV_DIR: &str = "aovs";
+// Default name of the frame files, the run of '#' is replaced by the frame number
+const FRAME_PATTERN: &str = "render####.png";
+
+// Ambient Occlusion defaults. Occluders further away than AO_DISTANCE
+// from the shaded point are ignored.
+const AO_SAMPLES: i32 = 64;
+const AO_DISTANCE: Float = 2.0;
+// Default frames per second of exported animations
+const FRAMERATE: u32 = 60;
+// Defaults of the external video encoder the frames are streamed into
+const VIDEO_FILE: &str = "movie.mkv";
+const VIDEO_ENCODER: &str = "ffmpeg";
+const VIDEO_CODEC_ARGS: &str = "-c:v libx264 -preset veryslow -crf 0";
+
+/// Builds the description of the default scene. Objects and Materials are
+/// named so they can be animated by a Timeline.
+fn default_scene() -> SceneDesc {
+    let materials = vec![
+        ("blue".to_string(), BLUE),
+        ("green".to_string(), GREEN),
+        ("red".to_string(), RED),
+        ("mirror".to_string(), MIRROR),
+        ("floor".to_string(), FLOOR_MAT),
+        ("brass".to_string(), BRASS),
+    ];
+    let (blue, green, red, mirror, floor, brass) = (0, 1, 2, 3, 4, 5);
+    let sphere = |center: Vec3<Float>, radius: Float, material: usize| Primitive::Sphere {
+        center: center,
+        radius: radius,
+        material: material,
+    };
+    let triangle = |a, b, c, material: usize, pattern: bool| Primitive::Triangle {
+        a: a,
+        b: b,
+        c: c,
+        material: material,
+        pattern: pattern,
+    };
+    let object = |name: &str, pivot: Vec3<Float>, primitives: Vec<Primitive>| Object {
+        name: name.to_string(),
+        pivot: pivot,
+        transform: Transform::identity(),
+        primitives: primitives,
+    };
 
-// Initialize Values that are not compile time constants that are still
-// static relative to the scene / renderer
-lazy_static! {
-    // -----Setup Surfaces-----
-    static ref SURFACES:Vec<Box<Surface>> = vec!(
+    let objects = vec![
         // Add Snowman
-        Sphere::boxed(Vec3::new(0.0,0.5,3.0), 1.0, BLUE),
-        Sphere::boxed(Vec3::new(0.0,1.85,3.0), 0.75, GREEN),
-        Sphere::boxed(Vec3::new(0.0,2.65,3.0), 0.50, RED),
-
+        object(
+            "snowman",
+            Vec3::new(0.0, 0.0, 3.0),
+            vec![
+                sphere(Vec3::new(0.0, 0.5, 3.0), 1.0, blue),
+                sphere(Vec3::new(0.0, 1.85, 3.0), 0.75, green),
+                sphere(Vec3::new(0.0, 2.65, 3.0), 0.50, red),
+            ],
+        ),
         // Add Mirror Sphere
-        Sphere::boxed(SPHERE_LOCATION, SPHERE_RADIUS, MIRROR),
-
+        object(
+            "mirror_sphere",
+            SPHERE_LOCATION,
+            vec![sphere(SPHERE_LOCATION, SPHERE_RADIUS, mirror)],
+        ),
         // Add floor with pattern value set to true
-        Triangle::boxed(FLOOR_VERTS[0], FLOOR_VERTS[1], FLOOR_VERTS[2], FLOOR_MAT, true),
-        Triangle::boxed(FLOOR_VERTS[0], FLOOR_VERTS[2], FLOOR_VERTS[3], FLOOR_MAT, true),
+        object(
+            "floor",
+            Vec3::new(0.0, 0.0, 0.0),
+            vec![
+                triangle(FLOOR_VERTS[0], FLOOR_VERTS[1], FLOOR_VERTS[2], floor, true),
+                triangle(FLOOR_VERTS[0], FLOOR_VERTS[2], FLOOR_VERTS[3], floor, true),
+            ],
+        ),
+        // Add brass cube triangles, pivoting about the center of its base
+        object(
+            "cube",
+            Vec3::new(1.5, 0.0, 1.0),
+            vec![
+                triangle(CUBE[0], CUBE[5], CUBE[1], brass, false),
+                triangle(CUBE[0], CUBE[4], CUBE[5], brass, false),
+                triangle(CUBE[0], CUBE[4], CUBE[3], brass, false),
+                triangle(CUBE[4], CUBE[7], CUBE[3], brass, false),
+                triangle(CUBE[4], CUBE[7], CUBE[5], brass, false),
+                triangle(CUBE[5], CUBE[7], CUBE[6], brass, false),
+                triangle(CUBE[5], CUBE[2], CUBE[6], brass, false),
+                triangle(CUBE[5], CUBE[1], CUBE[2], brass, false),
+                triangle(CUBE[6], CUBE[7], CUBE[3], brass, false),
+                triangle(CUBE[6], CUBE[3], CUBE[2], brass, false),
+            ],
+        ),
+    ];
 
-        // Add brass cube triangles
-        Triangle::boxed(CUBE[0],CUBE[5],CUBE[1], BRASS, false),
-        Triangle::boxed(CUBE[0],CUBE[4],CUBE[5], BRASS, false),
-        Triangle::boxed(CUBE[0],CUBE[4],CUBE[3], BRASS, false),
-        Triangle::boxed(CUBE[4],CUBE[7],CUBE[3], BRASS, false),
-        Triangle::boxed(CUBE[4],CUBE[7],CUBE[5], BRASS, false),
-        Triangle::boxed(CUBE[5],CUBE[7],CUBE[6], BRASS, false),
-        Triangle::boxed(CUBE[5],CUBE[2],CUBE[6], BRASS, false),
-        Triangle::boxed(CUBE[5],CUBE[1],CUBE[2], BRASS, false),
-        Triangle::boxed(CUBE[6],CUBE[7],CUBE[3], BRASS, false),
-        Triangle::boxed(CUBE[6],CUBE[3],CUBE[2], BRASS, false)
-    );
+    SceneDesc {
+        view: View::look_at(Vec3::new(0.0, 1.0, -5.0), SPHERE_LOCATION),
+        light: Light {
+            position: Vec3::new(-10.0, 15.0, -35.5),
+            color: Vec3::new(1.0, 1.0, 1.0),
+            direction: None,
+        },
+        materials: materials,
+        objects: objects,
+        environment: None,
+        fog: None,
+    }
 }
 
 fn main() {
     /*
     let args: Vec<String> = env::args().collect();
-    let mut unwrap:Vec<f32> = Vec::new();
+    let mut unwrap:Vec<Float> = Vec::new();
     let mut error = false;
     for x in 1..args.len() {
-        let parse = f32::from_str(&args[x]);
+        let parse = Float::from_str(&args[x]);
         match parse {
             Ok(num) => unwrap.push(num),
             Err(e) => {
@@ -232,86 +421,1380 @@ fn main() {
     // Retrieve EYE and LOOKAT positions from commandline args
     // if they exist. Otherwise, default to initial values
     let look = SPHERE_LOCATION;
-    let light = Vec3::new(-10f32, 15f32, -35.5f32);
-    let d = SPHERE_RADIUS + 0.75f32;
-    let h = SPHERE_LOCATION.y - 0.25f32;
+    let d = SPHERE_RADIUS + 0.75;
+    let h = SPHERE_LOCATION.y - 0.25;
     let time = 300;
+    let args: Vec<String> = env::args().collect();
+    let config = parse_args(&args);
+    let mut settings = config.settings;
+    let mut color = config.color;
+    let mut environment = config.environment.clone();
+    let mut fog = config.fog;
+
+    // A Timeline overrides the default orbit for the parameters it animates
+    let mut description = default_scene();
+    // An imported camera, or one placed on the command line, replaces the orbit
+    let mut imported_view = None;
+    // The animation saved with a scene, unless another one is given
+    let mut saved_timeline = None;
+    if let Some(path) = config.scene.as_ref().filter(|p| is_scene_file(p)) {
+        // A saved scene is rendered the way it was when it was saved
+        let file = SceneFile::load(path).unwrap_or_else(|e| fail(&e));
+        description = file.scene().unwrap_or_else(|e| fail(&e));
+        imported_view = Some(description.view);
+        settings = file.settings();
+        color = file.color();
+        environment = file.environment.clone();
+        fog = description.fog;
+        saved_timeline = file.animation;
+    } else if let Some(ref path) = config.scene {
+        let imported = gltf_import::load(path).unwrap_or_else(|e| fail(&e));
+        for warning in imported.warnings.iter() {
+            eprintln!("'{}': {}", path, warning);
+        }
+        description.materials = imported.materials;
+        description.objects = imported.objects;
+        if let Some(light) = imported.light {
+            description.light = light;
+        }
+        if let Some((view, projection)) = imported.camera {
+            imported_view = Some(view);
+            if !config.projection_given {
+                settings.projection = projection;
+            }
+        }
+    }
+    if config.view.is_some() {
+        imported_view = config.view;
+    }
+    // A mesh named after an Object replaces its Primitives, keeping its pivot
+    for &(ref path, ref material) in config.meshes.iter() {
+        let mesh = load_mesh(path, config.weld, config.crease).unwrap_or_else(|e| fail(&e));
+        let material = match description.materials.iter().position(|m| &m.0 == material) {
+            Some(index) => index,
+            None => fail(&format!("The scene has no material named '{}'", material)),
+        };
+        let object = mesh_object(path, mesh, material);
+        match description.object_mut(&object.name) {
+            Some(existing) => existing.primitives = object.primitives,
+            None => description.objects.push(object),
+        }
+    }
+    description.environment = environment
+        .as_ref()
+        .map(|env| Arc::new(env.build().unwrap_or_else(|e| fail(&e))));
+    description.fog = fog;
+    for &(ref name, emission) in config.emission.iter() {
+        change_material(&mut description, name, |m| m.emission = emission);
+    }
+    for &(ref name, optics) in config.optics.iter() {
+        change_material(&mut description, name, |m| m.optics = Some(optics));
+    }
+    for &(ref name, roughness) in config.roughness.iter() {
+        change_material(&mut description, name, |m| m.roughness = roughness);
+    }
+    for &(ref name, pbr) in config.pbr.iter() {
+        change_material(&mut description, name, |m| m.pbr = Some(pbr));
+    }
+    for &(ref name, medium) in config.media.iter() {
+        change_material(&mut description, name, |m| m.medium = Some(medium));
+    }
+    // A sky comes with its sun, which replaces the default light
+    if let Some((direction, sun)) = description.environment.as_ref().and_then(|env| env.sun()) {
+        description.light.direction = Some(direction);
+        description.light.color = sun;
+    }
+    // Broken input is reported up front instead of rendering NaNs
+    let problems = validate::check(&description);
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            let report = Report {
+                desc: &description,
+                problem: problem,
+            };
+            eprintln!("{}", report);
+        }
+        if !config.skip_invalid || problems.iter().any(|p| !p.skippable()) {
+            fail(&format!(
+                "The scene has {} problem{}",
+                problems.len(),
+                if problems.len() == 1 { "" } else { "s" }
+            ));
+        }
+        validate::skip(&mut description, &problems);
+        eprintln!("Skipped the broken primitives and faces");
+    }
+    let timeline = match config.animation {
+        Some(ref path) => Some(Timeline::load(path).unwrap_or_else(|e| fail(&e))),
+        None => saved_timeline,
+    };
+    if let Some(ref t) = timeline {
+        t.validate(&description).unwrap_or_else(|e| fail(&e));
+    }
+    let animation = match timeline {
+        Some(ref t) => t.start..(t.end + 1),
+        None => 0..time,
+    };
+    // Only part of the animation is rendered when the job is split up
+    let frames = match config.frames {
+        Some((first, last)) => {
+            if first < animation.start || last >= animation.end {
+                fail(&format!(
+                    "Frames {}-{} are outside of the animation which has frames {}-{}",
+                    first,
+                    last,
+                    animation.start,
+                    animation.end - 1
+                ));
+            }
+            first..(last + 1)
+        }
+        None => animation.clone(),
+    };
+    fs::create_dir_all(&config.output_dir)
+        .unwrap_or_else(|e| fail(&format!("Can't create '{}': {}", config.output_dir, e)));
+
+    // Animations that are encoded while the frames are rendered
+    let mut encoders: Vec<Box<AnimationEncoder>> = Vec::new();
+    if let Some(ref path) = config.gif {
+        encoders.push(Box::new(GifEncoder::new(
+            path,
+            config.framerate,
+            config.dither,
+        )));
+    }
+    if let Some(ref path) = config.apng {
+        let count = frames.end - frames.start;
+        encoders.push(Box::new(ApngEncoder::new(path, count, config.framerate)));
+    }
+    // The default video would only hold part of the animation when the job is split up
+    let video = match config.video {
+        Some(ref path) if !config.video_requested && frames != animation => {
+            println!(
+                "Not making '{}', only frames {}-{} are rendered",
+                path,
+                frames.start,
+                frames.end - 1
+            );
+            None
+        }
+        ref video => video.as_ref(),
+    };
+    // Set when the encoder of the default video can't be started, the frames are
+    // still rendered but the run fails once they are done
+    let mut video_error = None;
+    if let Some(path) = video {
+        // Started up front so a missing or misconfigured encoder fails before rendering
+        match PipeEncoder::new(
+            &config.encoder,
+            &config.codec_args,
+            path,
+            config.pipe_format,
+            (HALFDIM as u32, HALFDIM as u32),
+            config.framerate,
+        ) {
+            Ok(video) => encoders.push(Box::new(video)),
+            Err(e) if config.video_requested => fail(&e.to_string()),
+            Err(e) => {
+                let error = format!("Can't make '{}': {}", path, e);
+                eprintln!("{}, only the frames are rendered", error);
+                video_error = Some(error);
+            }
+        }
+    }
 
-    let max = 2.0f32 * std::f32::consts::PI;
+    let max = 2.0 * consts::PI;
     let init = Vec3::new(look.x, h, look.z);
-    for dt in 0..time {
-        let theta = (dt as f32) * (max / (time as f32));
+    let scene_at = |frame: Float| {
+        let theta = frame * (max / (time as Float));
         let x = theta.sin() * d;
         let z = theta.cos() * d;
-        let eye = init + Vec3::new(x, 0f32, z);
-        // Save the image buffer to a file
-        let ref mut fout = File::create(format!("render{:04}.png", dt)).unwrap();
-        render(eye, look, light).save(fout, image::PNG).unwrap();
-    }
-
-    // Export .png images to an animation [mp4 or mkv]
-    let ffmpeg_shell = "ffmpeg -framerate 60 -pattern_type glob -i '*.png' -c:v libx264 -preset veryslow -crf 0 movie.mkv";
-    let make_movie = Command::new("sh")
-        .arg("-c")
-        .arg(ffmpeg_shell)
-        .output()
-        .expect("failed to execute process");
-    make_movie.stdout;
-}
-
-fn calculate_viewray(x: i32, y: i32, view_ray: ViewRay) -> Ray {
-    let us = -1.0 + view_ray.img_dim * ((x as f32) + 0.5);
-    let vs = -1.0 + view_ray.img_dim * ((y as f32) + 0.5);
-    let mut s = view_ray.eye + view_ray.u * us;
-    s = s + view_ray.v * vs;
-    s = s + view_ray.w * NEAR;
-    Ray {
-        src: view_ray.eye,
-        dir: (s - view_ray.eye).normalize(),
-    } // -> Return Ray
-}
-
-fn render(eye: Vec3<f32>, look: Vec3<f32>, light_pos: Vec3<f32>) -> image::DynamicImage {
+        let mut desc = description.clone();
+        desc.view = match imported_view {
+            Some(view) => view,
+            None => View::look_at(init + Vec3::new(x, 0.0, z), look),
+        };
+        if let Some(up) = config.up {
+            desc.view.up = up;
+        }
+        if let Some(roll) = config.roll {
+            desc.view.roll = roll;
+        }
+        if let Some(ref t) = timeline {
+            t.apply(&mut desc, frame);
+        }
+        desc
+    };
+    if let Some(ref path) = config.save_scene {
+        let (file, omitted) = SceneFile::new(
+            &scene_at(frames.start as Float),
+            environment.as_ref(),
+            &settings,
+            &color,
+            timeline.as_ref(),
+        );
+        for what in omitted.iter() {
+            eprintln!("'{}': {} can't be saved", path, what);
+        }
+        file.save(path).unwrap_or_else(|e| fail(&e));
+        println!("Saved the scene to '{}'", path);
+    }
+    for dt in frames {
+        let path = frame_path(&config.output_dir, &config.pattern, dt);
+        if config.resume && path.exists() {
+            // Frames left by an earlier run still go into the exported animations
+            println!("Skipping frame {}, '{}' exists", dt, path.display());
+            if !encoders.is_empty() {
+                let image = image::open(&path)
+                    .unwrap_or_else(|e| fail(&format!("Can't read '{}': {}", path.display(), e)));
+                export_frame(&mut encoders, &image.to_rgb(), dt);
+            }
+            continue;
+        }
+
+        // The scene moves towards its state in the next frame while the shutter is open
+        let open = scene_at(dt as Float);
+        let close = scene_at((dt + 1) as Float);
+        let mut problems = validate::check_camera(&open.view, &settings.projection);
+        problems.extend(validate::check_camera(&close.view, &settings.projection));
+        problems.extend(validate::check_frame(&open));
+        if let Some(problem) = problems.first() {
+            let report = Report {
+                desc: &open,
+                problem: problem,
+            };
+            fail(&format!("Frame {}: {}", dt, report));
+        }
+        let scene = Arc::new(open.build(&close));
+        let frame = render(scene, open.view, close.view, settings);
+        let beauty = frame.beauty(&color);
+        if settings.aovs {
+            // Passes go in their own directory to keep them apart from the frames
+            let dir = Path::new(&config.output_dir).join(AOV_DIR);
+            fs::create_dir_all(&dir).unwrap();
+            let stem = dir.join(path.file_stem().unwrap());
+            frame.save_aovs(&stem.to_string_lossy()).unwrap();
+        }
+
+        // Save the image buffer to a file. It is written under a temporary name first
+        // so an interrupted frame is never mistaken for a finished one when resuming.
+        let partial = PathBuf::from(format!("{}.partial", path.display()));
+        let ref mut fout = File::create(&partial).unwrap();
+        beauty.save(fout, image::PNG).unwrap();
+        fs::rename(&partial, &path).unwrap();
+        if !encoders.is_empty() {
+            export_frame(&mut encoders, &beauty.to_rgb(), dt);
+        }
+    }
+
+    for encoder in encoders.iter_mut() {
+        encoder
+            .finish()
+            .unwrap_or_else(|e| fail(&format!("Can't export animation: {}", e)));
+    }
+    if let Some(error) = video_error {
+        fail(&format!(
+            "{}. Pass --no-video to render without a video",
+            error
+        ));
+    }
+}
+
+/// Prints the error to stderr and exits
+fn fail(error: &str) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1)
+}
+
+/// Applies 'change' to the Material called 'name', fails if the scene has none
+fn change_material<F: FnOnce(&mut Material)>(desc: &mut SceneDesc, name: &str, change: F) {
+    match desc.material_mut(name) {
+        Some(material) => change(material),
+        None => fail(&format!("The scene has no material named '{}'", name)),
+    }
+}
+
+/// Path of the file frame number 'frame' is saved to. The first run of '#' in
+/// 'pattern' is replaced by the frame number, padded with zeros to its length.
+fn frame_path(dir: &str, pattern: &str, frame: u32) -> PathBuf {
+    let start = pattern.find('#').unwrap_or_else(|| pattern.len());
+    let width = pattern[start..].chars().take_while(|&c| c == '#').count();
+    let name = format!(
+        "{}{:0width$}{}",
+        &pattern[..start],
+        frame,
+        &pattern[start + width..],
+        width = width
+    );
+    Path::new(dir).join(name)
+}
+
+/// Hands the frame to every animation that is being exported
+fn export_frame(encoders: &mut [Box<AnimationEncoder>], frame: &RgbImage, number: u32) {
+    for encoder in encoders.iter_mut() {
+        encoder
+            .add_frame(frame)
+            .unwrap_or_else(|e| fail(&format!("Can't export frame {}: {}", number, e)));
+    }
+}
+
+/// Parses the render settings and other options from the command line arguments.
+/// Prints the usage and exits if the arguments can not be parsed.
+fn parse_args(args: &[String]) -> Config {
+    let mut opts = Options::new();
+    opts.optopt(
+        "",
+        "animation",
+        "JSON file with the keyframe timeline to render",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "scene",
+        "glTF file (.gltf or .glb) whose meshes and materials replace the default scene, or a scene saved with --save-scene (.json or .ron) that also sets the camera, environment and render settings",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "save-scene",
+        "save the scene of the first frame with its camera, environment and render settings as JSON or RON",
+        "FILE",
+    );
+    opts.optmulti(
+        "",
+        "mesh",
+        "add the triangle mesh in a PLY or STL file to the scene, made of MATERIAL. A file named after an object of the scene, like cube.stl, replaces that object",
+        "FILE:MATERIAL",
+    );
+    opts.optflag(
+        "",
+        "skip-invalid",
+        "leave broken primitives and faces out of the scene instead of refusing to render it",
+    );
+    opts.optopt(
+        "",
+        "weld",
+        "merge the corners of STL triangles closer than this distance (default 1e-5)",
+        "DISTANCE",
+    );
+    opts.optopt(
+        "",
+        "smooth",
+        "give STL meshes smooth normals, except across edges sharper than this angle",
+        "CREASE_DEGREES",
+    );
+    opts.optopt(
+        "",
+        "frames",
+        "only render the frames in this inclusive range",
+        "FIRST-LAST",
+    );
+    opts.optopt(
+        "",
+        "output-dir",
+        "directory the frames are written to",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "pattern",
+        "file name of the frames, the run of '#' is replaced by the frame number",
+        "PATTERN",
+    );
+    opts.optflag(
+        "",
+        "resume",
+        "skip frames that already exist in the output directory",
+    );
+    opts.optopt(
+        "",
+        "gif",
+        "also export the frames as an animated GIF",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "apng",
+        "also export the frames as an animated PNG",
+        "FILE",
+    );
+    opts.optflag("", "dither", "dither the colors of the GIF frames");
+    opts.optopt(
+        "",
+        "framerate",
+        "frames per second of the exported animations",
+        "FPS",
+    );
+    opts.optopt(
+        "",
+        "video",
+        "video file the frames are streamed into. Unless a GIF or APNG is exported or --frames renders part of the animation, movie.mkv is made in the output directory by default",
+        "FILE",
+    );
+    opts.optflag("", "no-video", "don't encode a video");
+    opts.optopt(
+        "",
+        "encoder",
+        "ffmpeg compatible program that encodes the video",
+        "PROGRAM",
+    );
+    opts.optopt(
+        "",
+        "codec-args",
+        "arguments that select the codec of the video",
+        "ARGS",
+    );
+    opts.optopt(
+        "",
+        "pipe-format",
+        "format of the frames streamed to the encoder",
+        "y4m|raw",
+    );
+    opts.optopt(
+        "",
+        "mode",
+        "what to render for each pixel",
+        "shaded|ao|path",
+    );
+    opts.optflag(
+        "",
+        "spectral",
+        "trace each path at a single wavelength, for dispersion and measured metals",
+    );
+    opts.optopt(
+        "",
+        "mis",
+        "how the path traced mode combines light and BSDF sampling of the direct light (default power)",
+        "power|balance|off",
+    );
+    opts.optmulti(
+        "",
+        "optics",
+        "wavelength dependent behavior of MATERIAL in the path traced mode: bk7, sf11, fused-silica, brass, copper, gold, silver, aluminium, cauchy:A,B, sellmeier:B1,B2,B3,C1,C2,C3, conductor:N1,..,N8:K1,..,K8 or reflectance:R1,..,R8 with tables from 400 to 750 nm in steps of 50",
+        "MATERIAL:OPTICS",
+    );
+    opts.optmulti(
+        "",
+        "roughness",
+        "blur the reflections of MATERIAL, from 0 for a polished mirror to 1",
+        "MATERIAL:ROUGHNESS",
+    );
+    opts.optmulti(
+        "",
+        "pbr",
+        "give MATERIAL a GGX microfacet reflection with its color as the base color, METALLIC and ROUGHNESS from 0 to 1 and SPECULAR (default 0.5) scaling the reflectance of non-metals",
+        "MATERIAL:METALLIC,ROUGHNESS[,SPECULAR]",
+    );
+    opts.optopt(
+        "",
+        "environment",
+        "surroundings of the scene: a color 'R,G,B', a gradient 'BOTTOM:TOP' of two colors, an equirectangular image or 'sky'",
+        "ENV",
+    );
+    opts.optopt(
+        "",
+        "env-rotation",
+        "rotation of the environment about the up axis in degrees",
+        "DEGREES",
+    );
+    opts.optopt(
+        "",
+        "env-intensity",
+        "brightness multiplier of the environment",
+        "SCALE",
+    );
+    opts.optopt(
+        "",
+        "turbidity",
+        "haziness of the sky, from 2 for a clear sky to 10 for a hazy one",
+        "T",
+    );
+    opts.optopt(
+        "",
+        "sun-elevation",
+        "angle of the sun above the horizon in degrees",
+        "DEGREES",
+    );
+    opts.optopt(
+        "",
+        "sun-azimuth",
+        "direction of the sun in degrees clockwise from north (+Z)",
+        "DEGREES",
+    );
+    opts.optopt(
+        "",
+        "latitude",
+        "place the sun as seen from this latitude in degrees, north positive",
+        "DEGREES",
+    );
+    opts.optopt("", "date", "date used to place the sun", "YYYY-MM-DD");
+    opts.optopt(
+        "",
+        "time",
+        "local solar time used to place the sun",
+        "HH:MM",
+    );
+    opts.optopt(
+        "",
+        "exposure",
+        "brightness adjustment in stops before tone mapping",
+        "STOPS",
+    );
+    opts.optopt(
+        "",
+        "tone-map",
+        "how colors brighter than white are brought into range",
+        "clamp|reinhard|filmic|aces",
+    );
+    opts.optopt(
+        "",
+        "encoding",
+        "transfer function of the saved images",
+        "srgb|linear",
+    );
+    opts.optflag(
+        "",
+        "dither-output",
+        "add noise of one 8 bit step to the images to hide banding",
+    );
+    opts.optopt(
+        "",
+        "fog",
+        "density of a fog filling the scene, per unit of distance",
+        "DENSITY",
+    );
+    opts.optopt(
+        "",
+        "fog-falloff",
+        "how quickly the fog thins out with height, 0 for a uniform fog",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "fog-base",
+        "height at which the fog has its full density",
+        "HEIGHT",
+    );
+    opts.optopt("", "fog-color", "color of the fog", "R,G,B");
+    opts.optmulti(
+        "",
+        "emission",
+        "make the objects made of MATERIAL emit light of the given color, scaled by STRENGTH",
+        "MATERIAL:R,G,B[:STRENGTH]",
+    );
+    opts.optmulti(
+        "",
+        "medium",
+        "fill the objects made of MATERIAL with a participating medium, G is the phase function asymmetry from -1 to 1",
+        "MATERIAL:ABSORPTION:SCATTERING[:G]",
+    );
+    opts.optopt(
+        "",
+        "ao-samples",
+        "number of ambient occlusion rays per hit",
+        "N",
+    );
+    opts.optopt("", "ao-distance", "maximum distance of an occluder", "DIST");
+    opts.optflag(
+        "",
+        "ao-multiply",
+        "multiply the shaded color by ambient occlusion",
+    );
+    opts.optopt(
+        "",
+        "camera",
+        "camera projection",
+        "perspective|orthographic|fisheye|equirectangular",
+    );
+    opts.optopt(
+        "",
+        "eye",
+        "place the camera at this point instead of orbiting the scene",
+        "X,Y,Z",
+    );
+    opts.optopt(
+        "",
+        "look",
+        "point the camera placed by --eye looks at (default the sphere)",
+        "X,Y,Z",
+    );
+    opts.optopt(
+        "",
+        "orientation",
+        "turn the camera placed by --eye from looking along -Z with Y up, by a unit quaternion or by Euler angles in degrees about X, then Y, then Z",
+        "quaternion:X,Y,Z,W|euler:X,Y,Z",
+    );
+    opts.optopt(
+        "",
+        "up",
+        "direction that points up in the image (default 0,1,0)",
+        "X,Y,Z",
+    );
+    opts.optopt(
+        "",
+        "roll",
+        "rotation of the camera about the viewing direction, positive angles turn its top to the right",
+        "DEGREES",
+    );
+    opts.optopt("", "fov", "vertical field of view of the perspective camera, or the image circle of the fisheye camera", "DEGREES");
+    opts.optopt(
+        "",
+        "focal-length",
+        "focal length of the perspective camera, overrides --fov",
+        "MM",
+    );
+    opts.optopt(
+        "",
+        "sensor-size",
+        "vertical sensor size used with --focal-length",
+        "MM",
+    );
+    opts.optopt(
+        "",
+        "view-width",
+        "width of the view of the orthographic camera",
+        "UNITS",
+    );
+    opts.optopt(
+        "",
+        "aperture",
+        "aperture radius of the perspective camera, enables depth of field",
+        "RADIUS",
+    );
+    opts.optopt(
+        "",
+        "focus-distance",
+        "distance to the plane in focus, focuses on the look point if not given",
+        "DIST",
+    );
+    opts.optopt(
+        "",
+        "blades",
+        "number of aperture blades, circular if less than 3",
+        "N",
+    );
+    opts.optopt("", "samples", "number of rays per super sample", "N");
+    opts.optopt(
+        "",
+        "shutter-open",
+        "fraction of the frame interval at which the shutter opens",
+        "FRACTION",
+    );
+    opts.optopt(
+        "",
+        "shutter-close",
+        "fraction of the frame interval at which the shutter closes, enables motion blur",
+        "FRACTION",
+    );
+    opts.optflag(
+        "",
+        "aovs",
+        "also write depth, normal, albedo, id, shadow and reflection passes",
+    );
+    opts.optflag("h", "help", "print this help menu");
+
+    let usage = |error: &str| -> ! {
+        let text = opts.usage(&format!("Usage: {} [options]", args[0]));
+        if error.is_empty() {
+            print!("{}", text);
+            std::process::exit(0)
+        }
+        // Errors go to stderr with the usage, --help prints it to stdout
+        eprintln!("{}", error);
+        eprint!("{}", text);
+        std::process::exit(1)
+    };
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => usage(&e.to_string()),
+    };
+    if matches.opt_present("h") {
+        usage("");
+    }
+
+    let mut settings = RenderSettings {
+        mode: RenderMode::Shaded,
+        ao_samples: AO_SAMPLES,
+        ao_distance: AO_DISTANCE,
+        ao_multiply: matches.opt_present("ao-multiply"),
+        aovs: matches.opt_present("aovs"),
+        projection: Projection::Perspective {
+            fov: DEFAULT_FOV.to_radians(),
+            lens: Lens::pinhole(),
+        },
+        samples: PIXEL_SAMPLES,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+        film: None,
+        mis: Some(Heuristic::Power),
+    };
+    if let Some(mode) = matches.opt_str("mode") {
+        settings.mode = match mode.as_ref() {
+            "shaded" => RenderMode::Shaded,
+            "ao" => RenderMode::AmbientOcclusion,
+            "path" => RenderMode::PathTraced,
+            _ => usage(&format!("Unknown render mode '{}'", mode)),
+        };
+    }
+    if matches.opt_present("spectral") {
+        if settings.mode != RenderMode::PathTraced {
+            usage("The spectral mode needs '--mode path'");
+        }
+        settings.film = Some(Film::new());
+    }
+    if let Some(mis) = matches.opt_str("mis") {
+        settings.mis = match mis.as_ref() {
+            "power" => Some(Heuristic::Power),
+            "balance" => Some(Heuristic::Balance),
+            "off" => None,
+            _ => usage(&format!("Unknown MIS heuristic '{}'", mis)),
+        };
+    }
+    if let Some(n) = matches.opt_str("ao-samples") {
+        match i32::from_str(&n) {
+            Ok(n) if n > 0 => settings.ao_samples = n,
+            _ => usage(&format!("Invalid AO sample count '{}'", n)),
+        }
+    }
+    if let Some(d) = matches.opt_str("ao-distance") {
+        match Float::from_str(&d) {
+            Ok(d) if d > 0.0 => settings.ao_distance = d,
+            _ => usage(&format!("Invalid AO distance '{}'", d)),
+        }
+    }
+    if let Some(n) = matches.opt_str("samples") {
+        match i32::from_str(&n) {
+            Ok(n) if n > 0 => settings.samples = n,
+            _ => usage(&format!("Invalid sample count '{}'", n)),
+        }
+    }
+    let fraction = |name: &str| -> Option<Float> {
+        matches
+            .opt_str(name)
+            .map(|value| match Float::from_str(&value) {
+                Ok(f) if f >= 0.0 && f <= 1.0 => f,
+                _ => usage(&format!("Invalid value '{}' for --{}", value, name)),
+            })
+    };
+    settings.shutter_open = fraction("shutter-open").unwrap_or(0.0);
+    settings.shutter_close = fraction("shutter-close").unwrap_or(settings.shutter_open);
+    if settings.shutter_close < settings.shutter_open {
+        usage("The shutter must close after it opens");
+    }
+
+    // Numeric camera options must be positive
+    let positive = |name: &str| -> Option<Float> {
+        matches
+            .opt_str(name)
+            .map(|value| match Float::from_str(&value) {
+                Ok(n) if n > 0.0 => n,
+                _ => usage(&format!("Invalid value '{}' for --{}", value, name)),
+            })
+    };
+    let camera = matches
+        .opt_str("camera")
+        .unwrap_or_else(|| "perspective".to_string());
+    settings.projection = match camera.as_ref() {
+        "perspective" => {
+            let fov = match positive("focal-length") {
+                Some(focal_length) => Projection::fov_from_focal_length(
+                    focal_length,
+                    positive("sensor-size").unwrap_or(DEFAULT_SENSOR_SIZE),
+                ),
+                None => positive("fov").unwrap_or(DEFAULT_FOV).to_radians(),
+            };
+            if fov >= consts::PI {
+                usage("The perspective field of view must be less than 180 degrees");
+            }
+            let blades = matches.opt_str("blades").map(|n| match u32::from_str(&n) {
+                Ok(n) => n,
+                _ => usage(&format!("Invalid blade count '{}'", n)),
+            });
+            let lens = Lens {
+                aperture: positive("aperture").unwrap_or(0.0),
+                focus_distance: positive("focus-distance"),
+                blades: blades.unwrap_or(0),
+                rotation: 0.0,
+            };
+            Projection::Perspective {
+                fov: fov,
+                lens: lens,
+            }
+        }
+        "orthographic" => Projection::Orthographic {
+            width: positive("view-width").unwrap_or(2.0),
+        },
+        "fisheye" => Projection::Fisheye {
+            fov: positive("fov").unwrap_or(DEFAULT_FISHEYE_FOV).to_radians(),
+        },
+        "equirectangular" => Projection::Equirectangular,
+        _ => usage(&format!("Unknown camera '{}'", camera)),
+    };
+    let vector = |name: &str| {
+        matches.opt_str(name).map(|v| match parse_color(&v) {
+            Some(v) if v.x.is_finite() && v.y.is_finite() && v.z.is_finite() => v,
+            _ => usage(&format!(
+                "Invalid value '{}' for --{}, expected X,Y,Z",
+                v, name
+            )),
+        })
+    };
+    let up = vector("up");
+    if up == Some(Vec3::new(0.0, 0.0, 0.0)) {
+        usage("The up direction can't be zero");
+    }
+    let orientation = matches
+        .opt_str("orientation")
+        .map(|spec| parse_orientation(&spec).unwrap_or_else(|e| usage(&e)));
+    let view = match (vector("eye"), vector("look"), orientation) {
+        (None, None, None) => None,
+        (Some(_), Some(_), Some(_)) => {
+            usage("The camera is aimed either by --look or by --orientation")
+        }
+        (Some(eye), look, None) => Some(View::look_at(eye, look.unwrap_or(SPHERE_LOCATION))),
+        (Some(eye), None, Some(orientation)) => {
+            if up.is_some() {
+                usage("--orientation already sets the up direction, it can't go with --up");
+            }
+            Some(View::oriented(eye, &orientation))
+        }
+        (None, _, _) => usage("--look and --orientation need --eye"),
+    };
+    let roll = matches.opt_str("roll").map(|r| match Float::from_str(&r) {
+        Ok(r) if r.is_finite() => r,
+        _ => usage(&format!("Invalid roll angle '{}'", r)),
+    });
+    let framerate = match matches.opt_str("framerate") {
+        Some(n) => match u32::from_str(&n) {
+            Ok(n) if n > 0 => n,
+            _ => usage(&format!("Invalid framerate '{}'", n)),
+        },
+        None => FRAMERATE,
+    };
+    let frames = matches.opt_str("frames").map(|range| {
+        let bounds: Vec<Result<u32, _>> = range.splitn(2, '-').map(u32::from_str).collect();
+        match bounds[..] {
+            [Ok(first)] => (first, first),
+            [Ok(first), Ok(last)] if first <= last => (first, last),
+            _ => usage(&format!("Invalid frame range '{}'", range)),
+        }
+    });
+    let pattern = matches
+        .opt_str("pattern")
+        .unwrap_or_else(|| FRAME_PATTERN.to_string());
+    if !pattern.contains('#') {
+        usage("The file name pattern needs a '#' for the frame number");
+    }
+    let number = |name: &str, default: Float| -> Float {
+        match matches.opt_str(name) {
+            Some(value) => match Float::from_str(&value) {
+                Ok(n) if n.is_finite() => n,
+                _ => usage(&format!("Invalid value '{}' for --{}", value, name)),
+            },
+            None => default,
+        }
+    };
+    let env_rotation = number("env-rotation", 0.0);
+    let env_intensity = number("env-intensity", 1.0);
+    if env_intensity < 0.0 {
+        usage("The environment intensity can't be negative");
+    }
+    let environment = matches.opt_str("environment").map(|spec| {
+        let background = if spec == "sky" {
+            parse_sky(&matches).unwrap_or_else(|e| usage(&e))
+        } else {
+            parse_background(&spec)
+        };
+        EnvironmentDesc {
+            background: background,
+            rotation: env_rotation,
+            intensity: env_intensity,
+        }
+    });
+    let tone_map = match matches.opt_str("tone-map") {
+        Some(name) => ToneMap::parse(&name)
+            .unwrap_or_else(|| usage(&format!("Unknown tone mapping operator '{}'", name))),
+        None => ToneMap::Clamp,
+    };
+    let srgb = match matches.opt_str("encoding") {
+        Some(encoding) => match encoding.as_ref() {
+            "srgb" => true,
+            "linear" => false,
+            _ => usage(&format!("Unknown encoding '{}'", encoding)),
+        },
+        None => true,
+    };
+    let color = ColorPipeline {
+        exposure: number("exposure", 0.0),
+        tone_map: tone_map,
+        srgb: srgb,
+        dither: matches.opt_present("dither-output"),
+    };
+    let fog_density = number("fog", 0.0);
+    let fog_falloff = number("fog-falloff", 0.0);
+    if fog_density < 0.0 || fog_falloff < 0.0 {
+        usage("The fog density and falloff can't be negative");
+    }
+    let fog_color = match matches.opt_str("fog-color") {
+        Some(spec) => {
+            parse_color(&spec).unwrap_or_else(|| usage(&format!("Invalid fog color '{}'", spec)))
+        }
+        None => BKG_COLOR,
+    };
+    let fog = if fog_density > 0.0 {
+        Some(Fog {
+            color: fog_color,
+            density: fog_density,
+            falloff: fog_falloff,
+            base: number("fog-base", 0.0),
+        })
+    } else {
+        None
+    };
+    let emission = matches
+        .opt_strs("emission")
+        .iter()
+        .map(|spec| parse_emission(spec).unwrap_or_else(|e| usage(&e)))
+        .collect();
+    let optics = matches
+        .opt_strs("optics")
+        .iter()
+        .map(|spec| match spec.find(':') {
+            Some(i) => match Optics::parse(&spec[i + 1..]) {
+                Ok(optics) => (spec[..i].to_string(), optics),
+                Err(e) => usage(&e),
+            },
+            None => usage(&format!(
+                "Invalid optics '{}', expected MATERIAL:OPTICS",
+                spec
+            )),
+        })
+        .collect();
+    let media = matches
+        .opt_strs("medium")
+        .iter()
+        .map(|spec| parse_medium(spec).unwrap_or_else(|e| usage(&e)))
+        .collect();
+    let roughness = matches
+        .opt_strs("roughness")
+        .iter()
+        .map(|spec| {
+            let parsed = spec
+                .find(':')
+                .and_then(|i| Some((&spec[..i], Float::from_str(&spec[i + 1..]).ok()?)));
+            match parsed {
+                Some((name, r)) if r >= 0.0 && r <= 1.0 => (name.to_string(), r),
+                Some(_) => usage(&format!(
+                    "The roughness of '{}' has to be between 0 and 1",
+                    spec
+                )),
+                None => usage(&format!(
+                    "Invalid roughness '{}', expected MATERIAL:ROUGHNESS",
+                    spec
+                )),
+            }
+        })
+        .collect();
+    let pbr = matches
+        .opt_strs("pbr")
+        .iter()
+        .map(|spec| parse_pbr(spec).unwrap_or_else(|e| usage(&e)))
+        .collect();
+    let gif = matches.opt_str("gif");
+    let apng = matches.opt_str("apng");
+    let output_dir = matches
+        .opt_str("output-dir")
+        .unwrap_or_else(|| ".".to_string());
+    // The video is only made by default when no other animation is exported. It
+    // goes next to the frames.
+    let video = if matches.opt_present("no-video") {
+        None
+    } else if gif.is_none() && apng.is_none() {
+        Some(matches.opt_str("video").unwrap_or_else(|| {
+            Path::new(&output_dir)
+                .join(VIDEO_FILE)
+                .to_string_lossy()
+                .into_owned()
+        }))
+    } else {
+        matches.opt_str("video")
+    };
+    let pipe_format = match matches.opt_str("pipe-format") {
+        Some(format) => match format.as_ref() {
+            "y4m" => PipeFormat::Y4m,
+            "raw" => PipeFormat::Raw,
+            _ => usage(&format!("Unknown pipe format '{}'", format)),
+        },
+        None => PipeFormat::Y4m,
+    };
+    let codec_args = matches
+        .opt_str("codec-args")
+        .unwrap_or_else(|| VIDEO_CODEC_ARGS.to_string());
+    Config {
+        settings: settings,
+        animation: matches.opt_str("animation"),
+        scene: matches.opt_str("scene"),
+        save_scene: matches.opt_str("save-scene"),
+        skip_invalid: matches.opt_present("skip-invalid"),
+        weld: matches
+            .opt_str("weld")
+            .map_or(DEFAULT_WELD, |w| match Float::from_str(&w) {
+                Ok(w) if w >= 0.0 => w,
+                _ => usage(&format!("Invalid weld distance '{}'", w)),
+            }),
+        crease: matches
+            .opt_str("smooth")
+            .map(|a| match Float::from_str(&a) {
+                Ok(a) if a >= 0.0 && a <= 180.0 => a.to_radians(),
+                _ => usage(&format!("Invalid crease angle '{}'", a)),
+            }),
+        meshes: matches
+            .opt_strs("mesh")
+            .iter()
+            .map(|spec| match spec.rfind(':') {
+                Some(i) if i > 0 => (spec[..i].to_string(), spec[i + 1..].to_string()),
+                _ => usage(&format!("Invalid mesh '{}', expected FILE:MATERIAL", spec)),
+            })
+            .collect(),
+        projection_given: ["camera", "fov", "focal-length", "view-width"]
+            .iter()
+            .any(|name| matches.opt_present(name)),
+        view: view,
+        up: up,
+        roll: roll,
+        gif: gif,
+        apng: apng,
+        dither: matches.opt_present("dither"),
+        framerate: framerate,
+        video: video,
+        video_requested: matches.opt_present("video"),
+        encoder: matches
+            .opt_str("encoder")
+            .unwrap_or_else(|| VIDEO_ENCODER.to_string()),
+        codec_args: codec_args.split_whitespace().map(String::from).collect(),
+        pipe_format: pipe_format,
+        frames: frames,
+        output_dir: output_dir,
+        pattern: pattern,
+        resume: matches.opt_present("resume"),
+        environment: environment,
+        color: color,
+        fog: fog,
+        emission: emission,
+        optics: optics,
+        media: media,
+        roughness: roughness,
+        pbr: pbr,
+    }
+}
+
+/// Parses a color written as 'R,G,B'
+fn parse_color(text: &str) -> Option<Vec3<Float>> {
+    let values: Vec<Float> = text
+        .split(',')
+        .filter_map(|v| Float::from_str(v.trim()).ok())
+        .collect();
+    if values.len() == 3 && text.split(',').count() == 3 {
+        Some(Vec3::new(values[0], values[1], values[2]))
+    } else {
+        None
+    }
+}
+
+/// Parses the --emission option 'MATERIAL:R,G,B[:STRENGTH]'
+fn parse_emission(spec: &str) -> Result<(String, Vec3<Float>), String> {
+    let error = || {
+        format!(
+            "Invalid emission '{}', expected MATERIAL:R,G,B[:STRENGTH]",
+            spec
+        )
+    };
+    let parts: Vec<&str> = spec.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(error());
+    }
+    let color = parse_color(parts[1]).ok_or_else(error)?;
+    let strength = match parts.get(2) {
+        Some(text) => Float::from_str(text).map_err(|_| error())?,
+        None => 1.0,
+    };
+    if color.x < 0.0 || color.y < 0.0 || color.z < 0.0 || !(strength >= 0.0) {
+        return Err(format!("The emission of '{}' can't be negative", spec));
+    }
+    Ok((parts[0].to_string(), color * strength))
+}
+
+/// Reads a triangle mesh, choosing the loader by the extension of 'path'.
+/// 'weld' and 'crease' are passed on to the STL loader.
+fn load_mesh(path: &str, weld: Float, crease: Option<Float>) -> Result<Mesh, String> {
+    let extension = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("ply") => ply::load(path),
+        Some("stl") => stl::load(path, weld, crease),
+        _ => Err(format!(
+            "Unknown mesh format '{}', expected a .ply or .stl file",
+            path
+        )),
+    }
+}
+
+/// Object named after the file at 'path' holding 'mesh'. It pivots about the
+/// center of the base of its bounding box.
+fn mesh_object(path: &str, mesh: Mesh, material: usize) -> Object {
+    let mut min = mesh.positions[0];
+    let mut max = mesh.positions[0];
+    for p in mesh.positions.iter() {
+        min = Vec3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+        max = Vec3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+    }
+    let name = Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+    Object {
+        name: name,
+        pivot: Vec3::new((min.x + max.x) / 2.0, min.y, (min.z + max.z) / 2.0),
+        transform: Transform::identity(),
+        primitives: vec![Primitive::Mesh {
+            mesh: Arc::new(mesh),
+            material: material,
+        }],
+    }
+}
+
+/// Parses the --pbr option 'MATERIAL:METALLIC,ROUGHNESS[,SPECULAR]'
+fn parse_pbr(spec: &str) -> Result<(String, Pbr), String> {
+    let error = || {
+        format!(
+            "Invalid PBR material '{}', expected MATERIAL:METALLIC,ROUGHNESS[,SPECULAR]",
+            spec
+        )
+    };
+    let i = spec.find(':').ok_or_else(error)?;
+    let values = spec[i + 1..]
+        .split(',')
+        .map(|v| Float::from_str(v.trim()))
+        .collect::<Result<Vec<Float>, _>>()
+        .map_err(|_| error())?;
+    if values.len() < 2 || values.len() > 3 {
+        return Err(error());
+    }
+    if values.iter().any(|v| !(*v >= 0.0 && *v <= 1.0)) {
+        return Err(format!(
+            "The PBR parameters of '{}' have to be between 0 and 1",
+            spec
+        ));
+    }
+    Ok((
+        spec[..i].to_string(),
+        Pbr {
+            metallic: values[0],
+            roughness: values[1],
+            specular: values.get(2).cloned().unwrap_or(0.5),
+        },
+    ))
+}
+
+/// Parses the --medium option 'MATERIAL:ABSORPTION:SCATTERING[:G]'. The
+/// coefficients are either a single value or 'R,G,B' per channel.
+fn parse_medium(spec: &str) -> Result<(String, Medium), String> {
+    let error = || {
+        format!(
+            "Invalid medium '{}', expected MATERIAL:ABSORPTION:SCATTERING[:G]",
+            spec
+        )
+    };
+    let coefficient = |text: &str| -> Option<Vec3<Float>> {
+        let c = match Float::from_str(text.trim()) {
+            Ok(v) => Some(Vec3::new(v, v, v)),
+            Err(_) => parse_color(text),
+        };
+        c.filter(|c| c.x >= 0.0 && c.y >= 0.0 && c.z >= 0.0)
+    };
+    let parts: Vec<&str> = spec.split(':').collect();
+    if parts.len() < 3 || parts.len() > 4 {
+        return Err(error());
+    }
+    let absorption = coefficient(parts[1]).ok_or_else(error)?;
+    let scattering = coefficient(parts[2]).ok_or_else(error)?;
+    let g = match parts.get(3) {
+        Some(text) => Float::from_str(text).map_err(|_| error())?,
+        None => 0.0,
+    };
+    if !(g > -1.0 && g < 1.0) {
+        return Err(format!(
+            "The phase function asymmetry of '{}' has to be between -1 and 1",
+            spec
+        ));
+    }
+    Ok((
+        parts[0].to_string(),
+        Medium {
+            absorption: absorption,
+            scattering: scattering,
+            g: g,
+        },
+    ))
+}
+
+/// Parses the --orientation option 'quaternion:X,Y,Z,W' or 'euler:X,Y,Z'
+fn parse_orientation(spec: &str) -> Result<Orientation, String> {
+    let error = || {
+        format!(
+            "Invalid orientation '{}', expected quaternion:X,Y,Z,W or euler:X,Y,Z",
+            spec
+        )
+    };
+    let (kind, values) = match spec.find(':') {
+        Some(i) => (&spec[..i], &spec[i + 1..]),
+        None => return Err(error()),
+    };
+    let values: Vec<Float> = values
+        .split(',')
+        .map(|v| Float::from_str(v.trim()).map_err(|_| error()))
+        .collect::<Result<_, _>>()?;
+    if values.iter().any(|v| !v.is_finite()) {
+        return Err(error());
+    }
+    match (kind, values.len()) {
+        ("quaternion", 4) => {
+            if values.iter().all(|&v| v == 0.0) {
+                return Err("The quaternion can't be zero".to_string());
+            }
+            Ok(Orientation::Quaternion([
+                values[0], values[1], values[2], values[3],
+            ]))
+        }
+        ("euler", 3) => Ok(Orientation::Euler([values[0], values[1], values[2]])),
+        _ => Err(error()),
+    }
+}
+
+/// Builds the sky for '--environment sky'. The sun is placed either by its
+/// elevation and azimuth or by the latitude, date and time of day.
+fn parse_sky(matches: &getopts::Matches) -> Result<BackgroundDesc, String> {
+    let number = |name: &str, default: Float| -> Result<Float, String> {
+        match matches.opt_str(name) {
+            Some(value) => match Float::from_str(&value) {
+                Ok(n) if n.is_finite() => Ok(n),
+                _ => Err(format!("Invalid value '{}' for --{}", value, name)),
+            },
+            None => Ok(default),
+        }
+    };
+    let turbidity = number("turbidity", 3.0)?;
+    if turbidity < 1.7 || turbidity > 10.0 {
+        return Err("The turbidity has to be between 1.7 and 10".to_string());
+    }
+    let placed = ["latitude", "date", "time"];
+    let (elevation, azimuth) = if placed.iter().any(|name| matches.opt_present(name)) {
+        if !placed.iter().all(|name| matches.opt_present(name)) {
+            return Err("--latitude, --date and --time have to be given together".to_string());
+        }
+        if matches.opt_present("sun-elevation") || matches.opt_present("sun-azimuth") {
+            return Err(
+                "The sun is placed either by elevation and azimuth or by date and time".to_string(),
+            );
+        }
+        let latitude = number("latitude", 0.0)?;
+        if latitude < -90.0 || latitude > 90.0 {
+            return Err("The latitude has to be between -90 and 90 degrees".to_string());
+        }
+        let day = sky::day_of_year(&matches.opt_str("date").unwrap())?;
+        let hour = sky::parse_time(&matches.opt_str("time").unwrap())?;
+        sky::sun_position(latitude, day, hour)
+    } else {
+        (
+            number("sun-elevation", 45.0)?,
+            number("sun-azimuth", 180.0)?,
+        )
+    };
+    if elevation < -90.0 || elevation > 90.0 {
+        return Err("The sun elevation has to be between -90 and 90 degrees".to_string());
+    }
+    Ok(BackgroundDesc::Sky {
+        turbidity: turbidity,
+        sun_elevation: elevation,
+        sun_azimuth: azimuth,
+    })
+}
+
+/// Parses the --environment option. A single color is written as 'R,G,B', a
+/// gradient as 'R,G,B:R,G,B' from the bottom to the top, and anything else is
+/// the path of an equirectangular image.
+fn parse_background(spec: &str) -> BackgroundDesc {
+    let color = |s: &str| parse_color(s).map(|c| [c.x, c.y, c.z]);
+    let parts: Vec<&str> = spec.split(':').collect();
+    match parts.len() {
+        1 => {
+            if let Some(c) = color(spec) {
+                return BackgroundDesc::Color(c);
+            }
+        }
+        2 => {
+            if let (Some(bottom), Some(top)) = (color(parts[0]), color(parts[1])) {
+                return BackgroundDesc::Gradient {
+                    bottom: bottom,
+                    top: top,
+                };
+            }
+        }
+        _ => (),
+    }
+    BackgroundDesc::Image(spec.to_string())
+}
+
+/// Maps the pixel onto the image plane in [-1, 1] and lets the camera
+/// generate the Ray through it. 'jitter' offsets the position within the pixel,
+/// 'lens' selects the point on the lens and 'time' the moment within the frame.
+/// Returns None if the camera can't see the pixel.
+fn calculate_viewray(
+    x: i32,
+    y: i32,
+    jitter: (Float, Float),
+    lens: (Float, Float),
+    time: Float,
+    view_ray: &ViewRay,
+) -> Option<Ray> {
+    let us = -1.0 + view_ray.img_dim * ((x as Float) + jitter.0);
+    let vs = -1.0 + view_ray.img_dim * ((y as Float) + jitter.1);
+    view_ray.camera.generate_ray(us, vs, lens, time)
+}
+
+fn render(scene: Arc<Scene>, open: View, close: View, settings: RenderSettings) -> RenderedImage {
     /* Begin Render Loop for Image
     Init Vec containing Surfaces
     Surface is a trait, which means that we must Box the
     Structs that impl Surface to properly store them */
 
-    /* U,V,W basis Vecs are now captured from the environment of the calling
-    thread and 'moved' to the closure 'calculate_viewray' */
-    let eye_at = (eye - look).normalize();
-    let u = na::cross(&eye_at, &UP).normalize();
-    let v = na::cross(&u, &eye_at).normalize();
-    let w = na::cross(&u, &v).normalize();
-
-    let img_dim = 2.0 / (DIM as f32);
+    /* The Camera is shared between the threads and used by
+    'calculate_viewray' to generate the ray for each pixel */
+    let img_dim = 2.0 / (DIM as Float);
 
     let viewray_data = ViewRay {
         img_dim: img_dim,
-        eye: eye,
-        u: u,
-        v: v,
-        w: w,
+        camera: Arc::from(settings.projection.camera(open, close)),
     };
 
     let start = time::precise_time_s();
 
     // Run Threads that operate on disjoint image Quads
+    let view = viewray_data.clone();
+    let world = scene.clone();
     let a_thread = thread::spawn(move || {
-        thread_render(
-            light_pos,
-            viewray_data,
-            0,
-            HALFDIM as i32,
-            0,
-            HALFDIM as i32,
-        )
+        thread_render(&world, view, settings, 0, HALFDIM as i32, 0, HALFDIM as i32)
     });
 
+    let view = viewray_data.clone();
+    let world = scene.clone();
     let b_thread = thread::spawn(move || {
         thread_render(
-            light_pos,
-            viewray_data,
+            &world,
+            view,
+            settings,
             HALFDIM as i32,
             DIM,
             0,
@@ -319,10 +1802,13 @@ fn render(eye: Vec3<f32>, look: Vec3<f32>, light_pos: Vec3<f32>) -> image::Dynam
         )
     });
 
+    let view = viewray_data.clone();
+    let world = scene.clone();
     let c_thread = thread::spawn(move || {
         thread_render(
-            light_pos,
-            viewray_data,
+            &world,
+            view,
+            settings,
             0,
             HALFDIM as i32,
             HALFDIM as i32,
@@ -330,10 +1816,13 @@ fn render(eye: Vec3<f32>, look: Vec3<f32>, light_pos: Vec3<f32>) -> image::Dynam
         )
     });
 
+    let view = viewray_data.clone();
+    let world = scene.clone();
     let d_thread = thread::spawn(move || {
         thread_render(
-            light_pos,
-            viewray_data,
+            &world,
+            view,
+            settings,
             HALFDIM as i32,
             DIM,
             HALFDIM as i32,
@@ -353,16 +1842,16 @@ fn render(eye: Vec3<f32>, look: Vec3<f32>, light_pos: Vec3<f32>) -> image::Dynam
     println!("Rendering Time: {} Seconds", end);
 
     // Combine each of the image quadrants into a single image while also scaling the image to half size.
-    let mut ans = vec![vec![Vec3::new(0f32, 0f32, 0f32); HALFDIM as usize]; HALFDIM as usize];
+    let background = Pixel::background(BKG_COLOR);
+    let mut ans = vec![vec![background; HALFDIM as usize]; HALFDIM as usize];
     for quad in quads.iter() {
-        let avg_color = move |index| {
-            let mut avg = Vec3::new(0.0, 0.0, 0.0);
-            avg = avg + quad.img[index];
-            avg = avg + quad.img[index + 1];
-            avg = avg + quad.img[index + HALFDIM as usize];
-            avg = avg + quad.img[index + HALFDIM as usize + 1];
-            avg = avg / 4.0;
-            avg
+        let avg_sample = move |index| {
+            Pixel::average(&[
+                quad.img[index],
+                quad.img[index + 1],
+                quad.img[index + HALFDIM as usize],
+                quad.img[index + HALFDIM as usize + 1],
+            ])
         };
         let mut index = 0;
         // We wish to map pixels from the expanded image space
@@ -370,141 +1859,947 @@ fn render(eye: Vec3<f32>, look: Vec3<f32>, light_pos: Vec3<f32>) -> image::Dynam
         // reduced pixel space and collect samples from the expanded space
         for y in (quad.ymin / 2)..(quad.ymax / 2) {
             for x in (quad.xmin / 2)..(quad.xmax / 2) {
-                ans[x as usize][y as usize] = avg_color(index);
+                ans[x as usize][y as usize] = avg_sample(index);
                 index += 2;
             }
             // Translate index by the offset of the row size in the expanded space
             index += HALFDIM as usize;
         }
     }
-    // Create an image buffer from the pixel vector
-    let buf = ImageBuffer::from_fn(HALFDIM as u32, HALFDIM as u32, |x, y| {
-        let color = ans[x as usize][FLIP - y as usize];
-        image::Rgb([
-            (color.x * 255f32) as u8,
-            (color.y * 255f32) as u8,
-            (color.z * 255f32) as u8,
-        ])
-    });
-    image::ImageRgb8(buf)
+    RenderedImage { pixels: ans }
+}
+
+/* This function will be used by a thread to Generate a section of the
+image being drawn. */
+fn thread_render(
+    scene: &Scene,
+    viewray_data: ViewRay,
+    settings: RenderSettings,
+    xmin: i32,
+    xmax: i32,
+    ymin: i32,
+    ymax: i32,
+) -> ImageQuad {
+    let mut image = ImageQuad::new(xmin, xmax, ymin, ymax);
+    // Iterate through the the pixels in our Image Plane
+    let mut v = Vec::new();
+    for y in ymin..ymax {
+        for x in xmin..xmax {
+            v.push((x, y));
+        }
+    }
+    image.img = v
+        .par_iter()
+        .map(|&(x, y)| {
+            // Depth of field needs a random point on the lens even for a single sample
+            let shutter = settings.shutter_close - settings.shutter_open;
+            if settings.samples == 1 && shutter == 0.0 && viewray_data.camera.pinhole() {
+                return trace_pixel(
+                    scene,
+                    &viewray_data,
+                    settings,
+                    x,
+                    y,
+                    (0.5, 0.5),
+                    (0.5, 0.5),
+                    settings.shutter_open,
+                );
+            }
+            // Spread the samples over the pixel, the lens and the time the
+            // shutter is open and average them
+            let mut rng = rand::thread_rng();
+            let mut range = Range::<Float>::new(0.0, 1.0);
+            let samples: Vec<Pixel> = (0..settings.samples)
+                .map(|_| {
+                    let jitter = (range.sample(&mut rng), range.sample(&mut rng));
+                    let lens = (range.sample(&mut rng), range.sample(&mut rng));
+                    let time = settings.shutter_open + shutter * range.sample(&mut rng);
+                    trace_pixel(scene, &viewray_data, settings, x, y, jitter, lens, time)
+                })
+                .collect();
+            Pixel::average(&samples)
+        })
+        .collect();
+    image
+}
+
+/// Traces a single View Ray through the pixel (x, y) and returns its color and AOVs
+fn trace_pixel(
+    scene: &Scene,
+    viewray_data: &ViewRay,
+    settings: RenderSettings,
+    x: i32,
+    y: i32,
+    jitter: (Float, Float),
+    lens: (Float, Float),
+    time: Float,
+) -> Pixel {
+    /* Generate the View Ray for 'this' pixel using the
+    Camera shared by all of the threads */
+    let view_ray = match calculate_viewray(x, y, jitter, lens, time, viewray_data) {
+        Some(ray) => ray,
+        None => return Pixel::background(Vec3::new(0.0, 0.0, 0.0)),
+    };
+
+    // For each Surface, test for intersection with View Ray
+    // and shade the Surface nearest to the Viewer
+    match closest_hit(scene, &view_ray) {
+        Some((index, surf, near_t)) => {
+            let point = spawn_point(surf, &view_ray, near_t, -view_ray.dir);
+            let normal = facing(surf.normal(point, view_ray.time), view_ray.dir);
+            let material = surf.material();
+            let mut sample = Pixel {
+                depth: viewray_data.camera.depth(&view_ray, near_t),
+                normal: normal,
+                albedo: surf.albedo(point, view_ray.time),
+                object_id: scene.sources[index].0 as i32,
+                material_id: scene.sources[index].1 as i32,
+                ..Pixel::background(BKG_COLOR)
+            };
+            match settings.mode {
+                RenderMode::Shaded => {
+                    sample.color = match material.medium {
+                        Some(medium) => march(scene, &view_ray, surf, near_t, medium, MAX_DEPTH),
+                        None => {
+                            let shade = surf.calculate_color(scene, &view_ray, near_t, MAX_DEPTH);
+                            sample.shadow = shade.shadow;
+                            sample.reflection = shade.reflection;
+                            if settings.ao_multiply {
+                                shade.color
+                                    * ambient_occlusion(
+                                        scene,
+                                        point,
+                                        normal,
+                                        view_ray.time,
+                                        settings,
+                                    )
+                            } else {
+                                shade.color
+                            }
+                        }
+                    };
+                }
+                RenderMode::AmbientOcclusion => {
+                    sample.color = match scene.environment {
+                        Some(ref env) => {
+                            ambient_light(scene, env, point, normal, view_ray.time, settings)
+                        }
+                        None => {
+                            let ao =
+                                ambient_occlusion(scene, point, normal, view_ray.time, settings);
+                            Vec3::new(ao, ao, ao)
+                        }
+                    };
+                }
+                RenderMode::PathTraced => {
+                    sample.color = match settings.film {
+                        Some(film) => {
+                            let u = Range::<Float>::new(0.0, 1.0).sample(&mut rand::thread_rng());
+                            let wavelength = film.sample_wavelength(u);
+                            film.record(
+                                trace_path(scene, &view_ray, Some(wavelength), settings.mis),
+                                wavelength,
+                            )
+                        }
+                        None => trace_path(scene, &view_ray, None, settings.mis),
+                    };
+                }
+            }
+            // The path tracer takes care of the fog along every segment of the path
+            if settings.mode != RenderMode::PathTraced {
+                sample.color = scene.fogged(sample.color, &view_ray, near_t);
+            }
+            sample
+        }
+        None => {
+            let background = match (settings.mode, &scene.environment) {
+                // Nothing was hit so the sky is completely unoccluded
+                (RenderMode::AmbientOcclusion, &None) => Vec3::new(1.0, 1.0, 1.0),
+                _ => scene.background(view_ray.dir),
+            };
+            // Misses are seen through the fog out to the far end of the Ray
+            Pixel::background(scene.fogged(background, &view_ray, T1))
+        }
+    }
+}
+
+/// For the given point, calculates if the point is shaded
+/// and returns true if in shadow, false otherwise.
+/// Requires access the Vec containing the scenes Surfaces.
+/// Shadow rays are traced at 'time' so moving Surfaces cast matching shadows.
+fn shadow(scene: &Scene, point: Vec3<Float>, time: Float) -> Float {
+    let mut count = 0.0;
+    let mut rng = rand::thread_rng();
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    for _ in 0..SHADOW_SAMPLES {
+        let (light_dir, distance) = scene.light.sample_from(
+            point,
+            (
+                range.sample(&mut rng),
+                range.sample(&mut rng),
+                range.sample(&mut rng),
+            ),
+        );
+        let light_ray = Ray {
+            src: point,
+            dir: light_dir,
+            time: time,
+        };
+        // Volumes cast partial shadows
+        let passed = transmittance(scene, &light_ray, distance);
+        count += 1.0 - (passed.x + passed.y + passed.z) / 3.0;
+    }
+    count / (SHADOW_SAMPLES as Float)
+}
+
+/// Finds the Surface nearest to the source of the given Ray.
+/// Returns the index of the Surface in the Scene, the Surface and the
+/// distance 't' along the Ray, or None if nothing in the scene was intersected.
+fn closest_hit<'a>(scene: &'a Scene, ray: &Ray) -> Option<(usize, &'a Surface, Float)> {
+    let mut near_surf: Option<usize> = None;
+    let mut near_t = T1;
+    for (index, surf) in scene.surfaces.iter().enumerate() {
+        if let Some(t) = surf.hit(ray) {
+            if t < near_t {
+                near_t = t;
+                near_surf = Some(index);
+            }
+        }
+    }
+    near_surf.map(|index| (index, &*scene.surfaces[index], near_t))
+}
+
+/// Origin for Rays leaving 'surf' where 'ray' hits it at 't', on the side of
+/// the Surface 'towards' points to, so they can't hit the same spot again
+fn spawn_point(surf: &Surface, ray: &Ray, t: Float, towards: Vec3<Float>) -> Vec3<Float> {
+    let (point, error) = surf.hit_point(ray, t);
+    offset_origin(
+        point,
+        error,
+        surf.geometric_normal(point, ray.time),
+        towards,
+    )
+}
+
+/// Estimates how much of the hemisphere above 'point' is unoccluded by casting
+/// cosine weighted rays about the normal. Only Surfaces closer than the configured
+/// AO distance count as occluders. The light is ignored entirely. Returns a value
+/// between 0.0 (fully occluded) and 1.0 (fully open).
+fn ambient_occlusion(
+    scene: &Scene,
+    point: Vec3<Float>,
+    normal: Vec3<Float>,
+    time: Float,
+    settings: RenderSettings,
+) -> Float {
+    let mut rng = rand::thread_rng();
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    let mut open = 0;
+    for _ in 0..settings.ao_samples {
+        // Cosine weighted sample of the hemisphere (Malley's method)
+        let r = range.sample(&mut rng).sqrt();
+        let phi = 2.0 * consts::PI * range.sample(&mut rng);
+        let z = largest_of(1.0 - r * r).sqrt();
+        let dir = tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * z;
+        let ao_ray = Ray {
+            src: point,
+            dir: dir.normalize(),
+            time: time,
+        };
+        let occluded = scene.surfaces.iter().any(|s| match s.hit(&ao_ray) {
+            Some(t) => t < settings.ao_distance,
+            None => false,
+        });
+        if !occluded {
+            open += 1;
+        }
+    }
+    (open as Float) / (settings.ao_samples as Float)
+}
+
+/// Ambient occlusion lit by the environment: the light arriving at 'point' from
+/// the unoccluded parts of the environment, weighted by the cosine to the normal.
+/// Directions are importance sampled from the environment.
+fn ambient_light(
+    scene: &Scene,
+    env: &Environment,
+    point: Vec3<Float>,
+    normal: Vec3<Float>,
+    time: Float,
+    settings: RenderSettings,
+) -> Vec3<Float> {
+    let mut rng = rand::thread_rng();
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    let mut light = Vec3::new(0.0, 0.0, 0.0);
+    for _ in 0..settings.ao_samples {
+        let (dir, pdf) = env.sample((range.sample(&mut rng), range.sample(&mut rng)));
+        let cos = na::dot(&normal, &dir);
+        if cos <= 0.0 || pdf <= 0.0 {
+            continue;
+        }
+        let ao_ray = Ray {
+            src: point,
+            dir: dir,
+            time: time,
+        };
+        let occluded = scene.surfaces.iter().any(|s| match s.hit(&ao_ray) {
+            Some(t) => t < settings.ao_distance,
+            None => false,
+        });
+        if !occluded {
+            light = light + env.radiance(dir) * (cos / (consts::PI * pdf));
+        }
+    }
+    light / (settings.ao_samples as Float)
+}
+
+/// True if nothing blocks the Ray before it has travelled 'distance'
+fn unoccluded(scene: &Scene, ray: &Ray, distance: Float) -> bool {
+    !scene.surfaces.iter().any(|s| match s.hit(ray) {
+        Some(t) => t < distance,
+        None => false,
+    })
+}
+
+/// Fraction of the light that makes it along 'ray' over 'distance'. Opaque
+/// Surfaces block the light while volumes let part of it through. Whether the
+/// ray starts inside a volume follows from the number of volume boundaries it
+/// crosses on its way out of the scene, so volumes have to be closed objects.
+fn transmittance(scene: &Scene, ray: &Ray, distance: Float) -> Vec3<Float> {
+    let blocked = Vec3::new(0.0, 0.0, 0.0);
+    if !scene.surfaces.iter().any(|s| s.material().medium.is_some()) {
+        return if unoccluded(scene, ray, distance) {
+            Vec3::new(1.0, 1.0, 1.0)
+        } else {
+            blocked
+        };
+    }
+    let mut crossings: Vec<(Float, Medium)> = Vec::new();
+    let mut next = *ray;
+    let mut travelled = 0.0;
+    while let Some((_, surf, t)) = closest_hit(scene, &next) {
+        travelled += t;
+        match surf.material().medium {
+            Some(medium) => crossings.push((travelled, medium)),
+            None if travelled < distance => return blocked,
+            None => {}
+        }
+        next.src = spawn_point(surf, &next, t, next.dir);
+    }
+
+    let mut passed = Vec3::new(1.0, 1.0, 1.0);
+    let mut inside = if crossings.len() % 2 == 1 {
+        Some(crossings[0].1)
+    } else {
+        None
+    };
+    let mut last = 0.0;
+    for &(t, medium) in crossings.iter().take_while(|c| c.0 < distance) {
+        inside = match inside {
+            Some(m) => {
+                passed = passed * m.transmittance(t - last);
+                None
+            }
+            None => Some(medium),
+        };
+        last = t;
+    }
+    if let Some(m) = inside {
+        passed = passed * m.transmittance(distance - last);
+    }
+    passed
+}
+
+/// Estimates the light arriving along 'ray' with a unidirectional path tracer.
+/// Surfaces are Lambertian with their albedo as color, except for the 'reflect'
+/// fraction of each Material which acts as a (rough) mirror. At every diffuse
+/// bounce the light sources and the environment are sampled directly. With a
+/// 'mis' heuristic the emissive Surfaces and the environment a path finds after
+/// such a bounce are weighted against the direct samples, otherwise they are
+/// ignored because they were already accounted for. Inside volumes the distance
+/// to the next scattering event is sampled, and the path continues in a
+/// direction picked from the phase function. Materials with Optics are
+/// evaluated at 'wavelength', or per RGB channel without one.
+fn trace_path(
+    scene: &Scene,
+    ray: &Ray,
+    wavelength: Option<Float>,
+    mis: Option<Heuristic>,
+) -> Vec3<Float> {
+    let mut rng = rand::thread_rng();
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    let mut ray = *ray;
+    let mut color = Vec3::new(0.0, 0.0, 0.0);
+    let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+    let mut specular = true;
+    // Where the last diffuse bounce happened and the density of the BSDF
+    // sample taken there, for weighting the light the path finds next
+    let mut bounced: Option<(Vec3<Float>, Float)> = None;
+    // The camera is assumed to be outside of every volume
+    let mut medium: Option<Medium> = None;
+    for bounce in 0..MAX_DEPTH {
+        let hit = closest_hit(scene, &ray);
+
+        // Free-flight sampling decides whether the path scatters inside a volume
+        if let Some(m) = medium {
+            let max = hit.map_or(Float::INFINITY, |h| h.2);
+            let (scatter, weight) =
+                m.sample_distance((range.sample(&mut rng), range.sample(&mut rng)), max);
+            throughput = throughput * weight;
+            if let Some(distance) = scatter {
+                let point = ray.src + ray.dir * distance;
+                color =
+                    color + throughput * in_scattered(scene, &m, point, ray.dir, ray.time, true);
+                ray = Ray {
+                    src: point,
+                    dir: m.sample_phase(ray.dir, (range.sample(&mut rng), range.sample(&mut rng))),
+                    time: ray.time,
+                };
+                specular = false;
+                bounced = None;
+                match roulette(throughput, bounce, range.sample(&mut rng)) {
+                    Some(t) => throughput = t,
+                    None => break,
+                }
+                continue;
+            }
+        }
+
+        // Fog along the segment, out to the far end of the Ray if nothing was hit
+        if let (None, Some(fog)) = (medium, scene.fog) {
+            let passed = fog.transmittance(&ray, hit.map_or(T1, |(_, _, t)| t));
+            color = color + throughput * fog.color * (1.0 - passed);
+            throughput = throughput * passed;
+        }
+        let (index, surf, t) = match hit {
+            Some(hit) => hit,
+            None => {
+                // Without an environment the constant background is only found by chance
+                if specular || scene.environment.is_none() {
+                    color = color + throughput * scene.background(ray.dir);
+                } else if let (Some((_, pdf)), Some(h), Some(env)) =
+                    (bounced, mis, scene.environment.as_ref())
+                {
+                    color = color
+                        + throughput * env.radiance(ray.dir) * h.weight(pdf, env.pdf(ray.dir));
+                }
+                break;
+            }
+        };
+        let material = surf.material();
+        let on_light = ray.src + ray.dir * t;
+        let emission = surf.emission(on_light, ray.time);
+
+        // Light sources hit after a diffuse bounce were already sampled directly
+        if specular {
+            color = color + throughput * emission;
+        } else if let (Some((from, pdf)), Some(h)) = (bounced, mis) {
+            if emission != Vec3::new(0.0, 0.0, 0.0) {
+                // Density of sample_emitter picking the same point, per solid angle
+                let distance = na::norm(&(on_light - from));
+                let normal = surf.geometric_normal(on_light, ray.time);
+                let cos_light = na::dot(&normal, &ray.dir).abs();
+                let light_pdf =
+                    scene.emitter_probability(index) / surf.area() * distance * distance
+                        / cos_light;
+                color = color + throughput * emission * h.weight(pdf, light_pdf);
+            }
+        }
+
+        // Crossing into or out of a volume doesn't change the direction of the path
+        if let Some(inside) = material.medium {
+            medium = match medium {
+                Some(_) => None,
+                None => Some(inside),
+            };
+            ray.src = spawn_point(surf, &ray, t, ray.dir);
+            continue;
+        }
+        let point = spawn_point(surf, &ray, t, -ray.dir);
+        let normal = facing(surf.normal(point, ray.time), ray.dir);
+
+        match material.optics {
+            Some(Optics::Dielectric(ior)) => {
+                // Reflect or refract in proportion to the Fresnel reflectance
+                let entering = na::dot(&surf.normal(point, ray.time), &ray.dir) < 0.0;
+                let eta = if entering {
+                    ior.at(wavelength)
+                } else {
+                    1.0 / ior.at(wavelength)
+                };
+                let cos_i = -na::dot(&normal, &ray.dir);
+                ray = if range.sample(&mut rng) < spectrum::fresnel_dielectric(cos_i, eta) {
+                    Ray {
+                        src: point,
+                        dir: mirrored(ray.dir, normal),
+                        time: ray.time,
+                    }
+                } else {
+                    let cos_t = (1.0 - (1.0 - cos_i * cos_i) / (eta * eta)).sqrt();
+                    Ray {
+                        src: spawn_point(surf, &ray, t, ray.dir),
+                        dir: (ray.dir / eta + normal * (cos_i / eta - cos_t)).normalize(),
+                        time: ray.time,
+                    }
+                };
+                specular = true;
+                continue;
+            }
+            Some(Optics::Conductor { ref n, ref k }) => {
+                let cos_i = -na::dot(&normal, &ray.dir);
+                throughput = throughput * Optics::conductor_fresnel(n, k, cos_i, wavelength);
+                ray = Ray {
+                    src: point,
+                    dir: mirrored(ray.dir, normal),
+                    time: ray.time,
+                };
+                specular = true;
+                continue;
+            }
+            _ => {}
+        }
+
+        // Microfacet Materials sample their own lobes
+        if let Some(pbr) = surf.pbr(point, ray.time) {
+            let base = surf.albedo(point, ray.time);
+            let wo = -ray.dir;
+            color = color
+                + throughput
+                    * direct_light(scene, point, ray.time, mis, &|wi| {
+                        (
+                            pbr.eval(base, normal, wo, wi),
+                            pbr.pdf(base, normal, wo, wi),
+                        )
+                    });
+            let u = (
+                range.sample(&mut rng),
+                range.sample(&mut rng),
+                range.sample(&mut rng),
+            );
+            match pbr.sample(base, normal, wo, u) {
+                Some((dir, weight)) => {
+                    ray = Ray {
+                        src: point,
+                        dir: dir,
+                        time: ray.time,
+                    };
+                    throughput = throughput * weight;
+                    bounced = Some((point, pbr.pdf(base, normal, wo, dir)));
+                }
+                None => break,
+            }
+            specular = false;
+            match roulette(throughput, bounce, range.sample(&mut rng)) {
+                Some(t) => throughput = t,
+                None => break,
+            }
+            continue;
+        }
+
+        // Pick between the mirror and the diffuse part of the Material
+        if range.sample(&mut rng) < material.reflect {
+            let u = (range.sample(&mut rng), range.sample(&mut rng));
+            ray = Ray {
+                src: point,
+                dir: glossy(ray.dir, normal, material.roughness, u),
+                time: ray.time,
+            };
+            specular = true;
+            continue;
+        }
+        let albedo = match material.optics {
+            Some(Optics::Reflectance(ref table)) => Optics::albedo(table, wavelength),
+            _ => surf.albedo(point, ray.time),
+        };
+        color = color
+            + throughput
+                * direct_light(scene, point, ray.time, mis, &|wi| {
+                    lambert(albedo, normal, wi)
+                });
+
+        // Continue the path in a cosine weighted direction, the cosine and the PDF cancel
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        let r = range.sample(&mut rng).sqrt();
+        let phi = 2.0 * consts::PI * range.sample(&mut rng);
+        let z = largest_of(1.0 - r * r).sqrt();
+        let dir = tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * z;
+        ray = Ray {
+            src: point,
+            dir: dir.normalize(),
+            time: ray.time,
+        };
+        throughput = throughput * albedo;
+        specular = false;
+        bounced = Some((point, z / consts::PI));
+
+        match roulette(throughput, bounce, range.sample(&mut rng)) {
+            Some(t) => throughput = t,
+            None => break,
+        }
+    }
+    color
+}
+
+/// Direction 'dir' is reflected into by a mirror with the given 'normal'
+fn mirrored(dir: Vec3<Float>, normal: Vec3<Float>) -> Vec3<Float> {
+    (dir - normal * (2.0 * na::dot(&dir, &normal))).normalize()
+}
+
+/// Direction 'dir' is reflected into by a rough mirror, mirrored around a
+/// microfacet normal picked from the GGX distribution with the uniform sample
+/// 'u'. Falls back to the perfect mirror direction when that would go below
+/// the surface.
+fn glossy(
+    dir: Vec3<Float>,
+    normal: Vec3<Float>,
+    roughness: Float,
+    u: (Float, Float),
+) -> Vec3<Float> {
+    if roughness <= 0.0 {
+        return mirrored(dir, normal);
+    }
+    let reflected = mirrored(dir, brdf::sample_ggx(normal, roughness, u));
+    if na::dot(&reflected, &normal) > 0.0 {
+        reflected
+    } else {
+        mirrored(dir, normal)
+    }
+}
+
+/// Russian roulette ends paths that can't contribute much. Returns the
+/// throughput of a path that survives, compensated for the paths that didn't.
+fn roulette(throughput: Vec3<Float>, bounce: i32, u: Float) -> Option<Vec3<Float>> {
+    if bounce < 3 {
+        return Some(throughput);
+    }
+    let survive = largest_of(throughput.x.max(throughput.y).max(throughput.z)).min(0.95);
+    if u >= survive {
+        None
+    } else {
+        Some(throughput / survive)
+    }
+}
+
+/// Light reflected at 'point' towards the viewer that arrives directly from the
+/// scene's light, the emissive Surfaces and the environment. 'bsdf' gives the
+/// fraction of the light arriving from a direction that is reflected, including
+/// the cosine to the normal, and the density of the path continuing in that
+/// direction. With a 'mis' heuristic the samples of the emissive Surfaces and
+/// the environment are weighted against that density. The light's color is the
+/// radiance a white diffuse surface facing the light reflects, matching the
+/// brightness of the other render modes.
+fn direct_light(
+    scene: &Scene,
+    point: Vec3<Float>,
+    time: Float,
+    mis: Option<Heuristic>,
+    bsdf: &Fn(Vec3<Float>) -> (Vec3<Float>, Float),
+) -> Vec3<Float> {
+    let mut rng = rand::thread_rng();
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    let mut light = Vec3::new(0.0, 0.0, 0.0);
+
+    // Pick a point within the area the soft shadows of the light are computed for
+    let (light_dir, distance) = scene.light.sample_from(
+        point,
+        (
+            range.sample(&mut rng),
+            range.sample(&mut rng),
+            range.sample(&mut rng),
+        ),
+    );
+    let light_ray = Ray {
+        src: point,
+        dir: light_dir,
+        time: time,
+    };
+    let (reflected, _) = bsdf(light_dir);
+    if reflected != Vec3::new(0.0, 0.0, 0.0) {
+        light = light
+            + scene.light.color
+                * reflected
+                * transmittance(scene, &light_ray, distance)
+                * consts::PI;
+    }
+
+    if let Some((dir, radiance, pdf)) = sample_emitter(scene, point, time) {
+        let (reflected, bsdf_pdf) = bsdf(dir);
+        light = light + radiance * reflected * mis.map_or(1.0, |h| h.weight(pdf, bsdf_pdf));
+    }
+
+    if let Some(ref env) = scene.environment {
+        let (dir, pdf) = env.sample((range.sample(&mut rng), range.sample(&mut rng)));
+        let (reflected, bsdf_pdf) = bsdf(dir);
+        let env_ray = Ray {
+            src: point,
+            dir: dir,
+            time: time,
+        };
+        if pdf > 0.0 && reflected != Vec3::new(0.0, 0.0, 0.0) {
+            let weight = mis.map_or(1.0, |h| h.weight(pdf, bsdf_pdf));
+            light = light
+                + env.radiance(dir)
+                    * transmittance(scene, &env_ray, T1)
+                    * reflected
+                    * (weight / pdf);
+        }
+    }
+    light
 }
 
-/* This function will be used by a thread to Generate a section of the
-image being drawn. */
-fn thread_render(
-    light_pos: Vec3<f32>,
-    viewray_data: ViewRay,
-    xmin: i32,
-    xmax: i32,
-    ymin: i32,
-    ymax: i32,
-) -> ImageQuad {
-    let mut image = ImageQuad::new(xmin, xmax, ymin, ymax);
-    // Iterate through the the pixels in our Image Plane
-    let mut v = Vec::new();
-    for y in ymin..ymax {
-        for x in xmin..xmax {
-            v.push((x, y));
+/// Diffuse reflection of light arriving from 'dir' at a surface with the given
+/// 'normal', including the cosine, and the density of cosine weighted sampling
+/// picking 'dir'. To be passed to direct_light.
+fn lambert(albedo: Vec3<Float>, normal: Vec3<Float>, dir: Vec3<Float>) -> (Vec3<Float>, Float) {
+    let pdf = largest_of(na::dot(&normal, &dir)) / consts::PI;
+    (albedo * pdf, pdf)
+}
+
+/// Light arriving at 'point' from the emissive Surfaces, divided by PI so that
+/// multiplying by the albedo gives the reflected radiance. Used by the shaded
+/// mode, which averages EMITTER_SAMPLES samples.
+fn emitter_light(
+    scene: &Scene,
+    point: Vec3<Float>,
+    normal: Vec3<Float>,
+    time: Float,
+) -> Vec3<Float> {
+    let mut light = Vec3::new(0.0, 0.0, 0.0);
+    if scene.emitters.is_empty() {
+        return light;
+    }
+    for _ in 0..EMITTER_SAMPLES {
+        if let Some((dir, radiance, _)) = sample_emitter(scene, point, time) {
+            light = light + radiance * largest_of(na::dot(&normal, &dir));
         }
     }
-    image.img = v
-        .par_iter()
-        .map(|&(x, y)| {
-            /* Generate the View Ray for 'this' pixel.
-            Makes use of UVW basis vecs captured in the 'closure'
-            of the lambda function No need for 'unsafe'
-            static mut 'global' variables */
-            let view_ray = calculate_viewray(x, y, viewray_data);
-
-            // For each Surface, test for intersection with View Ray
-            // Track surface nearest to Viewer with near_t scalar
-            let mut near_surf: Option<&Box<Surface>> = None;
-            let mut near_t = T1;
-
-            for s in SURFACES.iter() {
-                if let Some(t) = s.hit(&view_ray) {
-                    if t < near_t {
-                        near_t = t;
-                        near_surf = Some(s);
-                    }
-                }
-            }
-            match near_surf {
-                Some(surf) => surf.calculate_color(&view_ray, light_pos, near_t, MAX_DEPTH),
-                None => BKG_COLOR,
-            }
-        })
-        .collect();
-    image
+    light / (consts::PI * EMITTER_SAMPLES as Float)
 }
 
-/// For the given point, calculates if the point is shaded
-/// and returns true if in shadow, false otherwise.
-/// Requires access the Vec containing the scenes Surfaces.
-fn shadow(point: Vec3<f32>, light_pos: Vec3<f32>) -> f32 {
-    let mut count = 0;
+/// Picks a point on one of the emissive Surfaces as seen from 'point'. Returns
+/// the direction towards it, the light arriving from it divided by the
+/// probability density of picking that direction and the density itself, or
+/// None if nothing was picked. Emissive Surfaces shine from both of their sides.
+fn sample_emitter(
+    scene: &Scene,
+    point: Vec3<Float>,
+    time: Float,
+) -> Option<(Vec3<Float>, Vec3<Float>, Float)> {
     let mut rng = rand::thread_rng();
-    let mut range = Range::new(-LIGHT_RADIUS, LIGHT_RADIUS);
-    for _ in 0..SHADOW_SAMPLES {
-        let light_loc = light_pos
-            + Vec3::new(
-                range.sample(&mut rng),
-                range.sample(&mut rng),
-                range.sample(&mut rng),
-            );
-        let light_dir = (light_loc - point).normalize();
-        let light_ray = Ray {
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    let (surf, probability) = scene.pick_emitter(range.sample(&mut rng))?;
+    let (on_light, light_normal) =
+        surf.sample((range.sample(&mut rng), range.sample(&mut rng)), time);
+    let to_light = on_light - point;
+    let distance = na::norm(&to_light);
+    let dir = to_light / distance;
+    let cos_light = na::dot(&light_normal, &dir).abs();
+    if distance <= MIN_LIGHT_DISTANCE || cos_light <= 0.0 {
+        return None;
+    }
+    // Convert the density over the area of the Surface to one over solid angle
+    let pdf = probability / surf.area() * distance * distance / cos_light;
+    let ray = Ray {
+        src: point,
+        dir: dir,
+        time: time,
+    };
+    // Stop short of the emissive Surface itself
+    let passed = transmittance(scene, &ray, distance * (1.0 - 1e-3));
+    Some((dir, surf.emission(on_light, time) * passed / pdf, pdf))
+}
+
+/// Light scattered at 'point' inside 'medium' into the direction the light
+/// leaving along 'dir' came from, per unit of scattering coefficient. Light from
+/// the environment is only included with 'environment' set, matching the render
+/// modes that light surfaces with it.
+fn in_scattered(
+    scene: &Scene,
+    medium: &Medium,
+    point: Vec3<Float>,
+    dir: Vec3<Float>,
+    time: Float,
+    environment: bool,
+) -> Vec3<Float> {
+    let mut rng = rand::thread_rng();
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    let mut light = Vec3::new(0.0, 0.0, 0.0);
+
+    // The light's color is the radiance a white diffuse surface reflects, which
+    // is the irradiance divided by PI
+    let (light_dir, distance) = scene.light.sample_from(
+        point,
+        (
+            range.sample(&mut rng),
+            range.sample(&mut rng),
+            range.sample(&mut rng),
+        ),
+    );
+    let light_ray = Ray {
+        src: point,
+        dir: light_dir,
+        time: time,
+    };
+    let phase = medium.phase(light_dir * -1.0, dir * -1.0);
+    light = light
+        + scene.light.color * transmittance(scene, &light_ray, distance) * (consts::PI * phase);
+
+    if let Some((emitter_dir, radiance, _)) = sample_emitter(scene, point, time) {
+        light = light + radiance * medium.phase(emitter_dir * -1.0, dir * -1.0);
+    }
+
+    if let (true, Some(env)) = (environment, scene.environment.as_ref()) {
+        let (env_dir, pdf) = env.sample((range.sample(&mut rng), range.sample(&mut rng)));
+        let env_ray = Ray {
             src: point,
-            dir: light_dir,
+            dir: env_dir,
+            time: time,
         };
-        for s in SURFACES.iter() {
-            if let Some(_) = s.hit(&light_ray) {
-                count += 1;
-                break;
-            }
+        if pdf > 0.0 {
+            let phase = medium.phase(env_dir * -1.0, dir * -1.0);
+            light =
+                light + env.radiance(env_dir) * transmittance(scene, &env_ray, T1) * (phase / pdf);
         }
     }
-    (count as f32) / (SHADOW_SAMPLES as f32)
+    light
 }
 
 /// Casts a Reflection ray from the 'Point' in a direction that is calculated from the incoming
 /// view_dir and surface normal. If the maximum depth has been reached in computing rays, returns
-/// the background color for the scene.
+/// the background of the scene in the reflected direction. Rough reflections average rays
+/// scattered around the mirror direction, GLOSSY_SAMPLES of them for the first reflection.
 fn reflect(
-    point: Vec3<f32>,
-    view_dir: Vec3<f32>,
-    normal: Vec3<f32>,
-    light_pos: Vec3<f32>,
+    scene: &Scene,
+    point: Vec3<Float>,
+    view_dir: Vec3<Float>,
+    normal: Vec3<Float>,
+    roughness: Float,
     depth: i32,
-) -> Vec3<f32> {
-    if depth == 0 {
-        return BKG_COLOR;
+    time: Float,
+) -> Vec3<Float> {
+    let mut rng = rand::thread_rng();
+    let mut range = Range::<Float>::new(0.0, 1.0);
+    let rays = if roughness > 0.0 && depth == MAX_DEPTH - 1 {
+        GLOSSY_SAMPLES
+    } else {
+        1
+    };
+    let mut color = Vec3::new(0.0, 0.0, 0.0);
+    for _ in 0..rays {
+        // Calculate Reflection Ray
+        let u = (range.sample(&mut rng), range.sample(&mut rng));
+        let ray = Ray {
+            src: point,
+            dir: glossy(view_dir, normal, roughness, u),
+            time: time,
+        };
+        color = color
+            + if depth == 0 {
+                scene.background(ray.dir)
+            } else {
+                shade(scene, &ray, depth)
+            };
     }
+    color / rays as Float
+}
 
-    // Calculate Reflection Ray
-    let dot_n = 2.0 * na::dot(&view_dir, &normal);
-    let dir = normal * dot_n;
-    let ray = Ray {
-        src: point,
-        dir: (view_dir - dir).normalize(),
-    };
+/// Shaded mode color of a Surface with a microfacet Material. The light and the
+/// mirror reflection are weighted by the BRDF; the ambient term only lights
+/// the diffuse part.
+fn shade_pbr(
+    scene: &Scene,
+    ray: &Ray,
+    pbr: &Pbr,
+    base: Vec3<Float>,
+    point: Vec3<Float>,
+    normal: Vec3<Float>,
+    depth: i32,
+) -> Shade {
+    let normal = facing(normal, ray.dir);
+    let wo = ray.dir * -1.0;
+    let in_shadow = shadow(scene, point, ray.time);
+    let diffuse = base * (1.0 - pbr.metallic);
+    let (light_dir, _) = scene.light.direction_from(point);
+    let direct = scene.light.color * pbr.eval(base, normal, wo, light_dir) * consts::PI;
+    let mut color = (diffuse * 0.5 + direct) * (1.0 - in_shadow);
+    color = color + diffuse * emitter_light(scene, point, normal, ray.time);
+    let fresnel = pbr.fresnel(base, largest_of(na::dot(&normal, &wo)));
+    let reflection = reflect(
+        scene,
+        point,
+        ray.dir,
+        normal,
+        pbr.roughness,
+        depth - 1,
+        ray.time,
+    ) * fresnel;
+    Shade {
+        color: color + reflection,
+        shadow: in_shadow,
+        reflection: reflection,
+    }
+}
 
-    // Rest of function is largely similar to intersection test
-    // in thread_render()
-    let mut near_surf: Option<&Box<Surface>> = None;
-    let mut current = T1;
-    for surf in SURFACES.iter() {
-        if let Some(t) = surf.hit(&ray) {
-            if t < current {
-                current = t;
-                near_surf = Some(surf);
-            }
+/// Color seen along 'ray' in the shaded render mode, through the fog and any
+/// volumes in the way.
+fn shade(scene: &Scene, ray: &Ray, depth: i32) -> Vec3<Float> {
+    match closest_hit(scene, ray) {
+        Some((_, surf, t)) => {
+            let color = match surf.material().medium {
+                Some(medium) => march(scene, ray, surf, t, medium, depth),
+                None => surf.calculate_color(scene, ray, t, depth).color,
+            };
+            scene.fogged(color, ray, t)
         }
+        None => scene.fogged(scene.background(ray.dir), ray, T1),
     }
-    match near_surf {
-        Some(surf) => surf.calculate_color(&ray, light_pos, current, depth),
-        None => BKG_COLOR,
+}
+
+/// Color seen along 'ray' through the volume whose boundary it hits at 't'. The
+/// inside is ray marched in MARCH_STEPS jittered steps, gathering the light
+/// scattered towards the viewer at each, and attenuates whatever is behind it.
+fn march(
+    scene: &Scene,
+    ray: &Ray,
+    boundary: &Surface,
+    t: Float,
+    medium: Medium,
+    depth: i32,
+) -> Vec3<Float> {
+    let inside = Ray {
+        src: spawn_point(boundary, ray, t, ray.dir),
+        ..*ray
+    };
+    let (length, behind) = match closest_hit(scene, &inside) {
+        Some((_, surf, t)) => {
+            let color = if surf.material().medium.is_some() {
+                // Leaving the volume
+                let outside = Ray {
+                    src: spawn_point(surf, &inside, t, inside.dir),
+                    ..inside
+                };
+                shade(scene, &outside, depth)
+            } else {
+                surf.calculate_color(scene, &inside, t, depth).color
+            };
+            (t, color)
+        }
+        // The volume isn't closed
+        None => return scene.background(ray.dir),
+    };
+
+    let mut rng = rand::thread_rng();
+    let offset = Range::<Float>::new(0.0, 1.0).sample(&mut rng);
+    let step = length / MARCH_STEPS as Float;
+    let mut color = Vec3::new(0.0, 0.0, 0.0);
+    for i in 0..MARCH_STEPS {
+        let s = (i as Float + offset) * step;
+        let point = inside.src + inside.dir * s;
+        let scattered = in_scattered(scene, &medium, point, inside.dir, inside.time, false);
+        color = color + medium.transmittance(s) * medium.scattering * scattered * step;
     }
+    color + behind * medium.transmittance(length)
 }
 
 // Setup Data Structures used by the Ray Tracer
@@ -515,8 +2810,286 @@ fn reflect(
 /// (calculate_color function must be modified to use terms if so)
 #[derive(Copy, Clone)]
 struct Material {
-    amb: Vec3<f32>,
-    reflect: f32,
+    amb: Vec3<Float>,
+    reflect: Float,
+    /// Blur of the reflection from 0 for a polished mirror to 1, rough
+    /// reflections scatter in a lobe around the mirror direction
+    roughness: Float,
+    /// Light given off by the Surface, which makes it a light source
+    emission: Vec3<Float>,
+    /// Fills the inside of closed objects, whose surface then only marks the
+    /// boundary of the volume and isn't shaded itself
+    medium: Option<Medium>,
+    /// Wavelength dependent behavior used by the path traced mode instead of
+    /// the color and 'reflect'
+    optics: Option<Optics>,
+    /// Metallic/roughness microfacet reflection instead of the diffuse and
+    /// mirror terms, with the ambient color as the base color
+    pbr: Option<Pbr>,
+}
+
+/// Selects what the renderer outputs for each pixel.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum RenderMode {
+    /// Regular shading with shadows and reflections
+    Shaded,
+    /// Grayscale ambient occlusion, useful for clay renders and model review.
+    /// With an environment the occlusion is colored by the environment's light.
+    AmbientOcclusion,
+    /// Diffuse and mirror reflections of the light and environment traced with
+    /// a unidirectional path tracer
+    PathTraced,
+}
+
+/// How the path traced mode weighs the direct light found by sampling the
+/// light sources against the light found by following the BSDF, in multiple
+/// importance sampling
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Heuristic {
+    Balance,
+    /// Squares the densities, which favors the better strategy more strongly
+    Power,
+}
+
+impl Heuristic {
+    /// Weight of a sample taken with the probability density 'pdf' that the
+    /// other strategy would have taken with the density 'other'
+    fn weight(self, pdf: Float, other: Float) -> Float {
+        let (a, b) = match self {
+            Heuristic::Balance => (pdf, other),
+            Heuristic::Power => (pdf * pdf, other * other),
+        };
+        if !a.is_finite() {
+            1.0
+        } else if a + b > 0.0 {
+            a / (a + b)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Per render options that are passed along to each of the render threads.
+#[derive(Copy, Clone)]
+struct RenderSettings {
+    mode: RenderMode,
+    ao_samples: i32,
+    ao_distance: Float,
+    /// When set, the shaded color is multiplied by the ambient occlusion term
+    ao_multiply: bool,
+    /// When set, the arbitrary output variables are written next to each frame
+    aovs: bool,
+    projection: Projection,
+    /// Number of rays traced per super sample
+    samples: i32,
+    /// Fractions of the frame interval the shutter is open for, Rays are
+    /// spread over this interval to create motion blur
+    shutter_open: Float,
+    shutter_close: Float,
+    /// Set in the spectral mode, where each path is traced at one wavelength
+    film: Option<Film>,
+    /// Multiple importance sampling of the direct light in the path traced
+    /// mode, None only samples the light sources
+    mis: Option<Heuristic>,
+}
+
+/// Options parsed from the command line
+struct Config {
+    settings: RenderSettings,
+    /// Path of the Timeline to render instead of the default orbit
+    animation: Option<String>,
+    /// glTF file that replaces the objects and materials of the default
+    /// scene, or a saved scene that replaces the settings as well
+    scene: Option<String>,
+    /// JSON or RON file the scene of the first frame is saved to
+    save_scene: Option<String>,
+    /// Leave the Primitives that fail validation out of the scene
+    skip_invalid: bool,
+    /// Mesh files added to the scene and the names of their Materials
+    meshes: Vec<(String, String)>,
+    /// Distance below which the corners of STL triangles are welded
+    weld: Float,
+    /// Crease angle in radians of the smooth normals of STL meshes, None
+    /// keeps them faceted
+    crease: Option<Float>,
+    /// Whether the camera options were given, otherwise the camera of an
+    /// imported scene chooses the projection
+    projection_given: bool,
+    /// Camera placed on the command line, None orbits the scene
+    view: Option<View>,
+    /// Up direction and roll in degrees that replace those of every camera
+    up: Option<Vec3<Float>>,
+    roll: Option<Float>,
+    /// Paths the frames are exported to as an animated GIF and APNG
+    gif: Option<String>,
+    apng: Option<String>,
+    /// Dither the GIF frames after reducing them to their palettes
+    dither: bool,
+    /// Frames per second of the exported animations
+    framerate: u32,
+    /// Video file the frames are streamed into, None if no video is made
+    video: Option<String>,
+    /// Whether the video was asked for with --video. Only then does a missing
+    /// encoder stop the render before it starts.
+    video_requested: bool,
+    /// Program that encodes the video and the arguments selecting its codec
+    encoder: String,
+    codec_args: Vec<String>,
+    pipe_format: PipeFormat,
+    /// First and last frame to render, None renders the whole animation
+    frames: Option<(u32, u32)>,
+    /// Where the frames are written and the pattern their file names follow
+    output_dir: String,
+    pattern: String,
+    /// Skip frames whose file already exists
+    resume: bool,
+    /// Surroundings of the scene, None uses the constant background color
+    environment: Option<EnvironmentDesc>,
+    /// How the rendered colors are turned into 8 bit pixels
+    color: ColorPipeline,
+    fog: Option<Fog>,
+    /// Light emitted by the objects made of the named Materials
+    emission: Vec<(String, Vec3<Float>)>,
+    /// Wavelength dependent behavior of the named Materials
+    optics: Vec<(String, Optics)>,
+    /// Media filling the objects made of the named Materials
+    media: Vec<(String, Medium)>,
+    /// Blur of the reflections of the named Materials
+    roughness: Vec<(String, Float)>,
+    /// Microfacet reflection of the named Materials
+    pbr: Vec<(String, Pbr)>,
+}
+
+/// Everything recorded for a single (super)sample of the image. Besides the final color
+/// this holds the arbitrary output variables (AOVs) that are written as separate
+/// passes for compositing and debugging.
+#[derive(Copy, Clone)]
+struct Pixel {
+    color: Vec3<Float>,
+    /// Camera space depth of the hit, T1 if nothing was hit
+    depth: Float,
+    /// World space normal facing the viewer
+    normal: Vec3<Float>,
+    albedo: Vec3<Float>,
+    /// Index of the Object in the SceneDesc, -1 if nothing was hit
+    object_id: i32,
+    /// Index of the Material in the SceneDesc, -1 if nothing was hit
+    material_id: i32,
+    /// Shadow factor as computed by shadow()
+    shadow: Float,
+    /// Color contributed to the final color by reflections
+    reflection: Vec3<Float>,
+}
+
+impl Pixel {
+    fn background(color: Vec3<Float>) -> Pixel {
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        Pixel {
+            color: color,
+            depth: T1,
+            normal: zero,
+            albedo: zero,
+            object_id: -1,
+            material_id: -1,
+            shadow: 0.0,
+            reflection: zero,
+        }
+    }
+
+    /// Combines super samples into a single sample. Continuous values are averaged,
+    /// while depth and the IDs are taken from the first sample as blending them
+    /// would produce values that do not belong to any object.
+    fn average(samples: &[Pixel]) -> Pixel {
+        let n = samples.len() as Float;
+        let mut avg = samples[0];
+        avg.color = samples
+            .iter()
+            .fold(Vec3::new(0.0, 0.0, 0.0), |a, s| a + s.color)
+            / n;
+        avg.normal = samples
+            .iter()
+            .fold(Vec3::new(0.0, 0.0, 0.0), |a, s| a + s.normal)
+            / n;
+        avg.albedo = samples
+            .iter()
+            .fold(Vec3::new(0.0, 0.0, 0.0), |a, s| a + s.albedo)
+            / n;
+        avg.shadow = samples.iter().fold(0.0, |a, s| a + s.shadow) / n;
+        avg.reflection = samples
+            .iter()
+            .fold(Vec3::new(0.0, 0.0, 0.0), |a, s| a + s.reflection)
+            / n;
+        avg
+    }
+}
+
+/// The final downsampled image. Pixels are stored by column and from the bottom up.
+struct RenderedImage {
+    pixels: Vec<Vec<Pixel>>,
+}
+
+impl RenderedImage {
+    /// Creates an 8 bit image by mapping each pixel through 'f'
+    fn to_image<F: Fn(&Pixel) -> Vec3<Float>>(&self, f: F) -> image::DynamicImage {
+        let buf = ImageBuffer::from_fn(HALFDIM as u32, HALFDIM as u32, |x, y| {
+            let color = f(&self.pixels[x as usize][FLIP - y as usize]);
+            image::Rgb([
+                (color.x * 255.0) as u8,
+                (color.y * 255.0) as u8,
+                (color.z * 255.0) as u8,
+            ])
+        });
+        image::ImageRgb8(buf)
+    }
+
+    /// The final shaded image, its linear colors encoded by 'pipeline'
+    fn beauty(&self, pipeline: &ColorPipeline) -> image::DynamicImage {
+        let buf = ImageBuffer::from_fn(HALFDIM as u32, HALFDIM as u32, |x, y| {
+            let color = self.pixels[x as usize][FLIP - y as usize].color;
+            image::Rgb(pipeline.encode(color, x, y))
+        });
+        image::ImageRgb8(buf)
+    }
+
+    /// Writes each AOV as a separate image named '<stem>_<pass>'. Depth is written
+    /// as a floating point Radiance HDR image so it can be used without rescaling.
+    fn save_aovs(&self, stem: &str) -> io::Result<()> {
+        let half = Vec3::new(0.5, 0.5, 0.5);
+        let passes = vec![
+            ("normal", self.to_image(|s| s.normal * 0.5 + half)),
+            ("albedo", self.to_image(|s| s.albedo)),
+            (
+                "object_id",
+                self.to_image(|s| id_color((s.object_id + 1) as u32)),
+            ),
+            (
+                "material_id",
+                self.to_image(|s| id_color((s.material_id + 1) as u32)),
+            ),
+            (
+                "shadow",
+                self.to_image(|s| Vec3::new(s.shadow, s.shadow, s.shadow)),
+            ),
+            ("reflection", self.to_image(|s| s.reflection)),
+        ];
+        for (name, img) in passes {
+            let ref mut fout = File::create(format!("{}_{}.png", stem, name))?;
+            img.save(fout, image::PNG)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        }
+
+        let mut depth = Vec::with_capacity((HALFDIM * HALFDIM) as usize);
+        for y in 0..HALFDIM as usize {
+            for x in 0..HALFDIM as usize {
+                let d = to_f32(self.pixels[x][FLIP - y].depth);
+                depth.push(image::Rgb([d, d, d]));
+            }
+        }
+        let fout = File::create(format!("{}_depth.hdr", stem))?;
+        image::hdr::HDREncoder::new(fout).encode(&depth, HALFDIM as usize, HALFDIM as usize)
+    }
 }
 
 /// Structure that wraps an ImageQuad that is rended concurrently by one of 4 Threads.
@@ -527,7 +3100,7 @@ struct ImageQuad {
     xmax: i32,
     ymin: i32,
     ymax: i32,
-    img: Vec<Vec3<f32>>,
+    img: Vec<Pixel>,
 }
 
 impl ImageQuad {
@@ -544,56 +3117,145 @@ impl ImageQuad {
 
 /// Simple Container for a Ray
 /// Composed of ray position as Vec3
-/// and and direction as Vec3
+/// and and direction as Vec3, along with the
+/// time within the frame the Ray was cast at
 #[derive(Copy, Clone)]
 struct Ray {
-    src: Vec3<f32>,
-    dir: Vec3<f32>,
+    src: Vec3<Float>,
+    dir: Vec3<Float>,
+    time: Float,
+}
+
+/// Rigid motion of a Surface while the shutter is open. Between the start (time 0)
+/// and the end (time 1) of the frame the Surface is rotated by 'angle' radians
+/// about 'axis' through 'pivot', and translated by 'translation'.
+#[derive(Copy, Clone)]
+struct Motion {
+    translation: Vec3<Float>,
+    axis: Vec3<Float>,
+    angle: Float,
+    pivot: Vec3<Float>,
+}
+
+impl Motion {
+    /// Position at 'time' of the point that is at 'p' at the start of the frame
+    fn point_at(&self, p: Vec3<Float>, time: Float) -> Vec3<Float> {
+        self.pivot + self.rotate(p - self.pivot, time) + self.translation * time
+    }
+
+    /// Rotates the direction 'v' by the part of the rotation done by 'time'
+    /// using Rodrigues' rotation formula
+    fn rotate(&self, v: Vec3<Float>, time: Float) -> Vec3<Float> {
+        let angle = self.angle * time;
+        if angle == 0.0 {
+            return v;
+        }
+        let k = self.axis.normalize();
+        let (sin, cos) = angle.sin_cos();
+        v * cos + na::cross(&k, &v) * sin + k * (na::dot(&k, &v) * (1.0 - cos))
+    }
 }
 
 /// Trait for Surface type that can calculate a Ray Surface intersection
 /// and also calculates the Color for the point intersected on the Surface.
 trait Surface: Sync + Send {
-    fn hit(&self, ray: &Ray) -> Option<f32>;
-    fn calculate_color(&self, ray: &Ray, light_pos: Vec3<f32>, t: f32, depth: i32) -> Vec3<f32>;
+    fn hit(&self, ray: &Ray) -> Option<Float>;
+    fn normal(&self, point: Vec3<Float>, time: Float) -> Vec3<Float>;
+    /// Point at 't' along 'ray' moved onto the Surface, and a bound on the
+    /// rounding error of each of its coordinates
+    fn hit_point(&self, ray: &Ray, t: Float) -> (Vec3<Float>, Vec3<Float>);
+    /// Normal of the shape itself at 'point', without smoothing or normal maps
+    fn geometric_normal(&self, point: Vec3<Float>, time: Float) -> Vec3<Float>;
+    fn material(&self) -> Material;
+    /// Diffuse color of the Surface at 'point'
+    fn albedo(&self, _point: Vec3<Float>, _time: Float) -> Vec3<Float> {
+        self.material().amb
+    }
+    /// Microfacet parameters at 'point', if the Material has them
+    fn pbr(&self, _point: Vec3<Float>, _time: Float) -> Option<Pbr> {
+        self.material().pbr
+    }
+    /// Light emitted at 'point'
+    fn emission(&self, _point: Vec3<Float>, _time: Float) -> Vec3<Float> {
+        self.material().emission
+    }
+    fn calculate_color(&self, scene: &Scene, ray: &Ray, t: Float, depth: i32) -> Shade;
+    /// Surface area, used to sample the light of emissive Surfaces
+    fn area(&self) -> Float;
+    /// Uniformly distributed point on the Surface at 'time' and the normal there,
+    /// from a uniform sample 'u' in [0, 1) x [0, 1)
+    fn sample(&self, u: (Float, Float), time: Float) -> (Vec3<Float>, Vec3<Float>);
+}
+
+/// Result of shading a point. Along with the final color, the intermediate
+/// shadow factor and reflection contribution are kept for the AOV passes.
+#[derive(Copy, Clone)]
+struct Shade {
+    color: Vec3<Float>,
+    shadow: Float,
+    reflection: Vec3<Float>,
+}
+
+impl Shade {
+    fn unlit(color: Vec3<Float>) -> Shade {
+        Shade {
+            color: color,
+            shadow: 0.0,
+            reflection: Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
 }
 
 /// Datatype that contains sufficient information to
 /// calculate view ray from view plane
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 struct ViewRay {
-    img_dim: f32,
-    eye: Vec3<f32>,
-    u: Vec3<f32>,
-    v: Vec3<f32>,
-    w: Vec3<f32>,
+    img_dim: Float,
+    camera: Arc<Camera>,
 }
 
 /// Datatype for representing Sphere scene objects
-/// Contains location, radius (squared) and material
+/// Contains location, radius (squared), material
+/// and the optional motion during the frame
 #[derive(Copy, Clone)]
 struct Sphere {
-    center: Vec3<f32>,
-    radius_sqr: f32,
+    center: Vec3<Float>,
+    radius_sqr: Float,
     material: Material,
+    motion: Option<Motion>,
 }
 
 impl Sphere {
-    fn new(c: Vec3<f32>, r: f32, mat: Material) -> Sphere {
+    fn new(c: Vec3<Float>, r: Float, mat: Material) -> Sphere {
         Sphere {
             center: c,
             radius_sqr: r * r,
             material: mat,
+            motion: None,
+        }
+    }
+
+    fn with_motion(self, motion: Motion) -> Sphere {
+        Sphere {
+            motion: Some(motion),
+            ..self
+        }
+    }
+
+    fn center_at(&self, time: Float) -> Vec3<Float> {
+        match self.motion {
+            Some(ref m) => m.point_at(self.center, time),
+            None => self.center,
         }
     }
 
-    fn quadratic(&self, a: f32, b: f32, disc: f32) -> Option<f32> {
+    fn quadratic(&self, a: Float, b: Float, disc: Float) -> Option<Float> {
         let p = (-b + disc.sqrt()) / (2.0 * a);
         let q = (-b - disc.sqrt()) / (2.0 * a);
         self.nearest(p, q)
     }
 
-    fn nearest(&self, p: f32, q: f32) -> Option<f32> {
+    fn nearest(&self, p: Float, q: Float) -> Option<Float> {
         let p_bound = p > T0 && p < T1;
         let q_bound = q > T0 && q < T1;
         if p_bound && q_bound {
@@ -610,10 +3272,6 @@ impl Sphere {
             None
         }
     }
-
-    fn boxed(c: Vec3<f32>, r: f32, mat: Material) -> Box<Sphere> {
-        Box::new(Sphere::new(c, r, mat))
-    }
 }
 
 impl Surface for Sphere {
@@ -621,8 +3279,8 @@ impl Surface for Sphere {
     s.t. Ray intersects Sphere.
     Sphere is intersected by ray if t is real
     Returns Some(t) only if bounded by T0 && T1 */
-    fn hit(&self, ray: &Ray) -> Option<f32> {
-        let e_minus_c = ray.src - self.center;
+    fn hit(&self, ray: &Ray) -> Option<Float> {
+        let e_minus_c = ray.src - self.center_at(ray.time);
         let a = na::dot(&ray.dir, &ray.dir);
         let b = 2.0 * na::dot(&ray.dir, &e_minus_c);
         let c = na::dot(&e_minus_c, &e_minus_c) - self.radius_sqr;
@@ -634,20 +3292,55 @@ impl Surface for Sphere {
         }
     }
 
-    fn calculate_color(&self, ray: &Ray, light_pos: Vec3<f32>, t: f32, depth: i32) -> Vec3<f32> {
+    fn normal(&self, point: Vec3<Float>, time: Float) -> Vec3<Float> {
+        (point - self.center_at(time)).normalize()
+    }
+
+    fn hit_point(&self, ray: &Ray, t: Float) -> (Vec3<Float>, Vec3<Float>) {
+        let center = self.center_at(ray.time);
+        let radial = ray.src + ray.dir * t - center;
+        // Projecting onto the Sphere is much more precise than the Ray equation
+        let radial = radial * (self.radius_sqr.sqrt() / na::norm(&radial));
+        (center + radial, (abs(center) + abs(radial)) * gamma(6))
+    }
+
+    fn geometric_normal(&self, point: Vec3<Float>, time: Float) -> Vec3<Float> {
+        self.normal(point, time)
+    }
+
+    fn material(&self) -> Material {
+        self.material
+    }
+
+    fn calculate_color(&self, scene: &Scene, ray: &Ray, t: Float, depth: i32) -> Shade {
         if depth == 0 {
-            return self.material.amb;
+            return Shade::unlit(self.material.amb + self.material.emission);
         }
-        let dir_ammt = ray.dir * (t - EPSILON);
-        let point = ray.src + dir_ammt;
-        let normal = (point - self.center).normalize();
-        let in_shadow = shadow(point, light_pos);
+        let point = spawn_point(self, ray, t, -ray.dir);
+        let normal = self.normal(point, ray.time);
+        if let Some(ref pbr) = self.material.pbr {
+            let shade = shade_pbr(
+                scene,
+                ray,
+                pbr,
+                self.albedo(point, ray.time),
+                point,
+                normal,
+                depth,
+            );
+            return Shade {
+                color: shade.color + self.material.emission,
+                ..shade
+            };
+        }
+        let in_shadow = shadow(scene, point, ray.time);
         let mut mat = self.material.amb;
 
         // Compute Diffuse Component of BRDF
-        let light_dir = (light_pos - point).normalize();
+        let light = scene.light;
+        let (light_dir, _) = light.direction_from(point);
         let mut max = largest_of(na::dot(&normal, &light_dir));
-        mat = mat + Vec3::new(0.35, 0.35, 0.35) * max;
+        mat = mat + light.color * Vec3::new(0.35, 0.35, 0.35) * max;
 
         // Add Specular Contribution of BRDF
         // Compute Halfway Vector
@@ -655,38 +3348,129 @@ impl Surface for Sphere {
         let h = light_dir + negative_dir;
         max = largest_of(na::dot(&normal, &h));
         max.powf(1.2);
-        mat = mat + Vec3::new(0.35f32, 0.35f32, 0.35f32) * max;
+        mat = mat + light.color * Vec3::new(0.35, 0.35, 0.35) * max;
 
-        mat = mat * (1.0f32 - in_shadow) * 0.5;
+        mat = mat * (1.0 - in_shadow) * 0.5;
         // Apply Shadow if necessary
 
+        // Light from emissive Surfaces, which cast their own shadows
+        mat = mat + self.albedo(point, ray.time) * emitter_light(scene, point, normal, ray.time);
+
         // Cast Secondary Ray if Reflective index > 0.0
         if self.material.reflect > 0.0 {
-            mix(
-                mat,
-                reflect(point, ray.dir, normal, light_pos, depth - 1),
-                self.material.reflect,
-            )
+            let reflection = reflect(
+                scene,
+                point,
+                ray.dir,
+                normal,
+                self.material.roughness,
+                depth - 1,
+                ray.time,
+            );
+            Shade {
+                color: mix(mat, reflection, self.material.reflect) + self.material.emission,
+                shadow: in_shadow,
+                reflection: reflection * self.material.reflect,
+            }
         } else {
-            mat
+            Shade {
+                color: mat + self.material.emission,
+                shadow: in_shadow,
+                reflection: Vec3::new(0.0, 0.0, 0.0),
+            }
         }
     }
+
+    fn area(&self) -> Float {
+        4.0 * consts::PI * self.radius_sqr
+    }
+
+    fn sample(&self, u: (Float, Float), time: Float) -> (Vec3<Float>, Vec3<Float>) {
+        let z = 1.0 - 2.0 * u.0;
+        let r = largest_of(1.0 - z * z).sqrt();
+        let phi = 2.0 * consts::PI * u.1;
+        let normal = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+        (
+            self.center_at(time) + normal * self.radius_sqr.sqrt(),
+            normal,
+        )
+    }
+}
+
+/// Per vertex attributes shared by the Triangles of a Mesh, with the normals
+/// and tangents rotated by the transform of its Object
+struct Vertices {
+    mesh: Arc<Mesh>,
+    normals: Option<Vec<Vec3<Float>>>,
+    tangents: Option<Vec<Vec3<Float>>>,
+}
+
+/// Weights of the three vertices of a Triangle at a point on it
+type Barycentric = (Float, Float, Float);
+
+/// Value of a per vertex attribute at the barycentric coordinates 'w'
+fn interpolate(values: &[Vec3<Float>], face: [usize; 3], w: Barycentric) -> Vec3<Float> {
+    values[face[0]] * w.0 + values[face[1]] * w.1 + values[face[2]] * w.2
+}
+
+/// Texture coordinates at the barycentric coordinates 'w', if the Mesh has them
+fn uv_at(mesh: &Mesh, face: [usize; 3], w: Barycentric) -> Option<[Float; 2]> {
+    mesh.uvs.as_ref().map(|uvs| {
+        let (a, b, c) = (uvs[face[0]], uvs[face[1]], uvs[face[2]]);
+        [
+            a[0] * w.0 + b[0] * w.1 + c[0] * w.2,
+            a[1] * w.0 + b[1] * w.1 + c[1] * w.2,
+        ]
+    })
+}
+
+/// Tangent and bitangent of a face without vertex tangents, derived from how
+/// its texture coordinates change along its edges and made perpendicular to
+/// 'n'. The bitangent points towards decreasing V, which is up in the texture.
+fn uv_tangents(
+    mesh: &Mesh,
+    face: [usize; 3],
+    corners: (Vec3<Float>, Vec3<Float>, Vec3<Float>),
+    n: Vec3<Float>,
+) -> (Vec3<Float>, Vec3<Float>) {
+    let uvs = mesh.uvs.as_ref().unwrap();
+    let (a, b, c) = (uvs[face[0]], uvs[face[1]], uvs[face[2]]);
+    let (e1, e2) = (corners.1 - corners.0, corners.2 - corners.0);
+    let (du1, dv1, du2, dv2) = (b[0] - a[0], b[1] - a[1], c[0] - a[0], c[1] - a[1]);
+    let det = du1 * dv2 - du2 * dv1;
+    let tangent = (e1 * dv2 - e2 * dv1) * det.signum();
+    let tangent = tangent - n * na::dot(&n, &tangent);
+    if det == 0.0 || na::norm(&tangent) == 0.0 {
+        return orthonormal_basis(n);
+    }
+    let tangent = tangent.normalize();
+    // Flip the bitangent for mirrored texture coordinates
+    let bitangent = na::cross(&n, &tangent);
+    let up = (e2 * du1 - e1 * du2) * -det.signum();
+    if na::dot(&up, &bitangent) < 0.0 {
+        (tangent, -bitangent)
+    } else {
+        (tangent, bitangent)
+    }
 }
 
 /// Datatype for triangle's in a scene. Contains information
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 struct Triangle {
-    a: Vec3<f32>,
-    b: Vec3<f32>,
-    c: Vec3<f32>,
-    normal: Vec3<f32>,
+    a: Vec3<Float>,
+    b: Vec3<Float>,
+    c: Vec3<Float>,
+    normal: Vec3<Float>,
     material: Material,
     pattern: bool,
+    motion: Option<Motion>,
+    /// Attributes of the Mesh the Triangle belongs to and the index of its face
+    vertices: Option<(Arc<Vertices>, usize)>,
 }
 
 impl Triangle {
-    fn new(_a: Vec3<f32>, _b: Vec3<f32>, _c: Vec3<f32>, mat: Material, p: bool) -> Triangle {
+    fn new(_a: Vec3<Float>, _b: Vec3<Float>, _c: Vec3<Float>, mat: Material, p: bool) -> Triangle {
         let a_b = _a - _b;
         let a_c = _a - _c;
         let n = na::cross(&a_b, &a_c).normalize();
@@ -697,10 +3481,94 @@ impl Triangle {
             normal: n,
             material: mat,
             pattern: p,
+            motion: None,
+            vertices: None,
+        }
+    }
+
+    fn with_motion(self, motion: Motion) -> Triangle {
+        Triangle {
+            motion: Some(motion),
+            ..self
+        }
+    }
+
+    fn with_vertices(self, vertices: Arc<Vertices>, face: usize) -> Triangle {
+        Triangle {
+            vertices: Some((vertices, face)),
+            ..self
+        }
+    }
+
+    /// Weights of the vertices A, B and C at 'point' on the Triangle at 'time'
+    fn barycentric(&self, point: Vec3<Float>, time: Float) -> Barycentric {
+        let (va, vb, vc) = self.vertices_at(time);
+        let (e1, e2, p) = (vb - va, vc - va, point - va);
+        let (d11, d12, d22) = (na::dot(&e1, &e1), na::dot(&e1, &e2), na::dot(&e2, &e2));
+        let (dp1, dp2) = (na::dot(&p, &e1), na::dot(&p, &e2));
+        let det = d11 * d22 - d12 * d12;
+        if det == 0.0 {
+            return (1.0, 0.0, 0.0);
         }
+        let v = (d22 * dp1 - d12 * dp2) / det;
+        let w = (d11 * dp2 - d12 * dp1) / det;
+        (1.0 - v - w, v, w)
     }
-    fn boxed(_a: Vec3<f32>, _b: Vec3<f32>, _c: Vec3<f32>, mat: Material, p: bool) -> Box<Triangle> {
-        Box::new(Triangle::new(_a, _b, _c, mat, p))
+
+    /// Mesh attributes at 'point': the vertices, the indices of the face's
+    /// vertices and the barycentric coordinates
+    fn attributes(
+        &self,
+        point: Vec3<Float>,
+        time: Float,
+    ) -> Option<(&Vertices, [usize; 3], Barycentric)> {
+        self.vertices.as_ref().map(|&(ref vertices, face)| {
+            (
+                &**vertices,
+                vertices.mesh.triangles[face],
+                self.barycentric(point, time),
+            )
+        })
+    }
+
+    /// Normal interpolated from the vertex normals and perturbed by the normal
+    /// map, before any motion
+    fn shading_normal(&self, vertices: &Vertices, face: [usize; 3], w: Barycentric) -> Vec3<Float> {
+        let mut n = match vertices.normals {
+            Some(ref normals) => interpolate(normals, face, w).normalize(),
+            None => self.normal,
+        };
+        let mesh = &vertices.mesh;
+        if let (Some(&(ref map, scale)), Some(uv)) =
+            (mesh.maps.normal.as_ref(), uv_at(mesh, face, w))
+        {
+            let (tangent, bitangent) = match (vertices.tangents.as_ref(), mesh.tangents.as_ref()) {
+                (Some(tangents), Some(signs)) => {
+                    let t = interpolate(tangents, face, w);
+                    let t = (t - n * na::dot(&n, &t)).normalize();
+                    (t, na::cross(&n, &t) * signs[face[0]][3].signum())
+                }
+                _ => uv_tangents(mesh, face, (self.a, self.b, self.c), n),
+            };
+            let texel = map.sample(uv) * 2.0 - Vec3::new(1.0, 1.0, 1.0);
+            let perturbed =
+                tangent * (texel.x * scale) + bitangent * (texel.y * scale) + n * texel.z;
+            if na::norm(&perturbed) > 0.0 {
+                n = perturbed.normalize();
+            }
+        }
+        n
+    }
+
+    fn vertices_at(&self, time: Float) -> (Vec3<Float>, Vec3<Float>, Vec3<Float>) {
+        match self.motion {
+            Some(ref m) => (
+                m.point_at(self.a, time),
+                m.point_at(self.b, time),
+                m.point_at(self.c, time),
+            ),
+            None => (self.a, self.b, self.c),
+        }
     }
 }
 
@@ -711,43 +3579,44 @@ impl Surface for Triangle {
     /// are restricted to Triangle. Solve for Barycentric coords
     /// using Cramers rule of M * [Beta, Gamma, t] = [A - Src]
     /// to solve for [Beta,Gamma,t]
-    fn hit(&self, ray: &Ray) -> Option<f32> {
+    fn hit(&self, ray: &Ray) -> Option<Float> {
         /*
         let edge1 = self.b - self.a;
         let edge2 = self.c - self.a;
         let p = na::cross(&ray.dir, &edge2);
         let det = na::dot(&edge1, &p);
         if det > -EPSILON && det < EPSILON {return None;}
-        let inv_det = 1.0f32 / det;
+        let inv_det = 1.0 / det;
 
         let tvec = ray.src - self.a;
         let u = na::dot(&tvec,&p) * inv_det;
-        if u < 0.0f32 || u > 1.0f32 { return None; }
+        if u < 0.0 || u > 1.0 { return None; }
 
         let q = na::cross(&tvec,&edge1);
         let v = na::dot(&ray.dir, &q);
-        if v < 0.0f32 || u + v > 1.0f32 { return None; }
+        if v < 0.0 || u + v > 1.0 { return None; }
 
         let t = na::dot(&edge2,&q) * inv_det;
-        if t > 0.000001f32 {
+        if t > 0.000001 {
             Some(t)
         } else { None }
         */
 
         // Why not create transformation matrix?
+        let (va, vb, vc) = self.vertices_at(ray.time);
         // Init Matrix M vals
-        let a = self.a.x - self.b.x;
-        let b = self.a.x - self.c.x;
+        let a = va.x - vb.x;
+        let b = va.x - vc.x;
         let c = ray.dir.x;
-        let d = self.a.y - self.b.y;
-        let e = self.a.y - self.c.y;
+        let d = va.y - vb.y;
+        let e = va.y - vc.y;
         let f = ray.dir.y;
-        let g = self.a.z - self.b.z;
-        let h = self.a.z - self.c.z;
+        let g = va.z - vb.z;
+        let h = va.z - vc.z;
         let i = ray.dir.z;
-        let j = self.a.x - ray.src.x;
-        let k = self.a.y - ray.src.y;
-        let l = self.a.z - ray.src.z;
+        let j = va.x - ray.src.x;
+        let k = va.y - ray.src.y;
+        let l = va.z - ray.src.z;
 
         let dheg = d * h - e * g;
         let eihf = e * i - h * f;
@@ -787,17 +3656,40 @@ impl Surface for Triangle {
         }
     }
 
-    fn calculate_color(&self, ray: &Ray, light_pos: Vec3<f32>, t: f32, depth: i32) -> Vec3<f32> {
-        if depth == 0 {
-            return self.material.amb;
+    fn normal(&self, point: Vec3<Float>, time: Float) -> Vec3<Float> {
+        let normal = match self.attributes(point, time) {
+            Some((vertices, face, w)) => self.shading_normal(vertices, face, w),
+            None => self.normal,
+        };
+        match self.motion {
+            Some(ref m) => m.rotate(normal, time),
+            None => normal,
         }
-        let dir_ammt = ray.dir * (t - EPSILON);
-        let point = ray.src + dir_ammt;
-        let normal = self.normal;
-        let in_shadow = shadow(point, light_pos);
-        let mut mat = self.material.amb;
+    }
 
+    fn hit_point(&self, ray: &Ray, t: Float) -> (Vec3<Float>, Vec3<Float>) {
+        // Weighting the corners puts the point on the plane of the Triangle
+        let (va, vb, vc) = self.vertices_at(ray.time);
+        let (u, v, w) = self.barycentric(ray.src + ray.dir * t, ray.time);
+        let point = va * u + vb * v + vc * w;
+        let error = (abs(va * u) + abs(vb * v) + abs(vc * w)) * gamma(7);
+        (point, error)
+    }
+
+    fn geometric_normal(&self, _point: Vec3<Float>, time: Float) -> Vec3<Float> {
+        match self.motion {
+            Some(ref m) => m.rotate(self.normal, time),
+            None => self.normal,
+        }
+    }
+
+    fn material(&self) -> Material {
+        self.material
+    }
+
+    fn albedo(&self, point: Vec3<Float>, time: Float) -> Vec3<Float> {
         // Set Checkerboard pattern
+        let mut mat = self.material.amb;
         if self.pattern {
             let tx = (point.x + 10.0) % 2.0;
             let ty = (point.z + 10.0) % 2.0;
@@ -805,38 +3697,139 @@ impl Surface for Triangle {
                 mat = mat - Vec3::new(0.2, 0.2, 0.2);
             }
         }
+        if let Some((vertices, face, w)) = self.attributes(point, time) {
+            let mesh = &vertices.mesh;
+            if let Some(ref colors) = mesh.colors {
+                let color = interpolate(colors, face, w);
+                mat = if mesh.color_is_albedo {
+                    color
+                } else {
+                    mat * color
+                };
+            }
+            if let (Some(map), Some(uv)) = (mesh.maps.base_color.as_ref(), uv_at(mesh, face, w)) {
+                mat = mat * map.sample(uv);
+            }
+        }
+        mat
+    }
+
+    fn pbr(&self, point: Vec3<Float>, time: Float) -> Option<Pbr> {
+        let mut pbr = self.material.pbr?;
+        if let Some((vertices, face, w)) = self.attributes(point, time) {
+            let mesh = &vertices.mesh;
+            if let (Some(map), Some(uv)) =
+                (mesh.maps.metallic_roughness.as_ref(), uv_at(mesh, face, w))
+            {
+                let texel = map.sample(uv);
+                pbr.roughness *= texel.y;
+                pbr.metallic *= texel.z;
+            }
+        }
+        Some(pbr)
+    }
+
+    fn emission(&self, point: Vec3<Float>, time: Float) -> Vec3<Float> {
+        let mut emission = self.material.emission;
+        if let Some((vertices, face, w)) = self.attributes(point, time) {
+            let mesh = &vertices.mesh;
+            if let (Some(map), Some(uv)) = (mesh.maps.emissive.as_ref(), uv_at(mesh, face, w)) {
+                emission = emission * map.sample(uv);
+            }
+        }
+        emission
+    }
+
+    fn calculate_color(&self, scene: &Scene, ray: &Ray, t: Float, depth: i32) -> Shade {
+        if depth == 0 {
+            let point = ray.src + ray.dir * t;
+            return Shade::unlit(self.albedo(point, ray.time) + self.emission(point, ray.time));
+        }
+        let point = spawn_point(self, ray, t, -ray.dir);
+        let normal = self.normal(point, ray.time);
+        let emission = self.emission(point, ray.time);
+        if let Some(ref pbr) = self.pbr(point, ray.time) {
+            let shade = shade_pbr(
+                scene,
+                ray,
+                pbr,
+                self.albedo(point, ray.time),
+                point,
+                normal,
+                depth,
+            );
+            return Shade {
+                color: shade.color + emission,
+                ..shade
+            };
+        }
+        let in_shadow = shadow(scene, point, ray.time);
+        let mut mat = self.albedo(point, ray.time);
 
         // Compute Diffuse Component of BRDF
-        let light_dir = (light_pos - point).normalize();
+        let light = scene.light;
+        let (light_dir, _) = light.direction_from(point);
         let mut max = largest_of(na::dot(&normal, &light_dir));
-        mat = mat + Vec3::new(0.25, 0.25, 0.25) * max;
+        mat = mat + light.color * Vec3::new(0.25, 0.25, 0.25) * max;
 
         // Add Specular Contribution of BRDF
         // Compute Halfway Vector
         let h = light_dir + ray.dir * -1.0;
         max = largest_of(na::dot(&normal, &h));
         max.powf(1.2);
-        mat = mat + Vec3::new(0.3f32, 0.3f32, 0.3f32) * max;
+        mat = mat + light.color * Vec3::new(0.3, 0.3, 0.3) * max;
 
         // Apply Shadow if necessary
-        mat = mat * 0.5 * (1.0f32 - in_shadow);
+        mat = mat * 0.5 * (1.0 - in_shadow);
+
+        // Light from emissive Surfaces, which cast their own shadows
+        mat = mat + self.albedo(point, ray.time) * emitter_light(scene, point, normal, ray.time);
 
         // Cast Secondary Ray if Reflective index > 0.0
         if self.material.reflect > 0.0 {
-            mix(
-                mat,
-                reflect(point, ray.dir, normal, light_pos, depth - 1),
-                self.material.reflect,
-            )
+            let reflection = reflect(
+                scene,
+                point,
+                ray.dir,
+                normal,
+                self.material.roughness,
+                depth - 1,
+                ray.time,
+            );
+            Shade {
+                color: mix(mat, reflection, self.material.reflect) + emission,
+                shadow: in_shadow,
+                reflection: reflection * self.material.reflect,
+            }
         } else {
-            mat
+            Shade {
+                color: mat + emission,
+                shadow: in_shadow,
+                reflection: Vec3::new(0.0, 0.0, 0.0),
+            }
         }
     }
+
+    fn area(&self) -> Float {
+        na::norm(&na::cross(&(self.b - self.a), &(self.c - self.a))) * 0.5
+    }
+
+    fn sample(&self, u: (Float, Float), time: Float) -> (Vec3<Float>, Vec3<Float>) {
+        let (a, b, c) = self.vertices_at(time);
+        // Fold the unit square onto the triangle
+        let (s, t) = if u.0 + u.1 > 1.0 {
+            (1.0 - u.0, 1.0 - u.1)
+        } else {
+            u
+        };
+        let point = a + (b - a) * s + (c - a) * t;
+        (point, self.geometric_normal(point, time))
+    }
 }
 
 // --- Helper Functions ---
 /// Mix function is used to blend between two colors
-fn mix(color_a: Vec3<f32>, color_b: Vec3<f32>, alpha: f32) -> Vec3<f32> {
+fn mix(color_a: Vec3<Float>, color_b: Vec3<Float>, alpha: Float) -> Vec3<Float> {
     Vec3::new(
         (1.0 - alpha) * color_a[0] + alpha * color_b[0],
         (1.0 - alpha) * color_a[1] + alpha * color_b[1],
@@ -844,6 +3837,48 @@ fn mix(color_a: Vec3<f32>, color_b: Vec3<f32>, alpha: f32) -> Vec3<f32> {
     )
 }
 
-fn largest_of(num: f32) -> f32 {
-    return if num < 0.0 { 0.0 as f32 } else { num as f32 };
+/// Flips the normal if necessary so that it faces against the incoming direction
+fn facing(normal: Vec3<Float>, dir: Vec3<Float>) -> Vec3<Float> {
+    if na::dot(&normal, &dir) > 0.0 {
+        normal * -1.0
+    } else {
+        normal
+    }
+}
+
+/// Builds two unit vectors that together with 'normal' form an orthonormal basis
+fn orthonormal_basis(normal: Vec3<Float>) -> (Vec3<Float>, Vec3<Float>) {
+    let helper = if normal.x.abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let tangent = na::cross(&helper, &normal).normalize();
+    let bitangent = na::cross(&normal, &tangent);
+    (tangent, bitangent)
+}
+
+/// Maps an ID to an arbitrary but stable color so neighbouring IDs are easy to tell apart.
+/// ID 0 is reserved for the background and is black.
+fn id_color(id: u32) -> Vec3<Float> {
+    if id == 0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    let mut h = id.wrapping_mul(0x9e37_79b9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    Vec3::new(
+        (h & 0xff) as Float / 255.0,
+        ((h >> 8) & 0xff) as Float / 255.0,
+        ((h >> 16) & 0xff) as Float / 255.0,
+    )
+}
+
+fn largest_of(num: Float) -> Float {
+    return if num < 0.0 {
+        0.0 as Float
+    } else {
+        num as Float
+    };
 }
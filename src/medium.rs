@@ -0,0 +1,133 @@
+//! Light travelling through something other than a vacuum. Fog is a cheap global
+//! effect that blends whatever a Ray hits towards the fog color with distance,
+//! optionally thinning out with height. A Medium fills the inside of a closed
+//! object with particles that absorb and scatter light, like smoke or milky
+//! water. Scattering follows the Henyey-Greenstein phase function.
+
+use na;
+use na::{Norm, Vec3};
+
+use precision::{consts::PI, Float};
+use {orthonormal_basis, Ray};
+
+/// Exponential fog filling the whole scene
+#[derive(Copy, Clone)]
+pub struct Fog {
+    pub color: Vec3<Float>,
+    /// Extinction per unit of distance at the base height
+    pub density: Float,
+    /// How quickly the fog thins out above the base height, 0 gives a uniform
+    /// distance fog
+    pub falloff: Float,
+    pub base: Float,
+}
+
+impl Fog {
+    /// Fraction of the light that makes it through 'distance' along 'ray'
+    pub fn transmittance(&self, ray: &Ray, distance: Float) -> Float {
+        if self.falloff <= 0.0 {
+            return (-self.density * distance).exp();
+        }
+        // Integral of density * exp(-falloff * (height - base)) along the ray. The
+        // exponents are added before exponentiating so that far distances, like
+        // the far end of Rays that miss everything, don't turn it into 0 * inf.
+        let above = self.falloff * (ray.src.y - self.base);
+        let rise = self.falloff * ray.dir.y;
+        let depth = if rise.abs() * distance > 1e-4 {
+            self.density * ((-above).exp() - (-above - rise * distance).exp()) / rise
+        } else {
+            self.density * (-above).exp() * distance
+        };
+        (-depth).exp()
+    }
+
+    /// Blends 'color', seen at 'distance' along 'ray', towards the fog color
+    pub fn apply(&self, color: Vec3<Float>, ray: &Ray, distance: Float) -> Vec3<Float> {
+        let t = self.transmittance(ray, distance);
+        color * t + self.color * (1.0 - t)
+    }
+}
+
+/// Homogeneous participating medium. Coefficients are per unit of distance and
+/// per color channel.
+#[derive(Copy, Clone)]
+pub struct Medium {
+    pub absorption: Vec3<Float>,
+    pub scattering: Vec3<Float>,
+    /// Asymmetry of the phase function from -1 (back scattering) over 0
+    /// (isotropic) to 1 (forward scattering)
+    pub g: Float,
+}
+
+impl Medium {
+    pub fn extinction(&self) -> Vec3<Float> {
+        self.absorption + self.scattering
+    }
+
+    /// Fraction of the light that makes it through 'distance' of the medium
+    pub fn transmittance(&self, distance: Float) -> Vec3<Float> {
+        let sigma = self.extinction();
+        let channel = |s: Float| if s > 0.0 { (-s * distance).exp() } else { 1.0 };
+        Vec3::new(channel(sigma.x), channel(sigma.y), channel(sigma.z))
+    }
+
+    /// Density of scattering light travelling in 'dir_in' into 'dir_out', per
+    /// steradian
+    pub fn phase(&self, dir_in: Vec3<Float>, dir_out: Vec3<Float>) -> Float {
+        let cos = na::dot(&dir_in, &dir_out);
+        let denom = 1.0 + self.g * self.g - 2.0 * self.g * cos;
+        (1.0 - self.g * self.g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+    /// Picks the direction light travelling in 'dir' is scattered into, in
+    /// proportion to the phase function. 'u' is uniform in [0, 1) x [0, 1).
+    pub fn sample_phase(&self, dir: Vec3<Float>, u: (Float, Float)) -> Vec3<Float> {
+        let g = self.g;
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u.0
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u.0);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let cos = cos.max(-1.0).min(1.0);
+        let sin = (1.0 - cos * cos).sqrt();
+        let phi = 2.0 * PI * u.1;
+        let (tangent, bitangent) = orthonormal_basis(dir);
+        (tangent * (sin * phi.cos()) + bitangent * (sin * phi.sin()) + dir * cos).normalize()
+    }
+
+    /// Free-flight sampling of the distance to the next scattering event along a
+    /// ray that leaves the medium after 'max'. Returns the distance, or None if
+    /// the ray made it through, together with the weight to multiply the path's
+    /// throughput by. The distance is sampled for one randomly chosen channel and
+    /// weighted against all three, so colored media stay unbiased.
+    pub fn sample_distance(&self, u: (Float, Float), max: Float) -> (Option<Float>, Vec3<Float>) {
+        let sigma = self.extinction();
+        let channel = [sigma.x, sigma.y, sigma.z][((u.0 * 3.0) as usize).min(2)];
+        let distance = if channel > 0.0 {
+            -(1.0 - u.1).ln() / channel
+        } else {
+            Float::INFINITY
+        };
+        let average = |v: Vec3<Float>| (v.x + v.y + v.z) / 3.0;
+        if distance < max {
+            let tr = self.transmittance(distance);
+            let pdf = average(sigma * tr);
+            let weight = if pdf > 0.0 {
+                self.scattering * tr / pdf
+            } else {
+                Vec3::new(0.0, 0.0, 0.0)
+            };
+            (Some(distance), weight)
+        } else {
+            let tr = self.transmittance(max);
+            let pdf = average(tr);
+            let weight = if pdf > 0.0 {
+                tr / pdf
+            } else {
+                Vec3::new(0.0, 0.0, 0.0)
+            };
+            (None, weight)
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Loads triangle meshes from PLY (Polygon File Format) files, the format most
+//! 3D scanners export. ASCII as well as little and big endian binary files are
+//! read. Vertex positions, normals and colors are taken from the 'vertex'
+//! element and the polygons of the 'face' element are split into triangles.
+//! Every other element and property is skipped. Point clouds, files without
+//! faces, aren't supported.
+//!
+//! ```text
+//! ply
+//! format ascii 1.0
+//! element vertex 3
+//! property float x
+//! property float y
+//! property float z
+//! property uchar red
+//! property uchar green
+//! property uchar blue
+//! element face 1
+//! property list uchar int vertex_indices
+//! end_header
+//! 0 0 0 255 0 0
+//! 1 0 0 0 255 0
+//! 0 1 0 0 0 255
+//! 3 0 1 2
+//! ```
+
+use na::{Norm, Vec3};
+use std::fs;
+use std::str::{self, SplitWhitespace};
+
+use environment::srgb_to_linear;
+use precision::Float;
+use scene::{Maps, Mesh};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+/// Type of a property value
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Scalar {
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+    Float64,
+}
+
+impl Scalar {
+    /// Accepts the original type names as well as the sized ones
+    fn parse(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::Int8),
+            "uchar" | "uint8" => Some(Scalar::Uint8),
+            "short" | "int16" => Some(Scalar::Int16),
+            "ushort" | "uint16" => Some(Scalar::Uint16),
+            "int" | "int32" => Some(Scalar::Int32),
+            "uint" | "uint32" => Some(Scalar::Uint32),
+            "float" | "float32" => Some(Scalar::Float32),
+            "double" | "float64" => Some(Scalar::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Scalar::Int8 | Scalar::Uint8 => 1,
+            Scalar::Int16 | Scalar::Uint16 => 2,
+            Scalar::Int32 | Scalar::Uint32 | Scalar::Float32 => 4,
+            Scalar::Float64 => 8,
+        }
+    }
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    /// Variable length list with the types of its length and of its items
+    List(String, Scalar, Scalar),
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match *self {
+            Property::Scalar(ref name, _) | Property::List(ref name, _, _) => name,
+        }
+    }
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Source of the values following the header
+enum Body<'a> {
+    Ascii(SplitWhitespace<'a>),
+    Binary { data: &'a [u8], big_endian: bool },
+}
+
+impl<'a> Body<'a> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        match *self {
+            Body::Ascii(ref mut tokens) => {
+                let token = tokens.next().ok_or("The file ends early")?;
+                token
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid value '{}'", token))
+            }
+            Body::Binary {
+                ref mut data,
+                big_endian,
+            } => {
+                let size = scalar.size();
+                if data.len() < size {
+                    return Err("The file ends early".to_string());
+                }
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(&data[..size]);
+                if big_endian {
+                    bytes[..size].reverse();
+                }
+                *data = &data[size..];
+                let b = bytes;
+                Ok(match scalar {
+                    Scalar::Int8 => f64::from(b[0] as i8),
+                    Scalar::Uint8 => f64::from(b[0]),
+                    Scalar::Int16 => f64::from(i16::from_le_bytes([b[0], b[1]])),
+                    Scalar::Uint16 => f64::from(u16::from_le_bytes([b[0], b[1]])),
+                    Scalar::Int32 => f64::from(i32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                    Scalar::Uint32 => f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                    Scalar::Float32 => f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                    Scalar::Float64 => f64::from_le_bytes(b),
+                })
+            }
+        }
+    }
+}
+
+/// Reads the mesh in the PLY file at 'path'. Its vertex colors, if it has
+/// any, are used as the diffuse color in place of the color of the Material.
+pub fn load(path: &str) -> Result<Mesh, String> {
+    let data = fs::read(path).map_err(|e| format!("Can't read '{}': {}", path, e))?;
+    parse(&data).map_err(|e| format!("Can't parse '{}': {}", path, e))
+}
+
+fn parse(data: &[u8]) -> Result<Mesh, String> {
+    let (format, elements, body) = header(data)?;
+    let mut body = match format {
+        Format::Ascii => Body::Ascii(
+            str::from_utf8(body)
+                .map_err(|_| "The ASCII body is not valid text")?
+                .split_whitespace(),
+        ),
+        Format::BinaryLittleEndian | Format::BinaryBigEndian => Body::Binary {
+            data: body,
+            big_endian: format == Format::BinaryBigEndian,
+        },
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+    let mut triangles = Vec::new();
+    for element in elements.iter() {
+        let find = |name: &str| element.properties.iter().position(|p| p.name() == name);
+        let position = (find("x"), find("y"), find("z"));
+        let normal = (find("nx"), find("ny"), find("nz"));
+        let color = match (find("red"), find("green"), find("blue")) {
+            (Some(r), Some(g), Some(b)) => Some((r, g, b)),
+            _ => match (
+                find("diffuse_red"),
+                find("diffuse_green"),
+                find("diffuse_blue"),
+            ) {
+                (Some(r), Some(g), Some(b)) => Some((r, g, b)),
+                _ => None,
+            },
+        };
+        let indices = find("vertex_indices").or_else(|| find("vertex_index"));
+        let mut values = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            let mut list = Vec::new();
+            for (i, property) in element.properties.iter().enumerate() {
+                match *property {
+                    Property::Scalar(_, scalar) => values[i] = body.read(scalar)?,
+                    Property::List(_, count, item) => {
+                        // The length comes from the file, so it isn't used to allocate
+                        let n = body.read(count)? as usize;
+                        let mut items = Vec::new();
+                        for _ in 0..n {
+                            items.push(body.read(item)? as usize);
+                        }
+                        if Some(i) == indices {
+                            list = items;
+                        }
+                    }
+                }
+            }
+            if element.name == "vertex" {
+                if let (Some(x), Some(y), Some(z)) = position {
+                    positions.push(Vec3::new(
+                        values[x] as Float,
+                        values[y] as Float,
+                        values[z] as Float,
+                    ));
+                } else {
+                    return Err("The vertices have no position".to_string());
+                }
+                if let (Some(x), Some(y), Some(z)) = normal {
+                    normals.push(Vec3::new(
+                        values[x] as Float,
+                        values[y] as Float,
+                        values[z] as Float,
+                    ));
+                }
+                if let Some((r, g, b)) = color {
+                    let channel = |i: usize| match element.properties[i] {
+                        Property::Scalar(_, Scalar::Uint8) => srgb_to_linear(values[i] as u8),
+                        Property::Scalar(_, Scalar::Uint16) => (values[i] / 65535.0) as Float,
+                        _ => values[i] as Float,
+                    };
+                    colors.push(Vec3::new(channel(r), channel(g), channel(b)));
+                }
+            } else if element.name == "face" {
+                // Polygons are split into a fan of triangles
+                for k in 2..list.len() {
+                    triangles.push([list[0], list[k - 1], list[k]]);
+                }
+            }
+        }
+    }
+    if positions.is_empty() {
+        return Err("The file has no vertices".to_string());
+    }
+    if triangles.is_empty() {
+        return Err("The file has no faces, point clouds aren't supported".to_string());
+    }
+    if triangles
+        .iter()
+        .any(|t| t.iter().any(|&i| i >= positions.len()))
+    {
+        return Err("A face refers to a vertex that doesn't exist".to_string());
+    }
+    Ok(Mesh {
+        normals: if normals.is_empty() {
+            None
+        } else {
+            Some(normals.iter().map(|n| safe_normalize(*n)).collect())
+        },
+        colors: if colors.is_empty() {
+            None
+        } else {
+            Some(colors)
+        },
+        color_is_albedo: true,
+        positions: positions,
+        uvs: None,
+        tangents: None,
+        triangles: triangles,
+        maps: Maps::default(),
+    })
+}
+
+/// Parses the header, returning the format, the elements and the rest of the data
+fn header(data: &[u8]) -> Result<(Format, Vec<Element>, &[u8]), String> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut rest = data;
+    let mut first = true;
+    loop {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or("The header has no end_header")?;
+        let line = str::from_utf8(&rest[..end]).map_err(|_| "The header is not valid text")?;
+        rest = &rest[end + 1..];
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if first {
+            if words != ["ply"] {
+                return Err("Not a PLY file".to_string());
+            }
+            first = false;
+            continue;
+        }
+        match words.first().cloned() {
+            Some("format") => {
+                format = Some(match words.get(1).cloned() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => Format::BinaryLittleEndian,
+                    Some("binary_big_endian") => Format::BinaryBigEndian,
+                    _ => return Err(format!("Unknown format '{}'", line)),
+                })
+            }
+            Some("element") => match (words.get(1), words.get(2).map(|n| n.parse())) {
+                (Some(name), Some(Ok(count))) => elements.push(Element {
+                    name: name.to_string(),
+                    count: count,
+                    properties: Vec::new(),
+                }),
+                _ => return Err(format!("Invalid element '{}'", line)),
+            },
+            Some("property") => {
+                let element = elements
+                    .last_mut()
+                    .ok_or("A property comes before any element")?;
+                let scalar = |i: usize| {
+                    words
+                        .get(i)
+                        .and_then(|name| Scalar::parse(name))
+                        .ok_or(format!("Invalid property '{}'", line))
+                };
+                let property = if words.get(1) == Some(&"list") {
+                    match words.get(4) {
+                        Some(name) => Property::List(name.to_string(), scalar(2)?, scalar(3)?),
+                        None => return Err(format!("Invalid property '{}'", line)),
+                    }
+                } else {
+                    match words.get(2) {
+                        Some(name) => Property::Scalar(name.to_string(), scalar(1)?),
+                        None => return Err(format!("Invalid property '{}'", line)),
+                    }
+                };
+                element.properties.push(property);
+            }
+            Some("end_header") => break,
+            // Comments, obj_info and blank lines
+            _ => {}
+        }
+    }
+    match format {
+        Some(format) => Ok((format, elements, rest)),
+        None => Err("The header has no format".to_string()),
+    }
+}
+
+fn safe_normalize(v: Vec3<Float>) -> Vec3<Float> {
+    if v.sqnorm() > 0.0 {
+        v.normalize()
+    } else {
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII: &str = "ply
+format ascii 1.0
+comment a triangle with colored corners
+element vertex 3
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+1 0 0 0 255 0
+0 1 0 0 0 255
+3 0 1 2
+";
+
+    /// A unit square with normals as a single quad, in a binary format
+    fn binary(format: &str, big_endian: bool) -> Vec<u8> {
+        let mut data = format!(
+            "ply\nformat {} 1.0\nelement vertex 4\nproperty float x\nproperty float y\n\
+             property float z\nproperty double nx\nproperty double ny\nproperty double nz\n\
+             element face 1\nproperty list uchar uint vertex_indices\nend_header\n",
+            format
+        )
+        .into_bytes();
+        let corners = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+        for c in corners.iter() {
+            for &v in [c[0] as f32, c[1] as f32, 0.0].iter() {
+                data.extend_from_slice(&if big_endian {
+                    v.to_be_bytes()
+                } else {
+                    v.to_le_bytes()
+                });
+            }
+            for &v in [0.0f64, 0.0, 2.0].iter() {
+                data.extend_from_slice(&if big_endian {
+                    v.to_be_bytes()
+                } else {
+                    v.to_le_bytes()
+                });
+            }
+        }
+        data.push(4);
+        for i in 0..4u32 {
+            data.extend_from_slice(&if big_endian {
+                i.to_be_bytes()
+            } else {
+                i.to_le_bytes()
+            });
+        }
+        data
+    }
+
+    fn check_square(mesh: &Mesh) {
+        assert_eq!(mesh.positions.len(), 4);
+        assert_eq!(mesh.positions[2], Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(mesh.triangles, vec![[0, 1, 2], [0, 2, 3]]);
+        let normals = mesh.normals.as_ref().unwrap();
+        assert!(normals.iter().all(|n| *n == Vec3::new(0.0, 0.0, 1.0)));
+        assert!(mesh.colors.is_none());
+    }
+
+    #[test]
+    fn ascii() {
+        let mesh = parse(ASCII.as_bytes()).unwrap();
+        assert_eq!(mesh.positions[1], Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(mesh.triangles, vec![[0, 1, 2]]);
+        assert!(mesh.normals.is_none());
+        let colors = mesh.colors.unwrap();
+        assert_eq!(colors[0], Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(colors[2], Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn binary_little_endian() {
+        check_square(&parse(&binary("binary_little_endian", false)).unwrap());
+    }
+
+    #[test]
+    fn binary_big_endian() {
+        check_square(&parse(&binary("binary_big_endian", true)).unwrap());
+    }
+
+    #[test]
+    fn truncated_binary_is_rejected() {
+        let data = binary("binary_little_endian", false);
+        assert!(parse(&data[..data.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn point_cloud_is_rejected() {
+        let header_end = ASCII.find("element face").unwrap();
+        let points = format!(
+            "{}end_header\n0 0 0 255 0 0\n1 0 0 0 255 0\n0 1 0 0 0 255\n",
+            &ASCII[..header_end]
+        );
+        assert!(parse(points.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Scalar type the renderer computes with and the bounds on its rounding error.
+//! Everything is f32 unless the 'f64' feature is enabled, which scenes with
+//! large coordinates, like whole buildings, need to be shaded correctly.
+//!
+//! Rays leaving a Surface can't start exactly at the hit point, rounding puts
+//! the computed point slightly above or below the Surface and the Ray could hit
+//! the Surface it starts on again. Instead of backing off along the Ray by a
+//! fixed distance, which is too little for points far from the origin and too
+//! much for grazing angles, the origin is pushed along the geometric normal by
+//! just more than the error the hit point was computed with.
+
+use na;
+use na::Vec3;
+
+#[cfg(not(feature = "f64"))]
+pub use std::f32::consts;
+#[cfg(feature = "f64")]
+pub use std::f64::consts;
+
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+/// Bound on the relative error of the result of 'n' rounded operations in a row
+pub fn gamma(n: u32) -> Float {
+    let e = n as Float * Float::EPSILON * 0.5;
+    e / (1.0 - e)
+}
+
+/// 'x' rounded to f32, for image formats that only store f32
+#[allow(clippy::unnecessary_cast)]
+pub fn to_f32(x: Float) -> f32 {
+    x as f32
+}
+
+/// Absolute value of each coordinate
+pub fn abs(v: Vec3<Float>) -> Vec3<Float> {
+    Vec3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+/// Smallest Float larger than 'x'
+pub fn next_up(x: Float) -> Float {
+    if x.is_nan() || x == Float::INFINITY {
+        return x;
+    }
+    // Both zeros step to the smallest positive number
+    let x = if x == 0.0 { 0.0 } else { x };
+    let bits = x.to_bits();
+    Float::from_bits(if x >= 0.0 { bits + 1 } else { bits - 1 })
+}
+
+/// Largest Float smaller than 'x'
+pub fn next_down(x: Float) -> Float {
+    -next_up(-x)
+}
+
+/// Origin for a Ray leaving the Surface at 'point', whose coordinates are off
+/// by at most 'error', on the side of the geometric 'normal' that 'towards'
+/// points to. The origin is far enough from the Surface that neither the
+/// rounding of 'point' nor of the offset itself can put it on the wrong side.
+pub fn offset_origin(
+    point: Vec3<Float>,
+    error: Vec3<Float>,
+    normal: Vec3<Float>,
+    towards: Vec3<Float>,
+) -> Vec3<Float> {
+    let n = if na::dot(&normal, &towards) < 0.0 {
+        -normal
+    } else {
+        normal
+    };
+    let distance = na::dot(&abs(n), &error);
+    let origin = point + n * distance;
+    // Adding the offset rounds too, so every coordinate moves one more step
+    // away, which also lifts points without any error off the Surface
+    let away = |x: Float, side: Float| {
+        if side > 0.0 {
+            next_up(x)
+        } else if side < 0.0 {
+            next_down(x)
+        } else {
+            x
+        }
+    };
+    Vec3::new(
+        away(origin.x, n.x),
+        away(origin.y, n.y),
+        away(origin.z, n.z),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_up_steps_to_the_neighbour() {
+        assert_eq!(next_up(1.0), Float::from_bits((1.0 as Float).to_bits() + 1));
+        assert_eq!(
+            next_down(1.0),
+            Float::from_bits((1.0 as Float).to_bits() - 1)
+        );
+        assert_eq!(next_up(-1.0), -next_down(1.0));
+        assert!(next_up(0.0) > 0.0);
+        assert_eq!(next_up(-0.0), next_up(0.0));
+        assert!(next_down(0.0) < 0.0);
+    }
+
+    #[test]
+    fn next_up_keeps_infinity_and_nan() {
+        assert_eq!(next_up(Float::INFINITY), Float::INFINITY);
+        assert_eq!(next_down(Float::NEG_INFINITY), Float::NEG_INFINITY);
+        assert_eq!(next_up(Float::NEG_INFINITY), -Float::MAX);
+        assert!(next_up(Float::NAN).is_nan());
+    }
+
+    #[test]
+    fn offset_origin_leaves_the_surface() {
+        let point = Vec3::new(1.0, 2.0, 3.0);
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let none = Vec3::new(0.0, 0.0, 0.0);
+        // Even without any error the origin is lifted off the Surface
+        let above = offset_origin(point, none, normal, normal);
+        assert_eq!((above.x, above.y), (1.0, 2.0));
+        assert!(above.z > 3.0);
+        let below = offset_origin(point, none, normal, -normal);
+        assert!(below.z < 3.0);
+        // The offset covers the error along the normal
+        let error = Vec3::new(0.5, 0.5, 0.25);
+        assert!(offset_origin(point, error, normal, normal).z > 3.25);
+        assert!(offset_origin(point, error, normal, -normal).z < 2.75);
+    }
+}
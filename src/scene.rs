@@ -0,0 +1,401 @@
+//! Description of the scene that is rendered. The description holds named
+//! Objects and Materials so their values can be animated, and is turned into
+//! the Surfaces traced by the renderer once per frame.
+
+use na;
+use na::{Rot3, Rotation, RotationTo, Vec3};
+use std::sync::Arc;
+
+use camera::View;
+use environment::Environment;
+use medium::Fog;
+use precision::{consts, Float};
+use texture::Texture;
+use {Material, Motion, Ray, Sphere, Surface, Triangle, Vertices, BKG_COLOR, LIGHT_RADIUS};
+
+/// Angular radius of the sun in radians, used for the soft shadows of directional lights
+const SUN_ANGULAR_RADIUS: Float = 0.00465;
+
+/// Point light with a position and color. Lights with a direction, like the sun,
+/// are infinitely far away and their position is ignored.
+#[derive(Copy, Clone)]
+pub struct Light {
+    pub position: Vec3<Float>,
+    pub color: Vec3<Float>,
+    /// Unit vector pointing towards a directional light
+    pub direction: Option<Vec3<Float>>,
+}
+
+impl Light {
+    /// Unit vector from 'point' towards the center of the light and the distance to it
+    pub fn direction_from(&self, point: Vec3<Float>) -> (Vec3<Float>, Float) {
+        match self.direction {
+            Some(dir) => (dir, Float::INFINITY),
+            None => {
+                let to_light = self.position - point;
+                let distance = na::norm(&to_light);
+                (to_light / distance, distance)
+            }
+        }
+    }
+
+    /// Unit vector from 'point' towards a random point on the light and the distance
+    /// to it, used for soft shadows. 'u' is a uniform sample in [0, 1) for each axis.
+    /// Point lights are treated as a cube of LIGHT_RADIUS, directional lights cover
+    /// the disk of the sun.
+    pub fn sample_from(
+        &self,
+        point: Vec3<Float>,
+        u: (Float, Float, Float),
+    ) -> (Vec3<Float>, Float) {
+        match self.direction {
+            Some(dir) => {
+                let cos_theta = 1.0 - u.0 * (1.0 - SUN_ANGULAR_RADIUS.cos());
+                let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+                let phi = 2.0 * consts::PI * u.1;
+                let (tangent, bitangent) = ::orthonormal_basis(dir);
+                let sample = tangent * (sin_theta * phi.cos())
+                    + bitangent * (sin_theta * phi.sin())
+                    + dir * cos_theta;
+                (sample, Float::INFINITY)
+            }
+            None => {
+                let offset = Vec3::new(u.0, u.1, u.2) * (2.0 * LIGHT_RADIUS)
+                    - Vec3::new(LIGHT_RADIUS, LIGHT_RADIUS, LIGHT_RADIUS);
+                let to_light = self.position + offset - point;
+                let distance = na::norm(&to_light);
+                (to_light / distance, distance)
+            }
+        }
+    }
+}
+
+/// Placement of an Object relative to where its Primitives are defined.
+/// The Object is scaled and rotated about its pivot and then translated.
+#[derive(Copy, Clone)]
+pub struct Transform {
+    pub translation: Vec3<Float>,
+    /// Euler angles in degrees, applied about X, then Y, then Z
+    pub rotation: Vec3<Float>,
+    pub scale: Float,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            translation: Vec3::new(0.0, 0.0, 0.0),
+            rotation: Vec3::new(0.0, 0.0, 0.0),
+            scale: 1.0,
+        }
+    }
+
+    fn rotation_matrix(&self) -> Rot3<Float> {
+        Rot3::new_with_euler_angles(
+            self.rotation.x.to_radians(),
+            self.rotation.y.to_radians(),
+            self.rotation.z.to_radians(),
+        )
+    }
+
+    fn apply(&self, p: Vec3<Float>, pivot: Vec3<Float>) -> Vec3<Float> {
+        pivot + self.translation + self.rotation_matrix() * ((p - pivot) * self.scale)
+    }
+
+    /// Rigid Motion that moves an Object from this Transform to 'next' over the
+    /// course of a frame. Changes in scale are not blurred. Returns None if the
+    /// Object does not move.
+    fn motion_to(&self, next: &Transform, pivot: Vec3<Float>) -> Option<Motion> {
+        let translation = next.translation - self.translation;
+        let axis_angle = self
+            .rotation_matrix()
+            .rotation_to(&next.rotation_matrix())
+            .rotation();
+        let angle = na::norm(&axis_angle);
+        if angle == 0.0 && translation == Vec3::new(0.0, 0.0, 0.0) {
+            return None;
+        }
+        Some(Motion {
+            translation: translation,
+            axis: if angle > 0.0 {
+                axis_angle / angle
+            } else {
+                axis_angle
+            },
+            angle: angle,
+            pivot: pivot + self.translation,
+        })
+    }
+}
+
+/// Textures that modulate the values of a Mesh's Material
+#[derive(Clone, Default)]
+pub struct Maps {
+    pub base_color: Option<Arc<Texture>>,
+    /// Roughness in the green and metalness in the blue channel
+    pub metallic_roughness: Option<Arc<Texture>>,
+    /// Tangent space normals and the scale of their X and Y components
+    pub normal: Option<(Arc<Texture>, Float)>,
+    pub emissive: Option<Arc<Texture>>,
+}
+
+/// Indexed triangle mesh with optional per vertex attributes, as loaded from a
+/// file. Triangles are counter clockwise when seen from the front.
+pub struct Mesh {
+    pub positions: Vec<Vec3<Float>>,
+    pub normals: Option<Vec<Vec3<Float>>>,
+    /// Texture coordinates, (0, 0) is the top left corner of the textures
+    pub uvs: Option<Vec<[Float; 2]>>,
+    /// Direction of increasing U, the sign of the fourth component gives the
+    /// direction of the bitangent relative to the normal crossed with the tangent
+    pub tangents: Option<Vec<[Float; 4]>>,
+    /// Linear vertex colors that multiply the color of the Material
+    pub colors: Option<Vec<Vec3<Float>>>,
+    /// Use the vertex colors as the diffuse color in place of the color of the
+    /// Material
+    pub color_is_albedo: bool,
+    pub triangles: Vec<[usize; 3]>,
+    pub maps: Maps,
+}
+
+/// Geometry of an Object. Materials are referred to by their index in the
+/// Materials of the SceneDesc.
+#[derive(Clone)]
+pub enum Primitive {
+    Sphere {
+        center: Vec3<Float>,
+        radius: Float,
+        material: usize,
+    },
+    Triangle {
+        a: Vec3<Float>,
+        b: Vec3<Float>,
+        c: Vec3<Float>,
+        material: usize,
+        pattern: bool,
+    },
+    Mesh {
+        mesh: Arc<Mesh>,
+        material: usize,
+    },
+}
+
+/// Named group of Primitives that are transformed together
+#[derive(Clone)]
+pub struct Object {
+    pub name: String,
+    pub pivot: Vec3<Float>,
+    pub transform: Transform,
+    pub primitives: Vec<Primitive>,
+}
+
+/// Everything that makes up the scene at a single point in time
+#[derive(Clone)]
+pub struct SceneDesc {
+    pub view: View,
+    pub light: Light,
+    pub materials: Vec<(String, Material)>,
+    pub objects: Vec<Object>,
+    /// Surroundings of the scene, None uses the constant background color
+    pub environment: Option<Arc<Environment>>,
+    pub fog: Option<Fog>,
+}
+
+impl SceneDesc {
+    pub fn material_mut(&mut self, name: &str) -> Option<&mut Material> {
+        self.materials
+            .iter_mut()
+            .find(|m| m.0 == name)
+            .map(|m| &mut m.1)
+    }
+
+    pub fn object_mut(&mut self, name: &str) -> Option<&mut Object> {
+        self.objects.iter_mut().find(|o| o.name == name)
+    }
+
+    /// Creates the Surfaces of the scene as it is described here. 'next' describes
+    /// the scene at the end of the frame and is used to find how Objects move
+    /// while the shutter is open.
+    pub fn build(&self, next: &SceneDesc) -> Scene {
+        let mut surfaces: Vec<Box<Surface>> = Vec::new();
+        let mut sources = Vec::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            let t = object.transform;
+            let motion = next
+                .objects
+                .get(index)
+                .and_then(|n| t.motion_to(&n.transform, object.pivot));
+            for prim in object.primitives.iter() {
+                let first = surfaces.len();
+                match *prim {
+                    Primitive::Sphere {
+                        center,
+                        radius,
+                        material,
+                    } => {
+                        let s = Sphere::new(
+                            t.apply(center, object.pivot),
+                            radius * t.scale,
+                            self.materials[material].1,
+                        );
+                        surfaces.push(Box::new(match motion {
+                            Some(m) => s.with_motion(m),
+                            None => s,
+                        }));
+                    }
+                    Primitive::Triangle {
+                        a,
+                        b,
+                        c,
+                        material,
+                        pattern,
+                    } => {
+                        let tri = Triangle::new(
+                            t.apply(a, object.pivot),
+                            t.apply(b, object.pivot),
+                            t.apply(c, object.pivot),
+                            self.materials[material].1,
+                            pattern,
+                        );
+                        surfaces.push(Box::new(match motion {
+                            Some(m) => tri.with_motion(m),
+                            None => tri,
+                        }));
+                    }
+                    Primitive::Mesh { ref mesh, material } => {
+                        // Normals and tangents only rotate, the scale is uniform
+                        let rotation = t.rotation_matrix();
+                        let vertices = Arc::new(Vertices {
+                            mesh: mesh.clone(),
+                            normals: mesh
+                                .normals
+                                .as_ref()
+                                .map(|normals| normals.iter().map(|n| rotation * *n).collect()),
+                            tangents: mesh.tangents.as_ref().map(|tangents| {
+                                tangents
+                                    .iter()
+                                    .map(|t| rotation * Vec3::new(t[0], t[1], t[2]))
+                                    .collect()
+                            }),
+                        });
+                        let points: Vec<Vec3<Float>> = mesh
+                            .positions
+                            .iter()
+                            .map(|p| t.apply(*p, object.pivot))
+                            .collect();
+                        for (face, tri) in mesh.triangles.iter().enumerate() {
+                            let tri = Triangle::new(
+                                points[tri[0]],
+                                points[tri[1]],
+                                points[tri[2]],
+                                self.materials[material].1,
+                                false,
+                            )
+                            .with_vertices(vertices.clone(), face);
+                            surfaces.push(Box::new(match motion {
+                                Some(m) => tri.with_motion(m),
+                                None => tri,
+                            }));
+                        }
+                    }
+                }
+                let material = match *prim {
+                    Primitive::Sphere { material, .. }
+                    | Primitive::Triangle { material, .. }
+                    | Primitive::Mesh { material, .. } => material,
+                };
+                for _ in first..surfaces.len() {
+                    sources.push((index, material));
+                }
+            }
+        }
+        // Emissive Surfaces are picked in proportion to their power
+        let mut emitters = Vec::new();
+        let mut total = 0.0;
+        for (index, surface) in surfaces.iter().enumerate() {
+            let e = surface.material().emission;
+            let power = (e.x + e.y + e.z) / 3.0 * surface.area();
+            if power > 0.0 {
+                total += power;
+                emitters.push((index, total));
+            }
+        }
+        Scene {
+            surfaces: surfaces,
+            sources: sources,
+            emitters: emitters,
+            light: self.light,
+            environment: self.environment.clone(),
+            fog: self.fog,
+        }
+    }
+}
+
+/// The Surfaces and Light the renderer traces against for one frame
+pub struct Scene {
+    pub surfaces: Vec<Box<Surface>>,
+    /// Index of the Object and of the Material in the SceneDesc each Surface
+    /// was built from, for the ID passes
+    pub sources: Vec<(usize, usize)>,
+    /// Index of each emissive Surface and the sum of the power of it and the
+    /// emissive Surfaces before it
+    pub emitters: Vec<(usize, Float)>,
+    pub light: Light,
+    pub environment: Option<Arc<Environment>>,
+    pub fog: Option<Fog>,
+}
+
+impl Scene {
+    /// Color seen by a Ray travelling in direction 'dir' that misses every Surface
+    pub fn background(&self, dir: Vec3<Float>) -> Vec3<Float> {
+        match self.environment {
+            Some(ref env) => env.radiance(dir),
+            None => BKG_COLOR,
+        }
+    }
+
+    /// Picks an emissive Surface in proportion to its power from a uniform sample
+    /// 'u' in [0, 1). Returns the Surface and the probability of picking it.
+    pub fn pick_emitter(&self, u: Float) -> Option<(&Surface, Float)> {
+        let total = match self.emitters.last() {
+            Some(&(_, total)) => total,
+            None => return None,
+        };
+        let target = u * total;
+        let picked = self
+            .emitters
+            .iter()
+            .position(|e| e.1 > target)
+            .unwrap_or(self.emitters.len() - 1);
+        let before = if picked > 0 {
+            self.emitters[picked - 1].1
+        } else {
+            0.0
+        };
+        let (index, upto) = self.emitters[picked];
+        Some((&*self.surfaces[index], (upto - before) / total))
+    }
+
+    /// Probability of pick_emitter picking the Surface with the given index
+    pub fn emitter_probability(&self, index: usize) -> Float {
+        let total = match self.emitters.last() {
+            Some(&(_, total)) => total,
+            None => return 0.0,
+        };
+        let mut before = 0.0;
+        for &(i, upto) in self.emitters.iter() {
+            if i == index {
+                return (upto - before) / total;
+            }
+            before = upto;
+        }
+        0.0
+    }
+
+    /// 'color' of something hit at 'distance' along 'ray' as seen through the fog
+    pub fn fogged(&self, color: Vec3<Float>, ray: &Ray, distance: Float) -> Vec3<Float> {
+        match self.fog {
+            Some(ref fog) => fog.apply(color, ray, distance),
+            None => color,
+        }
+    }
+}
@@ -0,0 +1,810 @@
+//! Saving and loading of whole scenes: the Objects with their Primitives, the
+//! Materials, the camera, the light, the environment, the fog, the render
+//! settings and the animation Timeline, if there is one. Scenes are written as
+//! JSON or RON, chosen by the file extension. Every file records the VERSION of
+//! the schema it was written with, files from a newer version are rejected
+//! instead of being misread.
+//!
+//! Materials are referred to by name, so scenes can be written by hand or by
+//! other tools. Vectors are written as [x, y, z], the angles of the projection
+//! in radians and rotations in degrees. Instead of a look point the camera may
+//! be given an orientation, as a quaternion or Euler angles. Meshes are
+//! stored with all of their vertices; the texture maps of imported meshes can't
+//! be stored and are left out.
+//!
+//! ```json
+//! {
+//!     "version": 1,
+//!     "camera": { "eye": [0, 1, -5], "look": [0, 1, 0],
+//!                 "projection": { "orthographic": { "width": 4.0 } } },
+//!     "light": { "position": [-10, 15, -35], "color": [1, 1, 1] },
+//!     "materials": [ { "name": "red", "color": [0.85, 0.1, 0.1] } ],
+//!     "objects": [ { "name": "ball", "pivot": [0, 0, 0], "primitives": [
+//!         { "sphere": { "center": [0, 1, 0], "radius": 1, "material": "red" } } ] } ],
+//!     "settings": { "mode": "shaded", "samples": 1 }
+//! }
+//! ```
+
+use na::Vec3;
+use ron;
+use serde_json;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use animation::Timeline;
+use brdf::Pbr;
+use camera::{Orientation, Projection, View};
+use color::{ColorPipeline, ToneMap};
+use environment::{Background, Environment, EnvironmentMap};
+use medium::{Fog, Medium};
+use precision::Float;
+use scene::{Light, Maps, Mesh, Object, Primitive, SceneDesc, Transform};
+use sky::{self, Sky};
+use spectrum::{Film, Optics};
+use {Heuristic, Material, RenderMode, RenderSettings, AO_DISTANCE, AO_SAMPLES, PIXEL_SAMPLES, UP};
+
+/// Version of the schema written by this renderer. Increase it whenever the
+/// meaning of existing files changes.
+pub const VERSION: u32 = 1;
+
+fn to_array(v: Vec3<Float>) -> [Float; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn to_vec3(v: [Float; 3]) -> Vec3<Float> {
+    Vec3::new(v[0], v[1], v[2])
+}
+
+/// Description of the environment that can be turned back into it, unlike the
+/// Environment itself which only holds the result
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnvironmentDesc {
+    pub background: BackgroundDesc,
+    /// Rotation about the UP axis in degrees
+    #[serde(default)]
+    pub rotation: Float,
+    #[serde(default = "one")]
+    pub intensity: Float,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackgroundDesc {
+    Color([Float; 3]),
+    Gradient {
+        bottom: [Float; 3],
+        top: [Float; 3],
+    },
+    /// Path of an equirectangular image
+    Image(String),
+    /// Daylight sky with the sun placed by its angles in degrees
+    Sky {
+        turbidity: Float,
+        sun_elevation: Float,
+        sun_azimuth: Float,
+    },
+}
+
+impl EnvironmentDesc {
+    /// Builds the Environment, loading its image if it has one
+    pub fn build(&self) -> Result<Environment, String> {
+        let background = match self.background {
+            BackgroundDesc::Color(c) => Background::Solid(to_vec3(c)),
+            BackgroundDesc::Gradient { bottom, top } => Background::Gradient {
+                bottom: to_vec3(bottom),
+                top: to_vec3(top),
+            },
+            BackgroundDesc::Image(ref path) => Background::Image(EnvironmentMap::load(path)?),
+            BackgroundDesc::Sky {
+                turbidity,
+                sun_elevation,
+                sun_azimuth,
+            } => Background::Sky(Sky::new(
+                sky::sun_direction(sun_elevation, sun_azimuth),
+                turbidity,
+            )),
+        };
+        Ok(Environment::new(
+            background,
+            self.rotation.to_radians(),
+            self.intensity,
+        ))
+    }
+}
+
+fn one() -> Float {
+    1.0
+}
+
+fn up() -> [Float; 3] {
+    to_array(UP)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraData {
+    pub eye: [Float; 3],
+    #[serde(default)]
+    pub look: [Float; 3],
+    #[serde(default = "up")]
+    pub up: [Float; 3],
+    /// Rotation about the viewing direction in degrees
+    #[serde(default)]
+    pub roll: Float,
+    /// Turns the camera from looking along -Z with the Y axis up, in place of
+    /// 'look' and 'up'
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<Orientation>,
+    pub projection: Projection,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LightData {
+    pub position: [Float; 3],
+    pub color: [Float; 3],
+    /// Direction towards a directional light, None for a point light
+    #[serde(default)]
+    pub direction: Option<[Float; 3]>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MediumData {
+    pub absorption: [Float; 3],
+    pub scattering: [Float; 3],
+    #[serde(default)]
+    pub g: Float,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MaterialData {
+    pub name: String,
+    pub color: [Float; 3],
+    #[serde(default)]
+    pub reflect: Float,
+    #[serde(default)]
+    pub roughness: Float,
+    #[serde(default)]
+    pub emission: [Float; 3],
+    #[serde(default)]
+    pub medium: Option<MediumData>,
+    #[serde(default)]
+    pub optics: Option<Optics>,
+    #[serde(default)]
+    pub pbr: Option<Pbr>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrimitiveData {
+    Sphere {
+        center: [Float; 3],
+        radius: Float,
+        material: String,
+    },
+    Triangle {
+        a: [Float; 3],
+        b: [Float; 3],
+        c: [Float; 3],
+        material: String,
+        /// Checkerboard pattern of the floor
+        #[serde(default)]
+        pattern: bool,
+    },
+    Mesh {
+        material: String,
+        positions: Vec<[Float; 3]>,
+        triangles: Vec<[usize; 3]>,
+        #[serde(default)]
+        normals: Option<Vec<[Float; 3]>>,
+        #[serde(default)]
+        uvs: Option<Vec<[Float; 2]>>,
+        #[serde(default)]
+        tangents: Option<Vec<[Float; 4]>>,
+        #[serde(default)]
+        colors: Option<Vec<[Float; 3]>>,
+        #[serde(default)]
+        color_is_albedo: bool,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransformData {
+    pub translation: [Float; 3],
+    /// Euler angles in degrees
+    pub rotation: [Float; 3],
+    pub scale: Float,
+}
+
+impl Default for TransformData {
+    fn default() -> TransformData {
+        TransformData {
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ObjectData {
+    pub name: String,
+    #[serde(default)]
+    pub pivot: [Float; 3],
+    #[serde(default)]
+    pub transform: TransformData,
+    pub primitives: Vec<PrimitiveData>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FogData {
+    pub color: [Float; 3],
+    pub density: Float,
+    #[serde(default)]
+    pub falloff: Float,
+    #[serde(default)]
+    pub base: Float,
+}
+
+impl CameraData {
+    pub fn view(&self) -> View {
+        let eye = to_vec3(self.eye);
+        let mut view = match self.orientation {
+            Some(ref orientation) => View::oriented(eye, orientation),
+            None => View {
+                eye: eye,
+                look: to_vec3(self.look),
+                up: to_vec3(self.up),
+                roll: 0.0,
+            },
+        };
+        view.roll = self.roll;
+        view
+    }
+}
+
+/// RenderSettings and the ColorPipeline, except for the projection which is
+/// stored with the camera
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub mode: RenderMode,
+    pub samples: i32,
+    pub ao_samples: i32,
+    pub ao_distance: Float,
+    pub ao_multiply: bool,
+    pub aovs: bool,
+    pub shutter_open: Float,
+    pub shutter_close: Float,
+    /// Trace each path at a single wavelength
+    pub spectral: bool,
+    pub mis: Option<Heuristic>,
+    pub exposure: Float,
+    pub tone_map: ToneMap,
+    pub srgb: bool,
+    pub dither: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            mode: RenderMode::Shaded,
+            samples: PIXEL_SAMPLES,
+            ao_samples: AO_SAMPLES,
+            ao_distance: AO_DISTANCE,
+            ao_multiply: false,
+            aovs: false,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            spectral: false,
+            mis: Some(Heuristic::Power),
+            exposure: 0.0,
+            tone_map: ToneMap::Clamp,
+            srgb: true,
+            dither: false,
+        }
+    }
+}
+
+/// Everything needed to render a frame, or the whole animation, again
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SceneFile {
+    pub version: u32,
+    pub camera: CameraData,
+    pub light: LightData,
+    pub materials: Vec<MaterialData>,
+    pub objects: Vec<ObjectData>,
+    #[serde(default)]
+    pub environment: Option<EnvironmentDesc>,
+    #[serde(default)]
+    pub fog: Option<FogData>,
+    #[serde(default)]
+    pub settings: Settings,
+    /// Keyframes of the whole animation, the rest of the file is the scene at
+    /// its first frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation: Option<Timeline>,
+}
+
+/// Only the version, read before the rest so files of a newer schema get a
+/// clear error. Versions start at 1, 0 means the file has none.
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default)]
+    version: u32,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Format {
+    Json,
+    Ron,
+}
+
+impl Format {
+    /// Format of the file at 'path', if it is a saved scene
+    fn of(path: &str) -> Option<Format> {
+        let extension = Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("json") => Some(Format::Json),
+            Some("ron") => Some(Format::Ron),
+            _ => None,
+        }
+    }
+}
+
+/// Whether 'path' names a JSON or RON scene
+pub fn is_scene_file(path: &str) -> bool {
+    Format::of(path).is_some()
+}
+
+impl SceneFile {
+    /// Describes 'desc' as it is rendered with 'settings' and 'color'. Returns
+    /// the description and what of the scene it leaves out.
+    pub fn new(
+        desc: &SceneDesc,
+        environment: Option<&EnvironmentDesc>,
+        settings: &RenderSettings,
+        color: &ColorPipeline,
+        timeline: Option<&Timeline>,
+    ) -> (SceneFile, Vec<String>) {
+        let mut omitted = Vec::new();
+        let material_name = |index: usize| desc.materials[index].0.clone();
+        let materials = desc
+            .materials
+            .iter()
+            .map(|(name, m)| MaterialData {
+                name: name.clone(),
+                color: to_array(m.amb),
+                reflect: m.reflect,
+                roughness: m.roughness,
+                emission: to_array(m.emission),
+                medium: m.medium.map(|medium| MediumData {
+                    absorption: to_array(medium.absorption),
+                    scattering: to_array(medium.scattering),
+                    g: medium.g,
+                }),
+                optics: m.optics,
+                pbr: m.pbr,
+            })
+            .collect();
+        let mut objects = Vec::new();
+        for object in desc.objects.iter() {
+            let mut primitives = Vec::new();
+            for primitive in object.primitives.iter() {
+                primitives.push(match *primitive {
+                    Primitive::Sphere {
+                        center,
+                        radius,
+                        material,
+                    } => PrimitiveData::Sphere {
+                        center: to_array(center),
+                        radius: radius,
+                        material: material_name(material),
+                    },
+                    Primitive::Triangle {
+                        a,
+                        b,
+                        c,
+                        material,
+                        pattern,
+                    } => PrimitiveData::Triangle {
+                        a: to_array(a),
+                        b: to_array(b),
+                        c: to_array(c),
+                        material: material_name(material),
+                        pattern: pattern,
+                    },
+                    Primitive::Mesh { ref mesh, material } => {
+                        let maps = &mesh.maps;
+                        if maps.base_color.is_some()
+                            || maps.metallic_roughness.is_some()
+                            || maps.normal.is_some()
+                            || maps.emissive.is_some()
+                        {
+                            omitted.push(format!("the texture maps of '{}'", object.name));
+                        }
+                        let arrays =
+                            |v: &Vec<Vec3<Float>>| v.iter().map(|p| to_array(*p)).collect();
+                        PrimitiveData::Mesh {
+                            material: material_name(material),
+                            positions: arrays(&mesh.positions),
+                            triangles: mesh.triangles.clone(),
+                            normals: mesh.normals.as_ref().map(arrays),
+                            uvs: mesh.uvs.clone(),
+                            tangents: mesh.tangents.clone(),
+                            colors: mesh.colors.as_ref().map(arrays),
+                            color_is_albedo: mesh.color_is_albedo,
+                        }
+                    }
+                });
+            }
+            objects.push(ObjectData {
+                name: object.name.clone(),
+                pivot: to_array(object.pivot),
+                transform: TransformData {
+                    translation: to_array(object.transform.translation),
+                    rotation: to_array(object.transform.rotation),
+                    scale: object.transform.scale,
+                },
+                primitives: primitives,
+            });
+        }
+        let file = SceneFile {
+            version: VERSION,
+            camera: CameraData {
+                eye: to_array(desc.view.eye),
+                look: to_array(desc.view.look),
+                up: to_array(desc.view.up),
+                roll: desc.view.roll,
+                orientation: None,
+                projection: settings.projection,
+            },
+            light: LightData {
+                position: to_array(desc.light.position),
+                color: to_array(desc.light.color),
+                direction: desc.light.direction.map(to_array),
+            },
+            materials: materials,
+            objects: objects,
+            environment: environment.cloned(),
+            fog: desc.fog.map(|fog| FogData {
+                color: to_array(fog.color),
+                density: fog.density,
+                falloff: fog.falloff,
+                base: fog.base,
+            }),
+            settings: Settings {
+                mode: settings.mode,
+                samples: settings.samples,
+                ao_samples: settings.ao_samples,
+                ao_distance: settings.ao_distance,
+                ao_multiply: settings.ao_multiply,
+                aovs: settings.aovs,
+                shutter_open: settings.shutter_open,
+                shutter_close: settings.shutter_close,
+                spectral: settings.film.is_some(),
+                mis: settings.mis,
+                exposure: color.exposure,
+                tone_map: color.tone_map,
+                srgb: color.srgb,
+                dither: color.dither,
+            },
+            animation: timeline.cloned(),
+        };
+        (file, omitted)
+    }
+
+    /// Reads a scene from a JSON or RON file
+    pub fn load(path: &str) -> Result<SceneFile, String> {
+        let format = Format::of(path)
+            .ok_or_else(|| format!("'{}' is not a scene, expected a .json or .ron file", path))?;
+        let text = fs::read_to_string(path).map_err(|e| format!("Can't read '{}': {}", path, e))?;
+        let error = |e: &::std::fmt::Display| format!("Can't parse '{}': {}", path, e);
+        let header: Header = match format {
+            Format::Json => serde_json::from_str(&text).map_err(|e| error(&e))?,
+            Format::Ron => ron::from_str(&text).map_err(|e| error(&e))?,
+        };
+        match header.version {
+            0 => return Err(format!("'{}' has no schema version", path)),
+            version if version > VERSION => {
+                return Err(format!(
+                    "'{}' was written with version {} of the scene schema, this renderer reads up to version {}",
+                    path, version, VERSION
+                ))
+            }
+            _ => {}
+        }
+        let mut file: SceneFile = match format {
+            Format::Json => serde_json::from_str(&text).map_err(|e| error(&e))?,
+            Format::Ron => ron::from_str(&text).map_err(|e| error(&e))?,
+        };
+        if let Some(ref mut timeline) = file.animation {
+            timeline.prepare(path)?;
+        }
+        Ok(file)
+    }
+
+    /// Writes the scene as JSON or RON, depending on the extension of 'path'
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let format = Format::of(path).ok_or_else(|| {
+            format!(
+                "Can't save the scene as '{}', expected a .json or .ron file",
+                path
+            )
+        })?;
+        let text = match format {
+            Format::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string()),
+            Format::Ron => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+                .map_err(|e| e.to_string()),
+        }
+        .map_err(|e| format!("Can't save the scene as '{}': {}", path, e))?;
+        fs::write(path, text).map_err(|e| format!("Can't write '{}': {}", path, e))
+    }
+
+    /// The scene without its environment, which is built from 'environment'
+    pub fn scene(&self) -> Result<SceneDesc, String> {
+        let materials: Vec<(String, Material)> = self
+            .materials
+            .iter()
+            .map(|m| {
+                (
+                    m.name.clone(),
+                    Material {
+                        amb: to_vec3(m.color),
+                        reflect: m.reflect,
+                        roughness: m.roughness,
+                        emission: to_vec3(m.emission),
+                        medium: m.medium.as_ref().map(|medium| Medium {
+                            absorption: to_vec3(medium.absorption),
+                            scattering: to_vec3(medium.scattering),
+                            g: medium.g,
+                        }),
+                        optics: m.optics,
+                        pbr: m.pbr,
+                    },
+                )
+            })
+            .collect();
+        let material = |name: &str| {
+            materials
+                .iter()
+                .position(|m| m.0 == name)
+                .ok_or_else(|| format!("The scene has no material named '{}'", name))
+        };
+        let vectors = |v: &Vec<[Float; 3]>| v.iter().map(|p| to_vec3(*p)).collect();
+        let mut objects = Vec::new();
+        for object in self.objects.iter() {
+            let mut primitives = Vec::new();
+            for primitive in object.primitives.iter() {
+                primitives.push(match *primitive {
+                    PrimitiveData::Sphere {
+                        center,
+                        radius,
+                        material: ref name,
+                    } => Primitive::Sphere {
+                        center: to_vec3(center),
+                        radius: radius,
+                        material: material(name)?,
+                    },
+                    PrimitiveData::Triangle {
+                        a,
+                        b,
+                        c,
+                        material: ref name,
+                        pattern,
+                    } => Primitive::Triangle {
+                        a: to_vec3(a),
+                        b: to_vec3(b),
+                        c: to_vec3(c),
+                        material: material(name)?,
+                        pattern: pattern,
+                    },
+                    PrimitiveData::Mesh {
+                        material: ref name,
+                        ref positions,
+                        ref triangles,
+                        ref normals,
+                        ref uvs,
+                        ref tangents,
+                        ref colors,
+                        color_is_albedo,
+                    } => {
+                        let count = positions.len();
+                        let matches = |n: Option<usize>| n.is_none() || n == Some(count);
+                        // Faces with indices out of range are left to the validation
+                        if !matches(normals.as_ref().map(|v| v.len()))
+                            || !matches(uvs.as_ref().map(|v| v.len()))
+                            || !matches(tangents.as_ref().map(|v| v.len()))
+                            || !matches(colors.as_ref().map(|v| v.len()))
+                        {
+                            return Err(format!(
+                                "A mesh of '{}' has attributes that don't match its vertices",
+                                object.name
+                            ));
+                        }
+                        Primitive::Mesh {
+                            mesh: Arc::new(Mesh {
+                                positions: vectors(positions),
+                                normals: normals.as_ref().map(vectors),
+                                uvs: uvs.clone(),
+                                tangents: tangents.clone(),
+                                colors: colors.as_ref().map(vectors),
+                                color_is_albedo: color_is_albedo,
+                                triangles: triangles.clone(),
+                                maps: Maps::default(),
+                            }),
+                            material: material(name)?,
+                        }
+                    }
+                });
+            }
+            objects.push(Object {
+                name: object.name.clone(),
+                pivot: to_vec3(object.pivot),
+                transform: Transform {
+                    translation: to_vec3(object.transform.translation),
+                    rotation: to_vec3(object.transform.rotation),
+                    scale: object.transform.scale,
+                },
+                primitives: primitives,
+            });
+        }
+        Ok(SceneDesc {
+            view: self.camera.view(),
+            light: Light {
+                position: to_vec3(self.light.position),
+                color: to_vec3(self.light.color),
+                direction: self.light.direction.map(to_vec3),
+            },
+            materials: materials,
+            objects: objects,
+            environment: None,
+            fog: self.fog.as_ref().map(|fog| Fog {
+                color: to_vec3(fog.color),
+                density: fog.density,
+                falloff: fog.falloff,
+                base: fog.base,
+            }),
+        })
+    }
+
+    pub fn settings(&self) -> RenderSettings {
+        let s = &self.settings;
+        RenderSettings {
+            mode: s.mode,
+            ao_samples: s.ao_samples,
+            ao_distance: s.ao_distance,
+            ao_multiply: s.ao_multiply,
+            aovs: s.aovs,
+            projection: self.camera.projection,
+            samples: s.samples,
+            shutter_open: s.shutter_open,
+            shutter_close: s.shutter_close,
+            film: if s.spectral { Some(Film::new()) } else { None },
+            mis: s.mis,
+        }
+    }
+
+    pub fn color(&self) -> ColorPipeline {
+        let s = &self.settings;
+        ColorPipeline {
+            exposure: s.exposure,
+            tone_map: s.tone_map,
+            srgb: s.srgb,
+            dither: s.dither,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use default_scene;
+    use std::env;
+
+    /// The default scene with a mesh, a sky and an animation of the cube
+    fn sample() -> SceneFile {
+        let mut desc = default_scene();
+        desc.objects.push(Object {
+            name: "mesh".to_string(),
+            pivot: Vec3::new(0.0, 0.0, 0.0),
+            transform: Transform::identity(),
+            primitives: vec![Primitive::Mesh {
+                mesh: Arc::new(Mesh {
+                    positions: vec![
+                        Vec3::new(0.0, 0.0, 0.0),
+                        Vec3::new(1.0, 0.0, 0.0),
+                        Vec3::new(0.0, 1.0, 0.0),
+                    ],
+                    normals: None,
+                    uvs: Some(vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]),
+                    tangents: None,
+                    colors: None,
+                    color_is_albedo: false,
+                    triangles: vec![[0, 1, 2]],
+                    maps: Maps::default(),
+                }),
+                material: 0,
+            }],
+        });
+        let base: SceneFile = serde_json::from_str(
+            r#"{ "version": 1, "camera": { "eye": [0, 1, -5], "look": [0, 1, 0],
+                             "projection": { "orthographic": { "width": 4.0 } } },
+                 "light": { "position": [-10, 15, -35], "color": [1, 1, 1] },
+                 "materials": [], "objects": [],
+                 "settings": { "mode": "shaded", "samples": 4, "exposure": 0.5 } }"#,
+        )
+        .unwrap();
+        let mut timeline: Timeline = serde_json::from_str(
+            r#"{ "start": 0, "end": 9,
+                 "objects": { "cube": { "rotation": { "interpolation": "bezier", "keys": [
+                     { "frame": 9, "value": [0, 90, 0], "in_tangent": [0, 10, 0] },
+                     { "frame": 0, "value": [0, 0, 0] } ] } } } }"#,
+        )
+        .unwrap();
+        timeline.prepare("sample").unwrap();
+        let environment = EnvironmentDesc {
+            background: BackgroundDesc::Color([0.2, 0.3, 0.4]),
+            rotation: 30.0,
+            intensity: 2.0,
+        };
+        SceneFile::new(
+            &desc,
+            Some(&environment),
+            &base.settings(),
+            &base.color(),
+            Some(&timeline),
+        )
+        .0
+    }
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("scene_file_{}_{}", ::std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Saves the sample, loads it back and compares both as JSON
+    fn round_trip(name: &str) {
+        let file = sample();
+        let path = temp_path(name);
+        file.save(&path).unwrap();
+        let loaded = SceneFile::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&file).unwrap()
+        );
+        assert!(loaded.animation.is_some());
+        assert!(loaded.scene().is_ok());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip("round_trip.json");
+    }
+
+    #[test]
+    fn ron_round_trip() {
+        round_trip("round_trip.ron");
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let mut text = serde_json::to_value(sample()).unwrap();
+        text["version"] = (VERSION + 1).into();
+        let path = temp_path("newer.json");
+        fs::write(&path, text.to_string()).unwrap();
+        let loaded = SceneFile::load(&path);
+        fs::remove_file(&path).unwrap();
+        let error = loaded.unwrap_err();
+        assert!(
+            error.contains(&format!("version {}", VERSION + 1)),
+            "{}",
+            error
+        );
+    }
+}
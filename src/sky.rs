@@ -0,0 +1,252 @@
+//! Analytic daylight model of Preetham, Shirley and Smits ("A Practical Analytic
+//! Model for Daylight", 1999). The color of the sky depends on the position of
+//! the sun and the turbidity of the atmosphere, which ranges from 2 for a very
+//! clear sky to around 10 for a hazy one. The same atmosphere attenuates the
+//! sun itself, giving the color of the directional light that goes with the sky.
+//!
+//! Directions use Y as up, north is +Z and east is +X. Azimuths are measured
+//! from north towards east.
+
+use na;
+use na::{Norm, Vec3};
+
+use largest_of;
+use precision::{consts::PI, Float};
+use spectrum::xyz_to_rgb;
+
+/// Converts the sky luminance from kcd/m^2 to the range the renderer works in
+const SKY_SCALE: Float = 0.05;
+/// Brightness of the sun light before it is attenuated by the atmosphere
+const SUN_SCALE: Float = 1.0;
+
+pub struct Sky {
+    /// Unit vector pointing towards the sun
+    sun: Vec3<Float>,
+    turbidity: Float,
+    /// Luminance Y and chromaticities x and y at the zenith
+    zenith: [Float; 3],
+    /// Perez distribution coefficients A to E for Y, x and y
+    perez: [[Float; 5]; 3],
+}
+
+impl Sky {
+    pub fn new(sun: Vec3<Float>, turbidity: Float) -> Sky {
+        let t = turbidity;
+        let theta_s = largest_of(sun.y).min(1.0).acos();
+        let (t2, s, s2, s3) = (
+            t * t,
+            theta_s,
+            theta_s * theta_s,
+            theta_s * theta_s * theta_s,
+        );
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = largest_of((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192);
+        let zenith_x = t2 * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s + 0.25886);
+        let zenith_yc = t2 * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s + 0.26688);
+
+        Sky {
+            sun: sun,
+            turbidity: turbidity,
+            zenith: [zenith_y, zenith_x, zenith_yc],
+            perez: [
+                [
+                    0.1787 * t - 1.4630,
+                    -0.3554 * t + 0.4275,
+                    -0.0227 * t + 5.3251,
+                    0.1206 * t - 2.5771,
+                    -0.0670 * t + 0.3703,
+                ],
+                [
+                    -0.0193 * t - 0.2592,
+                    -0.0665 * t + 0.0008,
+                    -0.0004 * t + 0.2125,
+                    -0.0641 * t - 0.8989,
+                    -0.0033 * t + 0.0452,
+                ],
+                [
+                    -0.0167 * t - 0.2608,
+                    -0.0950 * t + 0.0092,
+                    -0.0079 * t + 0.2102,
+                    -0.0441 * t - 1.6537,
+                    -0.0109 * t + 0.0529,
+                ],
+            ],
+        }
+    }
+
+    /// Unit vector pointing towards the sun
+    pub fn sun_direction(&self) -> Vec3<Float> {
+        self.sun
+    }
+
+    /// Radiance of the sky arriving from 'dir'. The model is only defined above
+    /// the horizon, below it the sky is continued with the color at the horizon.
+    pub fn radiance(&self, dir: Vec3<Float>) -> Vec3<Float> {
+        if self.sun.y <= 0.0 {
+            // Night, the model doesn't cover twilight
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let dir = Vec3::new(dir.x, largest_of(dir.y).max(0.001), dir.z).normalize();
+        let theta = dir.y.acos();
+        let gamma = na::dot(&dir, &self.sun).max(-1.0).min(1.0).acos();
+        let theta_s = self.sun.y.min(1.0).acos();
+        let mut values = [0.0; 3];
+        for i in 0..3 {
+            let p = &self.perez[i];
+            values[i] = self.zenith[i] * perez(p, theta, gamma) / perez(p, 0.0, theta_s);
+        }
+        let (lum, x, y) = (values[0] * SKY_SCALE, values[1], values[2]);
+        if y <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let rgb = xyz_to_rgb(Vec3::new(x / y * lum, lum, (1.0 - x - y) / y * lum));
+        Vec3::new(largest_of(rgb.x), largest_of(rgb.y), largest_of(rgb.z))
+    }
+
+    /// Color of the sunlight after it has passed through the atmosphere. Blue
+    /// light is scattered away by air (Rayleigh) and haze (aerosols), which makes
+    /// the sun more orange the lower it is and the hazier the sky.
+    pub fn sun_color(&self) -> Vec3<Float> {
+        if self.sun.y <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let theta_deg = self.sun.y.min(1.0).acos().to_degrees();
+        // Relative optical mass of the atmosphere along the path to the sun
+        let mass = 1.0 / (self.sun.y + 0.15 * (93.885 - theta_deg).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let attenuate = |lambda: Float| {
+            let rayleigh = 0.008735 * lambda.powf(-4.08);
+            let aerosol = beta * lambda.powf(-1.3);
+            (-(rayleigh + aerosol) * mass).exp()
+        };
+        // Wavelengths in micrometers for red, green and blue
+        Vec3::new(attenuate(0.65), attenuate(0.55), attenuate(0.45)) * SUN_SCALE
+    }
+}
+
+/// Perez et al.'s sky luminance distribution
+fn perez(p: &[Float; 5], theta: Float, gamma: Float) -> Float {
+    (1.0 + p[0] * (p[1] / theta.cos()).exp())
+        * (1.0 + p[2] * (p[3] * gamma).exp() + p[4] * gamma.cos() * gamma.cos())
+}
+
+/// Unit vector towards the sun at the given elevation above the horizon and
+/// azimuth from north, both in degrees
+pub fn sun_direction(elevation: Float, azimuth: Float) -> Vec3<Float> {
+    let (el, az) = (elevation.to_radians(), azimuth.to_radians());
+    Vec3::new(el.cos() * az.sin(), el.sin(), el.cos() * az.cos())
+}
+
+/// Elevation and azimuth of the sun in degrees, seen from 'latitude' (degrees,
+/// north positive) on day 'day' of the year at 'hour' in local solar time, where
+/// the sun is highest at 12.
+pub fn sun_position(latitude: Float, day: u32, hour: Float) -> (Float, Float) {
+    let lat = latitude.to_radians();
+    let declination =
+        (-23.44 as Float).to_radians() * (2.0 * PI / 365.0 * (day as Float + 10.0)).cos();
+    let hour_angle = (15.0 * (hour - 12.0)).to_radians();
+    let sin_el = lat.sin() * declination.sin() + lat.cos() * declination.cos() * hour_angle.cos();
+    let elevation = sin_el.max(-1.0).min(1.0).asin();
+    let azimuth = (-declination.cos() * hour_angle.sin())
+        .atan2(declination.sin() * lat.cos() - declination.cos() * hour_angle.cos() * lat.sin());
+    let azimuth = (azimuth.to_degrees() + 360.0) % 360.0;
+    (elevation.to_degrees(), azimuth)
+}
+
+/// Day of the year, starting at 1, of a date written as YYYY-MM-DD
+pub fn day_of_year(date: &str) -> Result<u32, String> {
+    let error = || format!("Invalid date '{}', expected YYYY-MM-DD", date);
+    let parts: Vec<u32> = date
+        .split('-')
+        .map(|p| p.parse::<u32>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| error())?;
+    if parts.len() != 3 {
+        return Err(error());
+    }
+    let (year, month, day) = (parts[0], parts[1], parts[2]);
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let mut days = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if leap {
+        days[1] = 29;
+    }
+    if month < 1 || month > 12 || day < 1 || day > days[month as usize - 1] {
+        return Err(error());
+    }
+    Ok(days[..month as usize - 1].iter().sum::<u32>() + day)
+}
+
+/// Hours since midnight of a time written as HH:MM
+pub fn parse_time(time: &str) -> Result<Float, String> {
+    let error = || format!("Invalid time '{}', expected HH:MM", time);
+    let parts: Vec<&str> = time.split(':').collect();
+    if parts.len() != 2 {
+        return Err(error());
+    }
+    let hours = parts[0].parse::<u32>().map_err(|_| error())?;
+    let minutes = parts[1].parse::<u32>().map_err(|_| error())?;
+    if hours > 23 || minutes > 59 {
+        return Err(error());
+    }
+    Ok(hours as Float + minutes as Float / 60.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_of_year_counts_leap_days() {
+        assert_eq!(day_of_year("2023-01-01"), Ok(1));
+        assert_eq!(day_of_year("2023-03-01"), Ok(60));
+        assert_eq!(day_of_year("2024-03-01"), Ok(61));
+        assert_eq!(day_of_year("2000-12-31"), Ok(366));
+        assert_eq!(day_of_year("1900-12-31"), Ok(365));
+        assert!(day_of_year("2023-02-29").is_err());
+        assert!(day_of_year("2023-13-01").is_err());
+        assert!(day_of_year("2023-00-10").is_err());
+        assert!(day_of_year("2023-06").is_err());
+        assert!(day_of_year("june").is_err());
+    }
+
+    #[test]
+    fn parse_time_gives_hours() {
+        assert_eq!(parse_time("12:30"), Ok(12.5));
+        assert_eq!(parse_time("00:00"), Ok(0.0));
+        assert!(parse_time("24:00").is_err());
+        assert!(parse_time("12:60").is_err());
+        assert!(parse_time("12").is_err());
+        assert!(parse_time("-1:00").is_err());
+    }
+
+    #[test]
+    fn sun_is_south_at_noon_in_the_north() {
+        // Winter solstice, the sun is 90 - 45 - 23.44 degrees high
+        let (elevation, azimuth) = sun_position(45.0, 355, 12.0);
+        assert!((elevation - 21.56).abs() < 0.1, "{}", elevation);
+        assert!((azimuth - 180.0).abs() < 0.1, "{}", azimuth);
+        // Near the equinox it is straight up at the equator
+        let (elevation, _) = sun_position(0.0, 81, 12.0);
+        assert!(elevation > 89.5, "{}", elevation);
+    }
+
+    #[test]
+    fn sun_rises_in_the_east() {
+        let (morning, east) = sun_position(45.0, 172, 8.0);
+        let (afternoon, west) = sun_position(45.0, 172, 16.0);
+        assert!(east > 45.0 && east < 135.0, "{}", east);
+        assert!((morning - afternoon).abs() < 1e-3);
+        assert!((east + west - 360.0).abs() < 1e-3);
+        // The direction of the sun matches its angles
+        let dir = sun_direction(morning, east);
+        assert!(dir.x > 0.0 && dir.y > 0.0);
+        assert!((na::norm(&dir) - 1.0).abs() < 1e-5);
+        // Below the horizon at midnight
+        assert!(sun_position(45.0, 172, 0.0).0 < 0.0);
+    }
+}
@@ -0,0 +1,360 @@
+//! Wavelength dependent optics. In the spectral mode every path is traced at a
+//! single wavelength picked at random, so glass refracts each wavelength by a
+//! different amount and metals reflect by their measured complex index of
+//! refraction. The film turns the result back into RGB with the CIE 1931
+//! color matching functions. Colors given as RGB, like the albedo of ordinary
+//! Materials and the lights, are turned into a spectrum at the film in such a
+//! way that a path without wavelength dependent events averages out to exactly
+//! the color it would have without the spectral mode.
+//!
+//! Outside of the spectral mode the wavelength dependent Materials are evaluated
+//! at one wavelength per RGB channel.
+
+use na;
+use na::Vec3;
+
+use precision::Float;
+
+/// Range of wavelengths in nm that paths are traced at
+const LAMBDA_MIN: Float = 380.0;
+const LAMBDA_MAX: Float = 780.0;
+
+/// Wavelengths in nm that stand in for the red, green and blue channels
+const RGB_WAVELENGTHS: [Float; 3] = [610.0, 550.0, 465.0];
+
+/// Wavelengths in nm that tabulated spectra are given at
+pub const TABLE_WAVELENGTHS: [Float; 8] = [400.0, 450.0, 500.0, 550.0, 600.0, 650.0, 700.0, 750.0];
+
+/// Index of refraction of a transparent Material, with the wavelength in µm
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ior {
+    /// n = a + b / λ^2
+    Cauchy { a: Float, b: Float },
+    /// n^2 = 1 + sum of b λ^2 / (λ^2 - c)
+    Sellmeier { b: [Float; 3], c: [Float; 3] },
+}
+
+impl Ior {
+    /// Index of refraction at 'wavelength' in nm. Without a wavelength the
+    /// index for green light is used, so there is no dispersion.
+    pub fn at(&self, wavelength: Option<Float>) -> Float {
+        let wavelength = wavelength.unwrap_or(RGB_WAVELENGTHS[1]);
+        let l2 = (wavelength / 1000.0) * (wavelength / 1000.0);
+        match *self {
+            Ior::Cauchy { a, b } => a + b / l2,
+            Ior::Sellmeier { b, c } => {
+                let sum: Float = (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+/// How a Material interacts with light of different wavelengths. Only the path
+/// traced mode uses this, the other modes use the RGB color of the Material.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Optics {
+    /// Glass and other transparent Materials that reflect and refract
+    Dielectric(Ior),
+    /// Metal with the complex index of refraction n + ik at TABLE_WAVELENGTHS
+    Conductor { n: [Float; 8], k: [Float; 8] },
+    /// Diffuse reflectance at TABLE_WAVELENGTHS
+    Reflectance([Float; 8]),
+}
+
+/// Schott N-BK7, the most common optical glass
+pub const BK7: Optics = Optics::Dielectric(Ior::Sellmeier {
+    b: [1.039_612, 0.231_792_34, 1.010_469_4],
+    c: [0.006_000_699, 0.020_017_914, 103.560_65],
+});
+/// Schott SF11, a dense flint glass with strong dispersion
+pub const SF11: Optics = Optics::Dielectric(Ior::Sellmeier {
+    b: [1.737_597, 0.313_747_35, 1.898_781],
+    c: [0.013_188_707, 0.062_306_814, 155.236_3],
+});
+pub const FUSED_SILICA: Optics = Optics::Dielectric(Ior::Sellmeier {
+    b: [0.696_166_3, 0.407_942_6, 0.897_479_4],
+    c: [0.004_679_148, 0.013_512_06, 97.934],
+});
+/// Brass (copper with 30% zinc), approximated from measured data
+pub const BRASS: Optics = Optics::Conductor {
+    n: [1.44, 1.30, 0.98, 0.56, 0.38, 0.35, 0.36, 0.38],
+    k: [1.88, 2.10, 2.30, 2.85, 3.30, 3.70, 4.05, 4.40],
+};
+pub const COPPER: Optics = Optics::Conductor {
+    n: [1.18, 1.17, 1.12, 1.04, 0.30, 0.21, 0.21, 0.24],
+    k: [2.21, 2.40, 2.56, 2.58, 3.00, 3.67, 4.20, 4.65],
+};
+pub const GOLD: Optics = Optics::Conductor {
+    n: [1.66, 1.50, 0.97, 0.43, 0.25, 0.17, 0.16, 0.17],
+    k: [1.96, 1.88, 1.87, 2.45, 2.99, 3.53, 3.95, 4.40],
+};
+pub const SILVER: Optics = Optics::Conductor {
+    n: [0.05, 0.05, 0.05, 0.06, 0.06, 0.05, 0.04, 0.03],
+    k: [2.10, 2.65, 3.10, 3.45, 3.80, 4.30, 4.80, 5.25],
+};
+pub const ALUMINIUM: Optics = Optics::Conductor {
+    n: [0.49, 0.62, 0.77, 0.96, 1.20, 1.49, 1.83, 2.40],
+    k: [4.86, 5.47, 6.08, 6.69, 7.26, 7.79, 8.31, 8.62],
+};
+
+impl Optics {
+    /// Parses a preset name, 'cauchy:A,B', 'sellmeier:B1,B2,B3,C1,C2,C3',
+    /// 'conductor:N1,..,N8:K1,..,K8' or 'reflectance:R1,..,R8'. Tables have a
+    /// value for each of TABLE_WAVELENGTHS.
+    pub fn parse(spec: &str) -> Result<Optics, String> {
+        let error = || format!("Invalid optics '{}'", spec);
+        let values = |text: &str, count: usize| -> Result<Vec<Float>, String> {
+            let values: Vec<Float> = text
+                .split(',')
+                .map(|v| v.trim().parse::<Float>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| error())?;
+            if values.len() == count && values.iter().all(|v| v.is_finite()) {
+                Ok(values)
+            } else {
+                Err(format!("'{}' needs {} values", spec, count))
+            }
+        };
+        let table = |v: Vec<Float>| {
+            let mut t = [0.0; 8];
+            t.copy_from_slice(&v);
+            t
+        };
+        let parts: Vec<&str> = spec.split(':').collect();
+        match (parts[0], parts.len()) {
+            ("bk7", 1) => Ok(BK7),
+            ("sf11", 1) => Ok(SF11),
+            ("fused-silica", 1) => Ok(FUSED_SILICA),
+            ("brass", 1) => Ok(BRASS),
+            ("copper", 1) => Ok(COPPER),
+            ("gold", 1) => Ok(GOLD),
+            ("silver", 1) => Ok(SILVER),
+            ("aluminium", 1) => Ok(ALUMINIUM),
+            ("cauchy", 2) => {
+                let v = values(parts[1], 2)?;
+                Ok(Optics::Dielectric(Ior::Cauchy { a: v[0], b: v[1] }))
+            }
+            ("sellmeier", 2) => {
+                let v = values(parts[1], 6)?;
+                Ok(Optics::Dielectric(Ior::Sellmeier {
+                    b: [v[0], v[1], v[2]],
+                    c: [v[3], v[4], v[5]],
+                }))
+            }
+            ("conductor", 3) => Ok(Optics::Conductor {
+                n: table(values(parts[1], 8)?),
+                k: table(values(parts[2], 8)?),
+            }),
+            ("reflectance", 2) => Ok(Optics::Reflectance(table(values(parts[1], 8)?))),
+            _ => Err(error()),
+        }
+    }
+
+    /// Diffuse reflectance of a Reflectance spectrum, for each RGB channel or
+    /// the same value in every channel at 'wavelength'
+    pub fn albedo(table: &[Float; 8], wavelength: Option<Float>) -> Vec3<Float> {
+        at_wavelengths(wavelength, |w| interpolate(table, w))
+    }
+
+    /// Fraction of the light reflected by a Conductor when the light arrives at
+    /// an angle with cosine 'cos' to the normal
+    pub fn conductor_fresnel(
+        n: &[Float; 8],
+        k: &[Float; 8],
+        cos: Float,
+        wavelength: Option<Float>,
+    ) -> Vec3<Float> {
+        at_wavelengths(wavelength, |w| {
+            fresnel_conductor(cos, interpolate(n, w), interpolate(k, w))
+        })
+    }
+}
+
+/// Evaluates 'f' at 'wavelength' in every channel, or at RGB_WAVELENGTHS
+fn at_wavelengths<F: Fn(Float) -> Float>(wavelength: Option<Float>, f: F) -> Vec3<Float> {
+    match wavelength {
+        Some(w) => {
+            let v = f(w);
+            Vec3::new(v, v, v)
+        }
+        None => Vec3::new(
+            f(RGB_WAVELENGTHS[0]),
+            f(RGB_WAVELENGTHS[1]),
+            f(RGB_WAVELENGTHS[2]),
+        ),
+    }
+}
+
+/// Linear interpolation in a table given at TABLE_WAVELENGTHS, clamped at the ends
+fn interpolate(table: &[Float; 8], wavelength: Float) -> Float {
+    let step = TABLE_WAVELENGTHS[1] - TABLE_WAVELENGTHS[0];
+    let x = ((wavelength - TABLE_WAVELENGTHS[0]) / step)
+        .max(0.0)
+        .min(7.0);
+    let i = (x as usize).min(6);
+    let t = x - i as Float;
+    table[i] * (1.0 - t) + table[i + 1] * t
+}
+
+/// Unpolarized Fresnel reflectance of a conductor with the complex index of
+/// refraction n + ik, seen from air
+fn fresnel_conductor(cos: Float, n: Float, k: Float) -> Float {
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+    let t0 = n * n - k * k - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * n * n * k * k).sqrt();
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let t1 = a2b2 + cos2;
+    let t2 = 2.0 * a * cos;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+/// Unpolarized Fresnel reflectance at the boundary between two dielectrics,
+/// with 'eta' the ratio of the index of refraction on the far side to the one
+/// on the side the light arrives from. Returns 1 for total internal reflection.
+pub fn fresnel_dielectric(cos: Float, eta: Float) -> Float {
+    let sin2_t = (1.0 - cos * cos) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (cos - eta * cos_t) / (cos + eta * cos_t);
+    let rp = (eta * cos - cos_t) / (eta * cos + cos_t);
+    0.5 * (rs * rs + rp * rp)
+}
+
+/// Converts the RGB colors carried by the paths of the spectral mode into
+/// the RGB seen on the film
+#[derive(Copy, Clone)]
+pub struct Film {
+    /// Turns an RGB color into the weights of the three basis spectra
+    uplift: [[Float; 3]; 3],
+    /// Integral of the CIE Y color matching function
+    y_integral: Float,
+}
+
+impl Film {
+    pub fn new() -> Film {
+        // Find the RGB the film records for each basis spectrum, the inverse of
+        // that mapping turns RGB into a spectrum the film records as that RGB
+        let mut y_integral = 0.0;
+        let mut recorded = [[0.0; 3]; 3];
+        let mut w = LAMBDA_MIN;
+        while w < LAMBDA_MAX {
+            let xyz = cie_xyz(w);
+            let rgb = xyz_to_rgb(xyz);
+            let b = basis(w);
+            for (row, c) in recorded.iter_mut().zip(&[rgb.x, rgb.y, rgb.z]) {
+                row[0] += c * b.x;
+                row[1] += c * b.y;
+                row[2] += c * b.z;
+            }
+            y_integral += xyz.y;
+            w += 1.0;
+        }
+        for row in recorded.iter_mut() {
+            for v in row.iter_mut() {
+                *v /= y_integral;
+            }
+        }
+        Film {
+            uplift: invert(&recorded),
+            y_integral: y_integral,
+        }
+    }
+
+    /// Wavelength in nm for a uniform sample 'u' in [0, 1)
+    pub fn sample_wavelength(&self, u: Float) -> Float {
+        LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * u
+    }
+
+    /// RGB recorded for 'color' arriving at 'wavelength', divided by the
+    /// probability density of the wavelength
+    pub fn record(&self, color: Vec3<Float>, wavelength: Float) -> Vec3<Float> {
+        let weights = multiply(&self.uplift, color);
+        let value = na::dot(&weights, &basis(wavelength));
+        let xyz = cie_xyz(wavelength) * (value * (LAMBDA_MAX - LAMBDA_MIN) / self.y_integral);
+        xyz_to_rgb(xyz)
+    }
+}
+
+/// Three smooth spectra for the red, green and blue ranges that add up to 1
+fn basis(wavelength: Float) -> Vec3<Float> {
+    let bump = |center: Float| {
+        let x = (wavelength - center) / 40.0;
+        (-0.5 * x * x).exp()
+    };
+    let b = Vec3::new(bump(620.0), bump(540.0), bump(450.0));
+    b / (b.x + b.y + b.z)
+}
+
+/// Multi-lobe fit of the CIE 1931 2° color matching functions by Wyman, Sloan
+/// and Shirley ("Simple Analytic Approximations to the CIE XYZ Color Matching
+/// Functions", 2013)
+fn cie_xyz(wavelength: Float) -> Vec3<Float> {
+    let g = |mu: Float, below: Float, above: Float| {
+        let sigma = if wavelength < mu { below } else { above };
+        let t = (wavelength - mu) / sigma;
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// CIE XYZ to linear sRGB (D65)
+pub fn xyz_to_rgb(c: Vec3<Float>) -> Vec3<Float> {
+    Vec3::new(
+        3.2406 * c.x - 1.5372 * c.y - 0.4986 * c.z,
+        -0.9689 * c.x + 1.8758 * c.y + 0.0415 * c.z,
+        0.0557 * c.x - 0.2040 * c.y + 1.0570 * c.z,
+    )
+}
+
+fn multiply(m: &[[Float; 3]; 3], v: Vec3<Float>) -> Vec3<Float> {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+}
+
+fn invert(m: &[[Float; 3]; 3]) -> [[Float; 3]; 3] {
+    let cofactor =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let c = [
+        [
+            cofactor(1, 2, 1, 2),
+            -cofactor(1, 2, 0, 2),
+            cofactor(1, 2, 0, 1),
+        ],
+        [
+            -cofactor(0, 2, 1, 2),
+            cofactor(0, 2, 0, 2),
+            -cofactor(0, 2, 0, 1),
+        ],
+        [
+            cofactor(0, 1, 1, 2),
+            -cofactor(0, 1, 0, 2),
+            cofactor(0, 1, 0, 1),
+        ],
+    ];
+    let det = m[0][0] * c[0][0] + m[0][1] * c[0][1] + m[0][2] * c[0][2];
+    let mut inverse = [[0.0; 3]; 3];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            // The inverse is the transposed cofactor matrix over the determinant
+            *v = c[j][i] / det;
+        }
+    }
+    inverse
+}
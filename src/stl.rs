@@ -0,0 +1,296 @@
+//! Loads triangle meshes from STL files, as exported by CAD programs, in both
+//! the ASCII and the binary variant. STL stores every triangle with its own
+//! copy of its corners, so vertices closer together than a tolerance are
+//! welded into one. Smooth vertex normals can then be computed by averaging
+//! the normals of the faces around each vertex, except across edges sharper
+//! than a crease angle which stay hard. The facet normals stored in the file
+//! are ignored, the counter clockwise winding of the corners decides the front.
+
+use na;
+use na::{Norm, Vec3};
+use std::collections::HashMap;
+use std::fs;
+use std::str;
+
+use precision::Float;
+use scene::{Maps, Mesh};
+
+/// Size of the binary header and of each triangle record
+const HEADER_SIZE: usize = 84;
+const RECORD_SIZE: usize = 50;
+
+/// Reads the mesh in the STL file at 'path'. Vertices closer than 'weld' are
+/// merged. With a 'crease' angle in radians the mesh gets smooth normals,
+/// edges whose faces meet at a larger angle stay sharp.
+pub fn load(path: &str, weld: Float, crease: Option<Float>) -> Result<Mesh, String> {
+    let data = fs::read(path).map_err(|e| format!("Can't read '{}': {}", path, e))?;
+    let corners = parse(&data).map_err(|e| format!("Can't parse '{}': {}", path, e))?;
+    if corners.is_empty() {
+        return Err(format!("'{}' has no triangles", path));
+    }
+    let (positions, triangles) = weld_vertices(&corners, weld);
+    if triangles.is_empty() {
+        return Err(format!("'{}' has no triangles left after welding", path));
+    }
+    Ok(match crease {
+        Some(angle) => smooth(&positions, &triangles, angle),
+        None => Mesh {
+            positions: positions,
+            normals: None,
+            uvs: None,
+            tangents: None,
+            colors: None,
+            color_is_albedo: false,
+            triangles: triangles,
+            maps: Maps::default(),
+        },
+    })
+}
+
+/// Corners of all of the triangles, three per triangle
+fn parse(data: &[u8]) -> Result<Vec<Vec3<Float>>, String> {
+    // Binary files may also start with 'solid', their size tells them apart
+    if data.len() >= HEADER_SIZE {
+        let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+        if data.len() == HEADER_SIZE + count * RECORD_SIZE {
+            return Ok(parse_binary(&data[HEADER_SIZE..], count));
+        }
+    }
+    if data.starts_with(b"solid") {
+        parse_ascii(str::from_utf8(data).map_err(|_| "The ASCII file is not valid text")?)
+    } else {
+        Err("The size doesn't match the triangle count of a binary file".to_string())
+    }
+}
+
+fn parse_binary(data: &[u8], count: usize) -> Vec<Vec3<Float>> {
+    let float = |b: &[u8]| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as Float;
+    let mut corners = Vec::with_capacity(count * 3);
+    for record in data.chunks(RECORD_SIZE).take(count) {
+        // The facet normal comes first and an attribute count last
+        for corner in 0..3 {
+            let b = &record[12 + corner * 12..];
+            corners.push(Vec3::new(float(b), float(&b[4..]), float(&b[8..])));
+        }
+    }
+    corners
+}
+
+fn parse_ascii(text: &str) -> Result<Vec<Vec3<Float>>, String> {
+    let mut corners = Vec::new();
+    let mut tokens = text.split_whitespace();
+    while let Some(token) = tokens.next() {
+        if token != "vertex" {
+            continue;
+        }
+        let mut coordinate = || -> Result<Float, String> {
+            let value = tokens.next().ok_or("The file ends inside a vertex")?;
+            value
+                .parse()
+                .map_err(|_| format!("Invalid coordinate '{}'", value))
+        };
+        corners.push(Vec3::new(coordinate()?, coordinate()?, coordinate()?));
+    }
+    if corners.len() % 3 != 0 {
+        return Err("A facet doesn't have three vertices".to_string());
+    }
+    Ok(corners)
+}
+
+/// Merges corners closer than 'tolerance' into shared vertices and drops the
+/// triangles that collapse. Returns the vertices and the triangles indexing them.
+fn weld_vertices(corners: &[Vec3<Float>], tolerance: Float) -> (Vec<Vec3<Float>>, Vec<[usize; 3]>) {
+    let mut positions: Vec<Vec3<Float>> = Vec::new();
+    // Vertices by the cell of a grid with the size of the tolerance they are in
+    let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    let cell = |p: Vec3<Float>| {
+        if tolerance > 0.0 {
+            [
+                (p.x / tolerance).floor() as i64,
+                (p.y / tolerance).floor() as i64,
+                (p.z / tolerance).floor() as i64,
+            ]
+        } else {
+            [
+                p.x.to_bits() as i64,
+                p.y.to_bits() as i64,
+                p.z.to_bits() as i64,
+            ]
+        }
+    };
+    let mut index = |p: Vec3<Float>| -> usize {
+        let c = cell(p);
+        // Only neighboring cells can hold vertices within the tolerance
+        let reach = if tolerance > 0.0 { 1 } else { 0 };
+        for dx in -reach..=reach {
+            for dy in -reach..=reach {
+                for dz in -reach..=reach {
+                    if let Some(candidates) = grid.get(&[c[0] + dx, c[1] + dy, c[2] + dz]) {
+                        for &i in candidates.iter() {
+                            if na::norm(&(positions[i] - p)) <= tolerance {
+                                return i;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        positions.push(p);
+        grid.entry(c).or_default().push(positions.len() - 1);
+        positions.len() - 1
+    };
+    let mut triangles = Vec::with_capacity(corners.len() / 3);
+    for t in corners.chunks(3) {
+        let tri = [index(t[0]), index(t[1]), index(t[2])];
+        if tri[0] != tri[1] && tri[1] != tri[2] && tri[0] != tri[2] {
+            triangles.push(tri);
+        }
+    }
+    (positions, triangles)
+}
+
+/// Mesh with area weighted vertex normals. A corner only averages the faces
+/// around its vertex whose normals are within 'crease' radians of its own
+/// face's, vertices on a crease are split so each side gets its own normal.
+fn smooth(positions: &[Vec3<Float>], triangles: &[[usize; 3]], crease: Float) -> Mesh {
+    // The length of the cross product is twice the area, which weights the normals
+    let weighted: Vec<Vec3<Float>> = triangles
+        .iter()
+        .map(|t| {
+            na::cross(
+                &(positions[t[1]] - positions[t[0]]),
+                &(positions[t[2]] - positions[t[0]]),
+            )
+        })
+        .collect();
+    let unit: Vec<Vec3<Float>> = weighted
+        .iter()
+        .map(|n| if na::norm(n) > 0.0 { n.normalize() } else { *n })
+        .collect();
+    let mut faces_of = vec![Vec::new(); positions.len()];
+    for (f, t) in triangles.iter().enumerate() {
+        for &v in t.iter() {
+            faces_of[v].push(f);
+        }
+    }
+
+    let cos_crease = crease.cos();
+    let mut mesh = Mesh {
+        positions: Vec::new(),
+        normals: Some(Vec::new()),
+        uvs: None,
+        tangents: None,
+        colors: None,
+        color_is_albedo: false,
+        triangles: Vec::with_capacity(triangles.len()),
+        maps: Maps::default(),
+    };
+    // Corners that average the same faces share a vertex
+    let mut split: HashMap<(usize, Vec<usize>), usize> = HashMap::new();
+    for (f, t) in triangles.iter().enumerate() {
+        let mut tri = [0; 3];
+        for (corner, &v) in tri.iter_mut().zip(t.iter()) {
+            let group: Vec<usize> = faces_of[v]
+                .iter()
+                .cloned()
+                .filter(|&g| na::dot(&unit[g], &unit[f]) >= cos_crease)
+                .collect();
+            let next = mesh.positions.len();
+            *corner = *split.entry((v, group.clone())).or_insert(next);
+            if *corner == next {
+                let mut normal = Vec3::new(0.0, 0.0, 0.0);
+                for &g in group.iter() {
+                    normal = normal + weighted[g];
+                }
+                if na::norm(&normal) > 0.0 {
+                    normal = normal.normalize();
+                } else {
+                    normal = unit[f];
+                }
+                mesh.positions.push(positions[v]);
+                mesh.normals.as_mut().unwrap().push(normal);
+            }
+        }
+        mesh.triangles.push(tri);
+    }
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII: &str = "solid square
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 1 1 0
+    endloop
+  endfacet
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 1 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid square
+";
+
+    /// The square of ASCII as a binary file with 'header' at its start
+    fn binary(header: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 80];
+        data[..header.len()].copy_from_slice(header);
+        data.extend_from_slice(&2u32.to_le_bytes());
+        let triangles = [
+            [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0]],
+            [[0.0, 0.0], [1.0, 1.0], [0.0, 1.0]],
+        ];
+        for t in triangles.iter() {
+            for &v in [0.0f32, 0.0, 1.0].iter() {
+                data.extend_from_slice(&v.to_le_bytes());
+            }
+            for c in t.iter() {
+                for &v in [c[0], c[1], 0.0f32].iter() {
+                    data.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            data.extend_from_slice(&[0, 0]);
+        }
+        data
+    }
+
+    fn check_square(corners: &[Vec3<Float>]) {
+        assert_eq!(corners.len(), 6);
+        assert_eq!(corners[2], Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(corners[5], Vec3::new(0.0, 1.0, 0.0));
+        let (positions, triangles) = weld_vertices(corners, 1e-6);
+        assert_eq!(positions.len(), 4);
+        assert_eq!(triangles, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn ascii() {
+        check_square(&parse(ASCII.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn binary_file() {
+        check_square(&parse(&binary(b"exported square")).unwrap());
+    }
+
+    #[test]
+    fn binary_file_with_solid_header() {
+        check_square(&parse(&binary(b"solid square")).unwrap());
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let data = binary(b"square");
+        assert!(parse(&data[..data.len() - 1]).is_err());
+        // Cut off in the middle of the first facet
+        let end = ASCII.find("vertex 1 1 0").unwrap();
+        assert!(parse(&ASCII.as_bytes()[..end]).is_err());
+    }
+}
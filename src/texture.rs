@@ -0,0 +1,87 @@
+//! Images that are mapped onto Surfaces through their texture coordinates.
+//! Texels are stored as linear values and filtered bilinearly.
+
+use image::DynamicImage;
+use na::Vec3;
+
+use environment::srgb_to_linear;
+use precision::Float;
+
+/// What happens to texture coordinates outside of [0, 1]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    Mirror,
+    Clamp,
+}
+
+impl Wrap {
+    /// Texel index for the continuous position 'x' in texels, 'size' texels wide
+    fn index(&self, x: i64, size: usize) -> usize {
+        let size = size as i64;
+        let i = match *self {
+            Wrap::Repeat => x.rem_euclid(size),
+            Wrap::Mirror => {
+                let period = x.rem_euclid(2 * size);
+                if period < size {
+                    period
+                } else {
+                    2 * size - 1 - period
+                }
+            }
+            Wrap::Clamp => x.max(0).min(size - 1),
+        };
+        i as usize
+    }
+}
+
+pub struct Texture {
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3<Float>>,
+    /// Wrapping of the horizontal and the vertical texture coordinate
+    wrap: (Wrap, Wrap),
+}
+
+impl Texture {
+    /// Texture with the color channels of 'image'. Colors are sRGB encoded, data
+    /// like normals or roughness is stored linearly.
+    pub fn new(image: &DynamicImage, srgb: bool, wrap: (Wrap, Wrap)) -> Texture {
+        let rgb = image.to_rgb();
+        let decode = |v: u8| {
+            if srgb {
+                srgb_to_linear(v)
+            } else {
+                v as Float / 255.0
+            }
+        };
+        Texture {
+            width: rgb.width() as usize,
+            height: rgb.height() as usize,
+            texels: rgb
+                .pixels()
+                .map(|p| Vec3::new(decode(p[0]), decode(p[1]), decode(p[2])))
+                .collect(),
+            wrap: wrap,
+        }
+    }
+
+    /// Bilinearly filtered value at the texture coordinates 'uv'. (0, 0) is the
+    /// top left corner of the image.
+    pub fn sample(&self, uv: [Float; 2]) -> Vec3<Float> {
+        if self.texels.is_empty() {
+            return Vec3::new(1.0, 1.0, 1.0);
+        }
+        let x = uv[0] * self.width as Float - 0.5;
+        let y = uv[1] * self.height as Float - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |dx: i64, dy: i64| {
+            let i = self.wrap.0.index(x0 as i64 + dx, self.width);
+            let j = self.wrap.1.index(y0 as i64 + dy, self.height);
+            self.texels[j * self.width + i]
+        };
+        (texel(0, 0) * (1.0 - fx) + texel(1, 0) * fx) * (1.0 - fy)
+            + (texel(0, 1) * (1.0 - fx) + texel(1, 1) * fx) * fy
+    }
+}
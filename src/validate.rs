@@ -0,0 +1,468 @@
+//! Checks a SceneDesc for input that would turn into NaNs while rendering:
+//! triangles whose corners lie on a line, spheres without a positive radius,
+//! coordinates that aren't finite and cameras without a direction to look in.
+//! Every problem is reported with the Object and Primitive it was found in.
+//! Broken Primitives and faces of Meshes can be left out of the scene, broken
+//! cameras, lights and Transforms can't.
+
+use na;
+use na::{Norm, Vec3};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use camera::{Projection, View};
+use precision::{consts, Float};
+use scene::{Mesh, Primitive, SceneDesc};
+
+/// Faces listed by number in a report before the rest are only counted
+const LISTED_FACES: usize = 8;
+
+/// Part of the scene a Problem was found in
+#[derive(Clone, Debug, PartialEq)]
+pub enum Site {
+    Camera,
+    Light,
+    /// Index of the Object
+    Object(usize),
+    /// Indices of the Object and of the Primitive within it
+    Primitive(usize, usize),
+    /// Faces of the Mesh that is Primitive 'primitive' of Object 'object'
+    Faces {
+        object: usize,
+        primitive: usize,
+        faces: Vec<usize>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub site: Site,
+    pub message: String,
+}
+
+impl Problem {
+    /// Whether leaving something out of the scene fixes the problem
+    pub fn skippable(&self) -> bool {
+        match self.site {
+            Site::Primitive(..) | Site::Faces { .. } => true,
+            Site::Camera | Site::Light | Site::Object(_) => false,
+        }
+    }
+}
+
+/// A Problem together with the scene it was found in, to name its Object
+pub struct Report<'a> {
+    pub desc: &'a SceneDesc,
+    pub problem: &'a Problem,
+}
+
+impl<'a> fmt::Display for Report<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |object: usize| &self.desc.objects[object].name;
+        match self.problem.site {
+            Site::Camera => write!(f, "camera")?,
+            Site::Light => write!(f, "light")?,
+            Site::Object(object) => write!(f, "object '{}'", name(object))?,
+            Site::Primitive(object, primitive) => {
+                write!(f, "object '{}' primitive {}", name(object), primitive)?
+            }
+            Site::Faces {
+                object,
+                primitive,
+                ref faces,
+            } => {
+                let listed: Vec<String> = faces
+                    .iter()
+                    .take(LISTED_FACES)
+                    .map(|face| face.to_string())
+                    .collect();
+                write!(
+                    f,
+                    "object '{}' primitive {} face{} {}",
+                    name(object),
+                    primitive,
+                    if faces.len() == 1 { "" } else { "s" },
+                    listed.join(", ")
+                )?;
+                if faces.len() > LISTED_FACES {
+                    write!(f, " and {} more", faces.len() - LISTED_FACES)?;
+                }
+            }
+        }
+        write!(f, ": {}", self.problem.message)
+    }
+}
+
+fn finite(v: Vec3<Float>) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+/// False for NaN and infinity as well as for zero and negative numbers
+fn positive(x: Float) -> bool {
+    x > 0.0 && x.is_finite()
+}
+
+/// Whether the corners of a triangle are on a line, within the precision
+/// their coordinates have
+fn degenerate(a: Vec3<Float>, b: Vec3<Float>, c: Vec3<Float>) -> bool {
+    let (ab, ac, bc) = (b - a, c - a, c - b);
+    let longest = ab.sqnorm().max(ac.sqnorm()).max(bc.sqnorm());
+    na::norm(&na::cross(&ab, &ac)) <= Float::EPSILON * longest
+}
+
+fn check_triangle(a: Vec3<Float>, b: Vec3<Float>, c: Vec3<Float>) -> Option<String> {
+    if !finite(a) || !finite(b) || !finite(c) {
+        Some("a corner of the triangle isn't finite".to_string())
+    } else if degenerate(a, b, c) {
+        Some("the triangle is degenerate, its corners are on a line".to_string())
+    } else {
+        None
+    }
+}
+
+/// Problems with the camera looking from 'view' through 'projection'
+pub fn check_camera(view: &View, projection: &Projection) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut camera = |message: &str| {
+        problems.push(Problem {
+            site: Site::Camera,
+            message: message.to_string(),
+        })
+    };
+    if !finite(view.eye) || !finite(view.look) {
+        camera("the eye or the look point isn't finite");
+    } else if view.eye == view.look {
+        camera("the eye and the look point are the same, the camera has no direction");
+    }
+    if !finite(view.up) || na::norm(&view.up) == 0.0 || !view.roll.is_finite() {
+        camera("the up direction has no length or the roll isn't finite");
+    }
+    match *projection {
+        Projection::Perspective { fov, lens } => {
+            if !positive(fov) || fov >= consts::PI {
+                camera("the field of view has to be between 0 and 180 degrees");
+            }
+            let aperture = lens.aperture == 0.0 || positive(lens.aperture);
+            if !aperture || lens.focus_distance.map_or(false, |d| !positive(d)) {
+                camera("the aperture can't be negative and the focus distance has to be positive");
+            }
+        }
+        Projection::Orthographic { width } => {
+            if !positive(width) {
+                camera("the view width has to be positive");
+            }
+        }
+        Projection::Fisheye { fov } => {
+            if !positive(fov) {
+                camera("the field of view has to be positive");
+            }
+        }
+        Projection::Equirectangular => {}
+    }
+    problems
+}
+
+/// Problems with the parts of the scene, other than the camera, that an
+/// animation changes from frame to frame: the light and the Transforms
+pub fn check_frame(desc: &SceneDesc) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let light = desc.light;
+    let mut light_problem = None;
+    if !finite(light.position) || !finite(light.color) {
+        light_problem = Some("the position or color of the light isn't finite");
+    } else if let Some(direction) = light.direction {
+        if !finite(direction) || na::norm(&direction) == 0.0 {
+            light_problem = Some("the direction of the light has no length");
+        }
+    }
+    if let Some(message) = light_problem {
+        problems.push(Problem {
+            site: Site::Light,
+            message: message.to_string(),
+        });
+    }
+
+    for (index, object) in desc.objects.iter().enumerate() {
+        let t = object.transform;
+        let message = if !finite(object.pivot) || !finite(t.translation) || !finite(t.rotation) {
+            Some("the pivot or the transform isn't finite")
+        } else if !positive(t.scale) {
+            Some("the scale has to be positive")
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            problems.push(Problem {
+                site: Site::Object(index),
+                message: message.to_string(),
+            });
+        }
+    }
+    problems
+}
+
+/// Every problem of the scene except for those of the camera, the ones
+/// 'check_frame' finds and the ones with the Primitives
+pub fn check(desc: &SceneDesc) -> Vec<Problem> {
+    let mut problems = check_frame(desc);
+    for (o, object) in desc.objects.iter().enumerate() {
+        for (p, primitive) in object.primitives.iter().enumerate() {
+            let material = match *primitive {
+                Primitive::Sphere { material, .. }
+                | Primitive::Triangle { material, .. }
+                | Primitive::Mesh { material, .. } => material,
+            };
+            let message = if material >= desc.materials.len() {
+                Some(format!("there is no material {}", material))
+            } else {
+                match *primitive {
+                    Primitive::Sphere { center, radius, .. } => {
+                        if !finite(center) {
+                            Some("the center of the sphere isn't finite".to_string())
+                        } else if !positive(radius) {
+                            Some(format!("the sphere has a radius of {}", radius))
+                        } else {
+                            None
+                        }
+                    }
+                    Primitive::Triangle { a, b, c, .. } => check_triangle(a, b, c),
+                    Primitive::Mesh { ref mesh, .. } => {
+                        check_mesh(mesh, o, p, &mut problems);
+                        None
+                    }
+                }
+            };
+            if let Some(message) = message {
+                problems.push(Problem {
+                    site: Site::Primitive(o, p),
+                    message: message,
+                });
+            }
+        }
+    }
+    problems
+}
+
+fn check_mesh(mesh: &Mesh, object: usize, primitive: usize, problems: &mut Vec<Problem>) {
+    let count = mesh.positions.len();
+    let mut missing = Vec::new();
+    let mut broken = Vec::new();
+    for (face, tri) in mesh.triangles.iter().enumerate() {
+        if tri.iter().any(|&i| i >= count) {
+            missing.push(face);
+        } else if check_triangle(
+            mesh.positions[tri[0]],
+            mesh.positions[tri[1]],
+            mesh.positions[tri[2]],
+        )
+        .is_some()
+        {
+            broken.push(face);
+        }
+    }
+    if !missing.is_empty() {
+        problems.push(Problem {
+            site: Site::Faces {
+                object: object,
+                primitive: primitive,
+                faces: missing,
+            },
+            message: format!("the mesh has only {} vertices", count),
+        });
+    }
+    if !broken.is_empty() {
+        problems.push(Problem {
+            site: Site::Faces {
+                object: object,
+                primitive: primitive,
+                faces: broken,
+            },
+            message: "degenerate or not finite".to_string(),
+        });
+    }
+}
+
+/// Leaves the Primitives and the faces the 'problems' were found in out of the
+/// scene. The 'problems' have to be skippable and found in 'desc'.
+pub fn skip(desc: &mut SceneDesc, problems: &[Problem]) {
+    // Faces go first, removing Primitives shifts the indices of the ones after them.
+    // A Mesh can have several problems whose face numbers all refer to its
+    // original triangles, so they are removed together.
+    let mut faces_of: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+    for problem in problems.iter() {
+        if let Site::Faces {
+            object,
+            primitive,
+            ref faces,
+        } = problem.site
+        {
+            faces_of
+                .entry((object, primitive))
+                .or_default()
+                .extend(faces.iter().cloned());
+        }
+    }
+    for (&(object, primitive), faces) in faces_of.iter_mut() {
+        faces.sort_unstable();
+        if let Primitive::Mesh { ref mut mesh, .. } = desc.objects[object].primitives[primitive] {
+            let kept = mesh
+                .triangles
+                .iter()
+                .enumerate()
+                .filter(|&(face, _)| faces.binary_search(&face).is_err())
+                .map(|(_, tri)| *tri)
+                .collect();
+            *mesh = Arc::new(Mesh {
+                positions: mesh.positions.clone(),
+                normals: mesh.normals.clone(),
+                uvs: mesh.uvs.clone(),
+                tangents: mesh.tangents.clone(),
+                colors: mesh.colors.clone(),
+                color_is_albedo: mesh.color_is_albedo,
+                triangles: kept,
+                maps: mesh.maps.clone(),
+            });
+        }
+    }
+    for (o, object) in desc.objects.iter_mut().enumerate() {
+        let mut p = 0;
+        object.primitives.retain(|_| {
+            let keep = !problems
+                .iter()
+                .any(|problem| problem.site == Site::Primitive(o, p));
+            p += 1;
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use default_scene;
+    use scene::{Maps, Object, Transform};
+
+    fn mesh(positions: Vec<Vec3<Float>>, triangles: Vec<[usize; 3]>) -> Primitive {
+        Primitive::Mesh {
+            mesh: Arc::new(Mesh {
+                positions: positions,
+                normals: None,
+                uvs: None,
+                tangents: None,
+                colors: None,
+                color_is_albedo: false,
+                triangles: triangles,
+                maps: Maps::default(),
+            }),
+            material: 0,
+        }
+    }
+
+    /// The default scene with an Object holding a broken sphere, a broken
+    /// triangle, a mesh with a face past its vertices and a sphere that's fine
+    fn broken_scene() -> (SceneDesc, usize) {
+        let mut desc = default_scene();
+        let (a, b, c) = (
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        desc.objects.push(Object {
+            name: "broken".to_string(),
+            pivot: a,
+            transform: Transform::identity(),
+            primitives: vec![
+                Primitive::Sphere {
+                    center: a,
+                    radius: 0.0,
+                    material: 0,
+                },
+                Primitive::Triangle {
+                    a: a,
+                    b: b,
+                    c: b * 2.0,
+                    material: 0,
+                    pattern: false,
+                },
+                mesh(vec![a, b, c], vec![[0, 1, 2], [0, 1, 5], [2, 1, 0]]),
+                Primitive::Sphere {
+                    center: c,
+                    radius: 1.0,
+                    material: 0,
+                },
+            ],
+        });
+        let object = desc.objects.len() - 1;
+        (desc, object)
+    }
+
+    #[test]
+    fn check_finds_the_broken_primitives_and_faces() {
+        let (desc, o) = broken_scene();
+        let sites: Vec<Site> = check(&desc).into_iter().map(|p| p.site).collect();
+        assert_eq!(
+            sites,
+            vec![
+                Site::Primitive(o, 0),
+                Site::Primitive(o, 1),
+                Site::Faces {
+                    object: o,
+                    primitive: 2,
+                    faces: vec![1],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_leaves_a_valid_scene() {
+        let (mut desc, o) = broken_scene();
+        let problems = check(&desc);
+        assert!(problems.iter().all(|p| p.skippable()));
+        skip(&mut desc, &problems);
+        assert!(check(&desc).is_empty());
+        let primitives = &desc.objects[o].primitives;
+        assert_eq!(primitives.len(), 2);
+        match primitives[0] {
+            Primitive::Mesh { ref mesh, .. } => {
+                assert_eq!(mesh.triangles, vec![[0, 1, 2], [2, 1, 0]])
+            }
+            _ => panic!("the mesh was removed"),
+        }
+    }
+
+    #[test]
+    fn skip_removes_the_faces_of_every_problem() {
+        let mut desc = default_scene();
+        let (a, b, c) = (
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        // A face past the vertices and a degenerate face are separate problems
+        let faces = vec![[0, 1, 2], [0, 1, 5], [0, 1, 1], [2, 1, 0]];
+        desc.objects[0].primitives = vec![mesh(vec![a, b, c], faces)];
+        let problems = check(&desc);
+        assert_eq!(problems.len(), 2);
+        skip(&mut desc, &problems);
+        assert!(check(&desc).is_empty());
+        match desc.objects[0].primitives[0] {
+            Primitive::Mesh { ref mesh, .. } => {
+                assert_eq!(mesh.triangles, vec![[0, 1, 2], [2, 1, 0]])
+            }
+            _ => panic!("the mesh was removed"),
+        }
+    }
+
+    #[test]
+    fn cameras_without_a_direction_are_reported() {
+        let mut view = default_scene().view;
+        view.look = view.eye;
+        let problems = check_camera(&view, &Projection::Orthographic { width: 0.0 });
+        assert_eq!(problems.len(), 2);
+        assert!(problems
+            .iter()
+            .all(|p| p.site == Site::Camera && !p.skippable()));
+    }
+}